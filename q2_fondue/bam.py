@@ -0,0 +1,328 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import gzip
+import io
+import os
+import re
+import shutil
+import struct
+import subprocess
+import zlib
+from dataclasses import dataclass
+from threading import Thread
+from typing import Iterable, List, Optional, Tuple, Union
+
+from q2_fondue.entrezpy_clients._utils import set_up_logger
+from q2_fondue.fastq import FastqGzWriter
+from q2_fondue.utils import (
+    CompressionError, FastqParseError, FastqRewriteError, _is_stream,
+    _remove_files
+)
+
+LOGGER = set_up_logger('INFO', logger_name=__name__)
+
+BAM_MAGIC = b'BAM\x01'
+# 4-bit codes of the bases of BAM records
+BAM_BASES = '=ACMGRSVTWYHKDBN'
+# Phred score of bases of BAM records lacking qualities (as in samtools)
+BAM_MISSING_QUALITY = 1
+CRAM_MAGIC = b'CRAM'
+# where htslib looks for CRAM references when REF_PATH is not set
+CRAM_REFERENCE_URL = 'https://www.ebi.ac.uk/ena/cram/md5/%s'
+
+
+@dataclass
+class BamConversionStats:
+    """Summary of a conversion of BAM records into FASTQ files.
+
+    Attributes:
+        pairs (int): Number of read pairs written to the paired outputs.
+        singles (int): Number of unpaired reads.
+        orphans (int): Number of paired reads whose mate was not found,
+            which were written along with the unpaired reads.
+        skipped (int): Number of secondary and supplementary alignments,
+            which were not written.
+    """
+    pairs: int = 0
+    singles: int = 0
+    orphans: int = 0
+    skipped: int = 0
+
+
+def _read_exact(f_in, size: int, name: str) -> bytes:
+    data = f_in.read(size)
+    if len(data) < size:
+        raise FastqParseError(f'{name} is truncated.')
+    return data
+
+
+def _read_bam(
+        file_in: Union[str, io.IOBase], name: str
+) -> Iterable[Tuple[str, int, str, str]]:
+    """Iterates over the records of a BAM file (or stream), returning their
+        read names, flags, sequences and Phred+33 qualities - exactly as
+        they are stored, i.e., reverse-complemented for reverse-strand
+        alignments. The file is referred to by name in errors.
+
+    Raises:
+        FastqParseError: If the file is not a BAM file or is truncated.
+    """
+    pairs = [x + y for x in BAM_BASES for y in BAM_BASES]
+    with gzip.open(file_in, 'rb') as f_in:
+        try:
+            magic = f_in.read(4)
+        except gzip.BadGzipFile:
+            magic = b''
+        if magic != BAM_MAGIC:
+            raise FastqParseError(f'{name} is not a BAM file.')
+        l_text, = struct.unpack('<i', _read_exact(f_in, 4, name))
+        _read_exact(f_in, l_text, name)
+        n_ref, = struct.unpack('<i', _read_exact(f_in, 4, name))
+        for _ in range(n_ref):
+            l_name, = struct.unpack('<i', _read_exact(f_in, 4, name))
+            _read_exact(f_in, l_name + 4, name)
+        for size in iter(lambda: f_in.read(4), b''):
+            if len(size) < 4:
+                raise FastqParseError(f'{name} is truncated.')
+            block_size, = struct.unpack('<i', size)
+            record = _read_exact(f_in, block_size, name)
+            l_name, _, _, n_cigar, flag, l_seq = struct.unpack_from(
+                '<BBHHHi', record, 8
+            )
+            name_end = 32 + l_name - 1
+            seq_start = 32 + l_name + 4 * n_cigar
+            qual_start = seq_start + (l_seq + 1) // 2
+            seq = ''.join(
+                pairs[x] for x in record[seq_start:qual_start]
+            )[:l_seq]
+            qual = record[qual_start:qual_start + l_seq]
+            if qual[:1] == b'\xff':
+                qual = chr(BAM_MISSING_QUALITY + 33) * l_seq
+            else:
+                qual = bytes(x + 33 for x in qual).decode('ascii')
+            yield record[32:name_end].decode(), flag, seq, qual
+
+
+def _bam_to_fastq(
+        bam: Union[str, io.IOBase], r1_out: str, r2_out: str,
+        singles_out: str, compression_level: int = 9
+) -> BamConversionStats:
+    """Converts the reads of a BAM file into gzip-compressed FASTQ files.
+
+    Mates of paired reads are found by their names and written, in sync,
+    to r1_out and r2_out (forward reads are those flagged as the first
+    segment). Reverse-strand alignments are reverse-complemented again,
+    such that the original reads are restored. Unpaired reads and paired
+    reads whose mate is missing are written to singles_out, while
+    secondary and supplementary alignments are skipped. Reads are kept in
+    memory until their mate is found - BAM files sorted or grouped by name
+    (as opposed to coordinate-sorted ones) thus need the least memory.
+
+    Args:
+        bam (Union[str, io.IOBase]): Path to the BAM file - or a binary
+            stream of BAM data (e.g., from samtools).
+        r1_out (str): Path where the forward reads should be written.
+        r2_out (str): Path where the reverse reads should be written.
+        singles_out (str): Path where the unpaired reads should be written.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        BamConversionStats: Numbers of the written and skipped reads.
+
+    Raises:
+        FastqParseError: If the file is not a BAM file or is truncated -
+            none of the outputs are kept then.
+        CompressionError: If the file could not be decompressed.
+    """
+    name = 'BAM stream' if _is_stream(bam) else os.fsdecode(bam)
+    complement = str.maketrans('ACGTMRWSYKVHDBN=', 'TGCAKYWSRMBDHVN=')
+    stats, pending = BamConversionStats(), {}
+    paths = [r1_out, r2_out, singles_out]
+    try:
+        with FastqGzWriter(r1_out, compression_level) as r1, \
+                FastqGzWriter(r2_out, compression_level) as r2, \
+                FastqGzWriter(singles_out, compression_level) as singles:
+            for read_id, flag, seq, qual in _read_bam(bam, name):
+                # secondary (0x100) and supplementary (0x800) alignments
+                if flag & 0x900:
+                    stats.skipped += 1
+                    continue
+                if flag & 0x10:
+                    seq, qual = seq.translate(complement)[::-1], qual[::-1]
+                if not flag & 0x1:
+                    singles.write_record(read_id, seq, qual)
+                    stats.singles += 1
+                elif read_id not in pending:
+                    pending[read_id] = (seq, qual)
+                else:
+                    mate = pending.pop(read_id)
+                    first, second = ((seq, qual), mate) if flag & 0x40 \
+                        else (mate, (seq, qual))
+                    r1.write_record(read_id, *first)
+                    r2.write_record(read_id, *second)
+                    stats.pairs += 1
+            for read_id, (seq, qual) in pending.items():
+                singles.write_record(read_id, seq, qual)
+                stats.orphans += 1
+    except (EOFError, zlib.error) as e:
+        _remove_files(paths)
+        raise CompressionError(
+            f'{name} could not be decompressed: {e}'
+        ) from e
+    except BaseException:
+        # no partial outputs of truncated or corrupt files are kept
+        _remove_files(paths)
+        raise
+    LOGGER.info(
+        'Converted %s read pairs and %s single reads from %s (%s reads '
+        'without a mate).', stats.pairs, stats.singles, name, stats.orphans
+    )
+    return stats
+
+
+def _expand_ref_template(template: str, md5: str) -> str:
+    """Expands a REF_PATH or REF_CACHE entry for a reference checksum, as
+        htslib does: every %Ns is replaced with the next N characters of
+        the checksum and %s with all the remaining ones."""
+    position = 0
+
+    def _next_part(match) -> str:
+        nonlocal position
+        size = int(match.group(1) or len(md5))
+        part, position = md5[position:position + size], position + size
+        return part
+    return re.sub(r'%(\d*)s', _next_part, template)
+
+
+def _reference_locations() -> Tuple[List[str], str]:
+    """Returns the REF_PATH entries and the REF_CACHE template through
+        which CRAM references are looked up - htslib's defaults are used
+        when those are not set. REF_PATH entries are separated by colons,
+        except for those of URLs (as in https://)."""
+    ref_path = os.environ.get('REF_PATH') or CRAM_REFERENCE_URL
+    cache_dir = os.environ.get('XDG_CACHE_HOME') \
+        or os.path.join(os.path.expanduser('~'), '.cache')
+    ref_cache = os.environ.get('REF_CACHE') \
+        or os.path.join(cache_dir, 'hts-ref', '%2s', '%2s', '%s')
+    return [x for x in re.split(r':(?!//)', ref_path) if x], ref_cache
+
+
+def _locate_reference(md5: str) -> Optional[str]:
+    """Finds a CRAM reference with the given MD5 checksum in REF_CACHE or
+        in any of the local REF_PATH entries - returns None if it is not
+        stored locally."""
+    ref_path, ref_cache = _reference_locations()
+    for template in (ref_cache, *ref_path):
+        if re.match(r'^[a-z]+://', template):
+            continue
+        path = _expand_ref_template(template, md5)
+        if os.path.isfile(path):
+            return path
+    return None
+
+
+def _cram_reference_md5s(cram: str) -> List[str]:
+    """Returns the MD5 checksums (M5 tags) of all the reference sequences
+        listed in the header of a CRAM file."""
+    proc = subprocess.run(
+        ['samtools', 'view', '-H', '--no-PG', cram], capture_output=True
+    )
+    if proc.returncode != 0:
+        raise FastqRewriteError(
+            f'Header of {cram} could not be read (exit code '
+            f'{proc.returncode}): {proc.stderr.decode(errors="replace")}'
+        )
+    return re.findall(
+        r'^@SQ\t.*\bM5:([0-9a-fA-F]{32})', proc.stdout.decode(), re.M
+    )
+
+
+def _cram_to_fastq(
+        cram: str, r1_out: str, r2_out: str, singles_out: str,
+        reference: str = None, compression_level: int = 9
+) -> BamConversionStats:
+    """Converts the reads of a CRAM file into gzip-compressed FASTQ files.
+
+    The CRAM file is decoded with samtools and its records are converted
+    like those of a BAM file (see _bam_to_fastq). Unless a reference FASTA
+    is given, the reference sequences are looked up by their checksums
+    as htslib does: through REF_CACHE and the entries of REF_PATH, from
+    where they may also be downloaded (by default from ENA's CRAM
+    reference registry) and stored in REF_CACHE for later runs.
+
+    Args:
+        cram (str): Path to the CRAM file.
+        r1_out (str): Path where the forward reads should be written.
+        r2_out (str): Path where the reverse reads should be written.
+        singles_out (str): Path where the unpaired reads should be written.
+        reference (str, default=None): Path to the reference FASTA file
+            the reads were compressed against.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        BamConversionStats: Numbers of the written and skipped reads.
+
+    Raises:
+        FastqParseError: If the file is not a CRAM file.
+        FastqRewriteError: If samtools is not available, a reference
+            cannot be found or the file could not be decoded.
+    """
+    cram = os.fsdecode(cram)
+    with open(cram, 'rb') as f_in:
+        if f_in.read(4) != CRAM_MAGIC:
+            raise FastqParseError(f'{cram} is not a CRAM file.')
+    if shutil.which('samtools') is None:
+        raise FastqRewriteError(
+            f'samtools is required to read CRAM files such as {cram}.'
+        )
+    ref_path, ref_cache = _reference_locations()
+    if reference is None and not any(
+            re.match(r'^[a-z]+://', x) for x in ref_path
+    ):
+        missing = [
+            x for x in _cram_reference_md5s(cram)
+            if _locate_reference(x) is None
+        ]
+        if missing:
+            raise FastqRewriteError(
+                f'References of {cram} (with MD5 checksums '
+                f'{", ".join(missing)}) could not be found - please provide '
+                f'the reference or add its location to REF_PATH.'
+            )
+    ref_params = ['-T', os.fsdecode(reference)] if reference else []
+    cmd = ['samtools', 'view', '-u', '--no-PG', *ref_params, cram]
+    env = {**os.environ, 'REF_PATH': ':'.join(ref_path),
+           'REF_CACHE': ref_cache}
+    paths, stderr, error = [r1_out, r2_out, singles_out], [], None
+    LOGGER.debug('Running %s.', ' '.join(cmd))
+    proc = subprocess.Popen(
+        cmd, stdout=subprocess.PIPE, stderr=subprocess.PIPE, env=env
+    )
+    # drain stderr concurrently so that samtools never blocks on it
+    stderr_reader = Thread(target=lambda: stderr.append(proc.stderr.read()))
+    stderr_reader.start()
+    try:
+        stats = _bam_to_fastq(proc.stdout, *paths, compression_level)
+    except FastqRewriteError as e:
+        # samtools failing leaves an incomplete stream behind
+        error = e
+    except BaseException:
+        proc.kill()
+        _remove_files(paths)
+        raise
+    finally:
+        proc.stdout.close()
+        returncode = proc.wait()
+        stderr_reader.join()
+    if returncode != 0 or error:
+        _remove_files(paths)
+        raise FastqRewriteError(
+            f'Reads of {cram} could not be decoded (exit code {returncode}): '
+            f'{b"".join(stderr).decode(errors="replace").strip() or error}'
+        ) from error
+    return stats
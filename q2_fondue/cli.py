@@ -0,0 +1,197 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import argparse
+import json
+import sys
+from dataclasses import asdict
+from typing import List
+
+from q2_fondue.fastq import _check_pairing, _concat_runs, _fastq_stats
+from q2_fondue.rewrite import RewriteOptions, _rewrite_fastq
+from q2_fondue.utils import FastqStats, FastqRewriteError
+
+
+def _print_json(obj, stream):
+    json.dump(obj, stream, indent=2)
+    stream.write('\n')
+
+
+def _stats_summary(stats: FastqStats) -> dict:
+    return {**asdict(stats), 'mean_length': stats.mean_length}
+
+
+def _compression_level(value: str):
+    return value if value == 'auto' else int(value)
+
+
+def _add_output_options(parser: argparse.ArgumentParser):
+    parser.add_argument(
+        '--compression-level', type=_compression_level, default=9,
+        help='gzip compression level from 0 to 9, or "auto" '
+             '(default: %(default)s)'
+    )
+    parser.add_argument(
+        '--threads', type=int, default=1,
+        help='number of threads used to compress the output '
+             '(default: %(default)s)'
+    )
+    parser.add_argument(
+        '--overwrite', action='store_true',
+        help='replace the output file if it exists'
+    )
+
+
+def _output_options(args, **kwargs) -> RewriteOptions:
+    return RewriteOptions(
+        compression_level=args.compression_level, threads=args.threads,
+        overwrite=args.overwrite, **kwargs
+    )
+
+
+def _rewrite(args) -> dict:
+    options = _output_options(
+        args, output_format=args.output_format, mode=args.mode,
+        validate=args.validate, min_length=args.min_length,
+        max_length=args.max_length, bin_qualities=args.bin_qualities,
+        checksums=args.checksum
+    )
+    return _stats_summary(_rewrite_fastq(args.input, args.output, options))
+
+
+def _stats(args) -> dict:
+    report = _fastq_stats(args.input, args.quality_offset)
+    return report.summary(histograms=args.histograms)
+
+
+def _pair_check(args) -> dict:
+    divergence = _check_pairing(args.forward, args.reverse)
+    if divergence is None:
+        return {'paired': True}
+    record, id_forward, id_reverse = divergence
+    return {
+        'paired': False, 'record': record,
+        'forward_id': id_forward, 'reverse_id': id_reverse
+    }
+
+
+def _subsample(args) -> dict:
+    options = _output_options(
+        args, subsample_fraction=args.fraction, subsample_n=args.n,
+        seed=args.seed
+    )
+    return _stats_summary(_rewrite_fastq(args.input, args.output, options))
+
+
+def _merge(args) -> dict:
+    stats = _concat_runs(
+        args.inputs, args.output, prefix_ids=args.prefix_ids,
+        compression_level=args.compression_level, threads=args.threads,
+        overwrite=args.overwrite
+    )
+    return _stats_summary(stats)
+
+
+def _build_parser() -> argparse.ArgumentParser:
+    parser = argparse.ArgumentParser(
+        prog='fondue-fastq',
+        description='Rewrite, inspect and combine FASTQ files fetched '
+                    'with q2-fondue, outside of QIIME 2. Results are '
+                    'printed as JSON.'
+    )
+    commands = parser.add_subparsers(dest='command', required=True)
+
+    rewrite = commands.add_parser(
+        'rewrite', help='write a gzip-compressed copy of a FASTQ file'
+    )
+    rewrite.add_argument('input', help='FASTQ file ("-" for stdin)')
+    rewrite.add_argument('output', help='output file ("-" for stdout)')
+    _add_output_options(rewrite)
+    rewrite.add_argument(
+        '--output-format', choices=['gzip', 'bgzf'], default='gzip'
+    )
+    rewrite.add_argument(
+        '--mode', choices=['copy', 'parse', 'auto'], default='auto',
+        help='whether records are copied or parsed (default: %(default)s)'
+    )
+    rewrite.add_argument(
+        '--validate', action='store_true',
+        help='validate every record before it is written'
+    )
+    rewrite.add_argument('--min-length', type=int)
+    rewrite.add_argument('--max-length', type=int)
+    rewrite.add_argument(
+        '--bin-qualities', action='store_true',
+        help="bin quality scores into Illumina's 8 levels"
+    )
+    rewrite.add_argument(
+        '--checksum', action='append', default=[], metavar='ALGORITHM',
+        help='hash algorithm (e.g., md5) of a checksum of the written file'
+    )
+    rewrite.set_defaults(func=_rewrite)
+
+    stats = commands.add_parser(
+        'stats', help='report quality-control metrics of a FASTQ file'
+    )
+    stats.add_argument('input', help='FASTQ file')
+    stats.add_argument(
+        '--quality-offset', type=int, choices=[33, 64], default=33
+    )
+    stats.add_argument(
+        '--histograms', action='store_true',
+        help='include histograms of the read lengths and mean qualities'
+    )
+    stats.set_defaults(func=_stats)
+
+    pair_check = commands.add_parser(
+        'pair-check', help='check that paired-end reads are in sync'
+    )
+    pair_check.add_argument('forward', help='FASTQ file of forward reads')
+    pair_check.add_argument('reverse', help='FASTQ file of reverse reads')
+    pair_check.set_defaults(func=_pair_check)
+
+    subsample = commands.add_parser(
+        'subsample', help='write a random subsample of a FASTQ file'
+    )
+    subsample.add_argument('input', help='FASTQ file ("-" for stdin)')
+    subsample.add_argument('output', help='output file ("-" for stdout)')
+    size = subsample.add_mutually_exclusive_group(required=True)
+    size.add_argument('--fraction', type=float)
+    size.add_argument('-n', type=int, help='exact number of records')
+    subsample.add_argument('--seed', type=int)
+    _add_output_options(subsample)
+    subsample.set_defaults(func=_subsample)
+
+    merge = commands.add_parser(
+        'merge', help='concatenate the reads of multiple runs'
+    )
+    merge.add_argument('output', help='output file')
+    merge.add_argument('inputs', nargs='+', help='FASTQ files of the runs')
+    merge.add_argument(
+        '--prefix-ids', action='store_true',
+        help='prefix read IDs with the run accessions'
+    )
+    _add_output_options(merge)
+    merge.set_defaults(func=_merge)
+    return parser
+
+
+def main(argv: List[str] = None) -> int:
+    parser = _build_parser()
+    args = parser.parse_args(argv)
+    try:
+        result = args.func(args)
+    except (FastqRewriteError, OSError, ValueError) as e:
+        parser.exit(1, f'{parser.prog}: error: {e}\n')
+    # keep stdout for the sequences when they are written there
+    streamed = getattr(args, 'output', None) == '-'
+    _print_json(result, sys.stderr if streamed else sys.stdout)
+    return 0 if result.get('paired', True) else 1
+
+
+if __name__ == '__main__':
+    sys.exit(main())
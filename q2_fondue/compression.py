@@ -0,0 +1,788 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import bz2
+import gzip
+import hashlib
+import io
+import lzma
+import mmap
+import os
+import queue
+import shutil
+import struct
+import sys
+import time
+import zlib
+from collections import deque
+from concurrent.futures import ThreadPoolExecutor
+from contextlib import nullcontext
+from threading import Event, Thread
+from typing import Callable, Dict, Iterable, List, Tuple, Union
+
+from q2_fondue.entrezpy_clients._utils import set_up_logger
+from q2_fondue.utils import (
+    CorruptGzipError, FastqRewriteError, FastqStats, InsufficientSpaceError,
+    _atomic_output, _is_file_like, _is_stream
+)
+
+LOGGER = set_up_logger('INFO', logger_name=__name__)
+
+# size of the blocks in which streams are copied (e.g., into a compressor)
+COPY_BLOCK_SIZE = 1024 ** 2
+# size of the buffers of written files (and of the formatted records
+# collected before they are compressed) - much larger than Python's default,
+# which performs poorly on parallel file systems such as Lustre or GPFS
+WRITE_BUFFER_SIZE = 1024 ** 2
+# number of blocks queued between the threads of a pipelined rewrite
+PIPELINE_DEPTH = 8
+# range within which the 'auto' compression level is adjusted
+AUTO_COMPRESSION_LEVELS = (1, 9)
+# size of the uncompressed blocks which are compressed in parallel
+COMPRESSION_BLOCK_SIZE = 4 * 1024 ** 2
+# number of uncompressed bytes after which progress is reported again
+PROGRESS_INTERVAL = 16 * 1024 ** 2
+# number of written bytes after which free disk space is checked again
+SPACE_CHECK_INTERVAL = 64 * 1024 ** 2
+# maximal uncompressed BGZF block size, as used by htslib
+BGZF_BLOCK_SIZE = 65280
+# empty block marking the end of a BGZF file
+BGZF_EOF = bytes.fromhex(
+    '1f8b08040000000000ff0600424302001b0003000000000000000000'
+)
+
+
+class _ProgressReporter:
+    """Reports the progress of a rewrite to a callback, which is called
+        with the numbers of records written so far, of uncompressed bytes
+        read and of compressed bytes written - every interval bytes read
+        and once more when done."""
+
+    def __init__(
+            self, callback: Callable[[int, int, int], None],
+            stats: FastqStats, interval: int = PROGRESS_INTERVAL
+    ):
+        self.callback = callback
+        self.stats = stats
+        self.interval = interval
+        self.bytes_in = 0
+        self.bytes_out = 0
+        self._next_report = interval
+
+    def read(self, size: int):
+        self.bytes_in += size
+        if self.bytes_in >= self._next_report:
+            self.report()
+            self._next_report = self.bytes_in + self.interval
+
+    def written(self, size: int):
+        self.bytes_out += size
+
+    def report(self):
+        self.callback(self.stats.reads, self.bytes_in, self.bytes_out)
+
+
+def _gzip_writer(f_out, compression_level: int) -> gzip.GzipFile:
+    """Opens a gzip stream on top of a binary file object without the
+        modification time or original file name in the header, such that
+        identical data always result in byte-identical files."""
+    return gzip.GzipFile(
+        filename='', mode='wb', compresslevel=compression_level,
+        fileobj=f_out, mtime=0
+    )
+
+
+def _compress_gzip_member(block: bytes, compression_level: int) -> bytes:
+    """Compresses data into a single gzip member with a reproducible
+        header (see _gzip_writer)."""
+    return gzip.compress(block, compression_level, mtime=0)
+
+
+class _SpaceWatcher:
+    """Wraps a binary file opened for writing and checks the free space on
+        its file system every SPACE_CHECK_INTERVAL written bytes (and before
+        the first write).
+
+    Raises:
+        InsufficientSpaceError: If less than min_free_space bytes are free.
+    """
+
+    def __init__(self, f_out, file_out: str, min_free_space: int):
+        self.f_out = f_out
+        self.file_out = file_out
+        self.min_free_space = min_free_space
+        self._unchecked = SPACE_CHECK_INTERVAL
+
+    def write(self, data: bytes) -> int:
+        if self._unchecked >= SPACE_CHECK_INTERVAL:
+            free = shutil.disk_usage(
+                os.path.dirname(os.path.abspath(self.file_out))
+            ).free
+            if free < self.min_free_space:
+                raise InsufficientSpaceError(
+                    self.file_out, free, self.min_free_space
+                )
+            self._unchecked = 0
+        self._unchecked += len(data)
+        return self.f_out.write(data)
+
+    def flush(self):
+        self.f_out.flush()
+
+    def close(self):
+        self.f_out.close()
+
+
+class _PrefixedReader(io.RawIOBase):
+    """Reads from a binary file object after replaying bytes which were
+        already read from it - the file object is not closed along."""
+
+    def __init__(self, prefix: bytes, f_in):
+        self.prefix = prefix
+        self.f_in = f_in
+
+    def readable(self) -> bool:
+        return True
+
+    def readinto(self, buffer) -> int:
+        if self.prefix:
+            data, self.prefix = (
+                self.prefix[:len(buffer)], self.prefix[len(buffer):]
+            )
+        else:
+            data = self.f_in.read(len(buffer))
+        buffer[:len(data)] = data
+        return len(data)
+
+
+class _Bz2DecompressionError(OSError):
+    """Raised when bz2-compressed data cannot be decompressed."""
+
+
+def _bz2_errors(method: Callable) -> Callable:
+    """Wraps a reading method of _Bz2Reader."""
+    def _read(self, *args):
+        try:
+            return method(self, *args)
+        except _Bz2DecompressionError:
+            raise
+        except OSError as e:
+            # I/O errors come with an errno, decompression errors do not
+            if e.errno is not None:
+                raise
+            raise _Bz2DecompressionError(*e.args) from e
+    return _read
+
+
+class _Bz2Reader(bz2.BZ2File):
+    """Reads a bz2-compressed file, raising a _Bz2DecompressionError for
+        corrupt data - which bz2 reports as a plain OSError, just like any
+        I/O error."""
+    read = _bz2_errors(bz2.BZ2File.read)
+    read1 = _bz2_errors(bz2.BZ2File.read1)
+    readinto = _bz2_errors(bz2.BZ2File.readinto)
+    readline = _bz2_errors(bz2.BZ2File.readline)
+    peek = _bz2_errors(bz2.BZ2File.peek)
+
+
+# magic bytes of compressed input formats which can be read directly
+# (BGZF files are read as any other gzip file)
+INPUT_OPENERS = {
+    b'\x1f\x8b': gzip.open,
+    b'BZh': _Bz2Reader,
+    b'\xfd7zXZ\x00': lzma.open,
+}
+ZSTD_MAGIC = b'\x28\xb5\x2f\xfd'
+
+
+def _map_file(file_in: str):
+    """Opens a file for binary reading through a read-only memory map,
+        which saves the system calls and copies of buffered reads - files
+        which cannot be mapped (e.g., empty ones) are opened as usual."""
+    with open(file_in, 'rb') as f_in:
+        try:
+            return mmap.mmap(f_in.fileno(), 0, access=mmap.ACCESS_READ)
+        except (ValueError, OSError):
+            return open(file_in, 'rb')
+
+
+def _open_fastq(file_in: str):
+    """Opens a FASTQ file for binary reading, decompressing it on the fly
+        if required.
+
+    Compression is detected from the first bytes of the file rather than
+    from its extension. Uncompressed files are memory-mapped (see
+    _map_file). Instead of a path, standard input ('-'), a file
+    descriptor (e.g., of a pipe), a binary file object or an in-memory
+    buffer can be read - those are not closed with the returned file.
+
+    Raises:
+        FastqRewriteError: If the file is compressed in an unsupported way.
+    """
+    if file_in == '-':
+        file_in = sys.stdin.fileno()
+    if isinstance(file_in, int):
+        file_in = os.fdopen(file_in, 'rb', closefd=False)
+    elif isinstance(file_in, (bytearray, memoryview)):
+        file_in = io.BytesIO(file_in)
+    if hasattr(file_in, 'read'):
+        magic = file_in.read(8)
+        file_in = io.BufferedReader(_PrefixedReader(magic, file_in))
+    else:
+        file_in = os.fsdecode(file_in)
+        with open(file_in, 'rb') as f_in:
+            magic = f_in.read(8)
+    for prefix, opener in INPUT_OPENERS.items():
+        if magic.startswith(prefix):
+            return opener(file_in, 'rb')
+    if magic.startswith(ZSTD_MAGIC):
+        raise FastqRewriteError(
+            f'Sequences from {file_in} are zstd-compressed, which is not '
+            f'supported. Please decompress the file first.'
+        )
+    return file_in if hasattr(file_in, 'read') else _map_file(file_in)
+
+
+def _compress_bgzf_block(block: bytes, compression_level: int) -> bytes:
+    """Compresses data into a single BGZF block (a gzip member with
+        the block size stored in the extra field of the header)."""
+    compressor = zlib.compressobj(compression_level, zlib.DEFLATED, -15)
+    data = compressor.compress(block) + compressor.flush()
+    header = struct.pack(
+        '<BBBBIBBHBBHH', 31, 139, 8, 4, 0, 0, 255, 6, 66, 67, 2,
+        len(data) + 25
+    )
+    footer = struct.pack('<II', zlib.crc32(block), len(block))
+    return header + data + footer
+
+
+def _available_cores() -> int:
+    """Returns the number of CPU cores the process may run on."""
+    if hasattr(os, 'sched_getaffinity'):
+        return len(os.sched_getaffinity(0))
+    return os.cpu_count() or 1
+
+
+class _AdaptiveLevel:
+    """Adjusts the compression level of consecutive blocks, within the range
+        from min_level to max_level, depending on which stage is slower:
+        the level is lowered whenever compressed blocks had to be waited
+        for longer than it took to read and write them, and raised when
+        compression took less than a quarter of that time."""
+
+    def __init__(self, min_level: int = 1, max_level: int = 9):
+        self.min_level = min_level
+        self.max_level = max_level
+        self.level = (min_level + max_level) // 2
+
+    def update(self, io_time: float, wait_time: float):
+        if wait_time > io_time:
+            self.level = max(self.level - 1, self.min_level)
+        elif 4 * wait_time < io_time:
+            self.level = min(self.level + 1, self.max_level)
+
+
+def _compress_blocks(
+        f_in, f_out, threads: int, compression_level: Union[int, str],
+        block_size: int, compress=_compress_gzip_member,
+        adaptive: _AdaptiveLevel = None
+) -> List[Tuple[int, int]]:
+    """Compresses a stream using multiple threads.
+
+    The input is split into blocks of block_size bytes which are compressed
+    concurrently and written out, in order, as independent gzip members -
+    any gzip reader will decompress them as one stream. Only a limited
+    number of blocks is kept in memory at any time. When given, the
+    adaptive level chooses the compression level of every block instead
+    of compression_level (see _AdaptiveLevel).
+
+    Returns:
+        List[Tuple[int, int]]: Compressed and uncompressed offsets
+            at which every written block starts.
+    """
+    offsets, offset_in = [], 0
+    pending = deque()
+    io_time = 0.0
+
+    def _write_next():
+        nonlocal io_time
+        future, size = pending.popleft()
+        offsets.append((f_out.tell(), offset_in))
+        start = time.monotonic()
+        data = future.result()
+        waited = time.monotonic() - start
+        f_out.write(data)
+        io_time += time.monotonic() - start - waited
+        if adaptive:
+            adaptive.update(io_time, waited)
+            io_time = 0.0
+        return size
+
+    with ThreadPoolExecutor(max_workers=threads) as executor:
+        while True:
+            start = time.monotonic()
+            block = f_in.read(block_size)
+            io_time += time.monotonic() - start
+            if not block:
+                break
+            level = adaptive.level if adaptive else compression_level
+            pending.append(
+                (executor.submit(compress, block, level), len(block))
+            )
+            if len(pending) >= 2 * threads:
+                offset_in += _write_next()
+        while pending:
+            offset_in += _write_next()
+    return offsets
+
+
+def _write_bgzf_index(offsets: List[Tuple[int, int]], index_path: str):
+    """Writes a bgzip-compatible (.gzi) index of BGZF blocks.
+
+    The index holds the number of entries followed by compressed and
+    uncompressed offsets of every block except for the first one.
+    """
+    with open(index_path, 'wb') as f_index:
+        f_index.write(struct.pack('<Q', len(offsets[1:])))
+        for offset_out, offset_in in offsets[1:]:
+            f_index.write(struct.pack('<QQ', offset_out, offset_in))
+
+
+class _HashingWriter:
+    """Wraps a binary file opened for writing and computes digests of all
+        the data written through it (e.g., with 'md5' or 'sha256') - the
+        amount of data written is reported to the optional progress
+        reporter. Short writes (e.g., to unbuffered file objects) are
+        continued until all the data are written."""
+
+    def __init__(
+            self, f_out, algorithms: Iterable[str] = (),
+            progress: _ProgressReporter = None
+    ):
+        self.f_out = f_out
+        self.hashers = {x: hashlib.new(x) for x in algorithms}
+        self.progress = progress
+        self._position = 0
+
+    def write(self, data: bytes) -> int:
+        for hasher in self.hashers.values():
+            hasher.update(data)
+        self._position += len(data)
+        if self.progress:
+            self.progress.written(len(data))
+        view = memoryview(data).cast('B')
+        while view:
+            written = self.f_out.write(view)
+            if written == 0:
+                raise OSError('No data could be written.')
+            # writers without a count (e.g., _BufferWriter) take everything
+            view = view[len(view) if written is None else written:]
+        return len(data)
+
+    def tell(self) -> int:
+        return self._position
+
+    def flush(self):
+        self.f_out.flush()
+
+    def digests(self) -> Dict[str, str]:
+        return {x: hasher.hexdigest() for x, hasher in self.hashers.items()}
+
+
+class _PipelineReader(io.RawIOBase):
+    """Reads a binary stream ahead in a background thread, handing blocks
+        of block_size bytes over through a queue of at most depth blocks,
+        so that reading (e.g., decompressing or parsing) the stream
+        overlaps with the work done on the data. Errors raised while
+        reading are re-raised to the consumer. The stream is closed
+        along."""
+
+    def __init__(
+            self, f_in, block_size: int = COPY_BLOCK_SIZE,
+            depth: int = PIPELINE_DEPTH
+    ):
+        super().__init__()
+        self.f_in = f_in
+        self.block_size = block_size
+        self._queue = queue.Queue(depth)
+        self._stopped = Event()
+        self._buffer = b''
+        self._eof = False
+        self._thread = Thread(target=self._produce, daemon=True)
+        self._thread.start()
+
+    def _produce(self):
+        try:
+            for block in iter(lambda: self.f_in.read(self.block_size), b''):
+                if not self._put(block):
+                    return
+            self._put(b'')
+        except BaseException as e:
+            self._put(e)
+
+    def _put(self, item) -> bool:
+        # give up once the consumer stopped reading
+        while not self._stopped.is_set():
+            try:
+                self._queue.put(item, timeout=0.1)
+                return True
+            except queue.Full:
+                pass
+        return False
+
+    def readable(self) -> bool:
+        return True
+
+    def read(self, size: int = -1) -> bytes:
+        chunks, length = [self._buffer], len(self._buffer)
+        while not self._eof and (size < 0 or length < size):
+            item = self._queue.get()
+            if isinstance(item, BaseException):
+                self._eof = True
+                raise item
+            if not item:
+                self._eof = True
+                break
+            chunks.append(item)
+            length += len(item)
+        data = b''.join(chunks)
+        size = len(data) if size < 0 else size
+        self._buffer = data[size:]
+        return data[:size]
+
+    def readinto(self, buffer) -> int:
+        data = self.read(len(buffer))
+        buffer[:len(data)] = data
+        return len(data)
+
+    def close(self):
+        if not self.closed:
+            self._stopped.set()
+            self._thread.join()
+            self.f_in.close()
+        super().close()
+
+
+class _PipelineWriter:
+    """Wraps a _HashingWriter and writes all the data to it in a background
+        thread, queueing at most depth blocks, so that hashing and writing
+        overlap with compression. Errors raised while writing are re-raised
+        on the next write or flush and once the writer is closed."""
+
+    def __init__(self, f_out: _HashingWriter, depth: int = PIPELINE_DEPTH):
+        self.f_out = f_out
+        self._queue = queue.Queue(depth)
+        self._position = 0
+        self._error = None
+        self._thread = Thread(target=self._consume, daemon=True)
+        self._thread.start()
+
+    def _consume(self):
+        while True:
+            data = self._queue.get()
+            try:
+                if data is None:
+                    return
+                # keep emptying the queue after a failure
+                if self._error is None:
+                    self.f_out.write(data)
+            except BaseException as e:
+                self._error = e
+            finally:
+                self._queue.task_done()
+
+    def _check(self):
+        if self._error is not None:
+            raise self._error
+
+    def write(self, data: bytes) -> int:
+        self._check()
+        self._queue.put(bytes(data))
+        self._position += len(data)
+        return len(data)
+
+    def tell(self) -> int:
+        return self._position
+
+    def flush(self):
+        self._queue.join()
+        self._check()
+        self.f_out.flush()
+
+    def close(self):
+        if self._thread.is_alive():
+            self._queue.put(None)
+            self._thread.join()
+        self._check()
+
+    def digests(self) -> Dict[str, str]:
+        return self.f_out.digests()
+
+
+class _BufferWriter:
+    """Appends all the data written to it to a bytearray."""
+
+    def __init__(self, buffer: bytearray):
+        self.write = buffer.extend
+
+    def flush(self):
+        pass
+
+
+def _open_output(
+        file_out: Union[str, int], buffer_size: int = WRITE_BUFFER_SIZE
+):
+    """Opens a path, standard output ('-'), a file descriptor, a binary
+        file object or a bytearray for writing binary data - descriptors
+        and objects are left open when the returned file gets closed.
+        Paths and descriptors are written through a buffer of buffer_size
+        bytes."""
+    if file_out == '-':
+        sys.stdout.flush()
+        file_out = sys.stdout.fileno()
+    if isinstance(file_out, int):
+        return os.fdopen(file_out, 'wb', buffering=buffer_size, closefd=False)
+    if isinstance(file_out, bytearray):
+        return nullcontext(_BufferWriter(file_out))
+    if hasattr(file_out, 'write'):
+        return nullcontext(file_out)
+    return open(file_out, 'wb', buffering=buffer_size)
+
+
+def _compress_stream(
+        f_in, file_out: Union[str, int], compression_level: Union[int, str],
+        threads: int, output_format: str, checksums: Iterable[str] = (),
+        min_free_space: int = None, progress: _ProgressReporter = None,
+        read_buffer_size: int = COPY_BLOCK_SIZE,
+        write_buffer_size: int = WRITE_BUFFER_SIZE, pipeline: bool = False,
+        block_size: int = COMPRESSION_BLOCK_SIZE
+) -> Tuple[int, Dict[str, str]]:
+    """Compresses a binary stream into a gzip or BGZF file (or into
+        a stream, see _open_output - BGZF indices are only written
+        next to files). Writing files fails with an InsufficientSpaceError
+        once less than min_free_space bytes are left, if given. Unless
+        compressed in blocks, the stream is read read_buffer_size bytes
+        at a time. With multiple threads, gzip output is compressed in
+        blocks of block_size bytes. With pipeline, the compressed data are
+        written in a separate thread (see _PipelineWriter). With the 'auto'
+        compression level, the input is always compressed in blocks, with
+        their levels adjusted to the throughput (see _AdaptiveLevel).
+
+    Returns:
+        Tuple[int, Dict[str, str]]: Number of written bytes and hex digests
+            of the written data, computed while writing with all the hash
+            algorithms from `checksums`.
+    """
+    with _open_output(file_out, write_buffer_size) as f_raw:
+        if min_free_space and not _is_stream(file_out):
+            f_raw = _SpaceWatcher(f_raw, file_out, min_free_space)
+        f_out = _HashingWriter(f_raw, checksums, progress)
+        if pipeline:
+            f_out = _PipelineWriter(f_out)
+        adaptive = _AdaptiveLevel(*AUTO_COMPRESSION_LEVELS) \
+            if compression_level == 'auto' else None
+        try:
+            if output_format == 'bgzf':
+                offsets = _compress_blocks(
+                    f_in, f_out, threads, compression_level,
+                    BGZF_BLOCK_SIZE, _compress_bgzf_block, adaptive
+                )
+                f_out.write(BGZF_EOF)
+            elif threads > 1 or adaptive:
+                _compress_blocks(
+                    f_in, f_out, threads, compression_level, block_size,
+                    adaptive=adaptive
+                )
+                # make sure an empty input still results in a valid gzip
+                if f_out.tell() == 0:
+                    f_out.write(_compress_gzip_member(
+                        b'', adaptive.level if adaptive else compression_level
+                    ))
+            else:
+                with _gzip_writer(f_out, compression_level) as f_gz:
+                    shutil.copyfileobj(f_in, f_gz, read_buffer_size)
+        finally:
+            if pipeline:
+                f_out.close()
+    if adaptive:
+        LOGGER.debug(
+            'Compressed the last block of %s with level %s.', file_out,
+            adaptive.level
+        )
+    if output_format == 'bgzf' and not _is_stream(file_out):
+        _write_bgzf_index(offsets, f'{file_out}.gzi')
+    return f_out.tell(), f_out.digests()
+
+
+def _concat_gzip_files(inputs: List[str], file_out: str) -> int:
+    """Concatenates gzip files without recompressing them.
+
+    The gzip format allows multiple members in a single file, so the
+    inputs are copied byte by byte into a multi-member gzip file which
+    decompresses into the concatenated sequences. The file is only written
+    once all of the inputs were copied (see _atomic_output).
+
+    Args:
+        inputs (List[str]): Paths to the gzip-compressed FASTQ files, in
+            the order in which they should be concatenated.
+        file_out (str): Path where the concatenated file should be written.
+
+    Returns:
+        int: Size of the written file.
+
+    Raises:
+        FastqRewriteError: If any of the input files is not gzip-compressed
+            or the files could not be copied.
+    """
+    try:
+        with _atomic_output(file_out) as tmp_out, \
+                open(tmp_out, 'wb') as f_out:
+            for file_in in inputs:
+                with open(file_in, 'rb') as f_in:
+                    if f_in.read(2) != b'\x1f\x8b':
+                        raise FastqRewriteError(
+                            f'Sequences from {file_in} are not '
+                            f'gzip-compressed and cannot be concatenated '
+                            f'without recompression.'
+                        )
+                    f_in.seek(0)
+                    shutil.copyfileobj(f_in, f_out)
+    except OSError as e:
+        raise FastqRewriteError(
+            f'Sequences from {", ".join(inputs)} could not be written '
+            f'to {file_out}: {e}'
+        ) from e
+    return os.path.getsize(file_out)
+
+
+def _skip_gzip_header(f_in) -> bool:
+    """Reads the header of a gzip member - returns False at the end of the
+        file (ignoring any zero padding) and raises a ValueError if the
+        header is invalid."""
+    magic = f_in.read(2)
+    while magic[:1] == b'\x00':
+        magic = magic[1:] + f_in.read(1)
+    if not magic:
+        return False
+    header = magic + f_in.read(8)
+    if len(header) < 10 or header[:3] != b'\x1f\x8b\x08':
+        raise ValueError('invalid gzip header')
+    flags = header[3]
+    if flags & 4:  # FEXTRA
+        extra_length, = struct.unpack('<H', f_in.read(2))
+        f_in.read(extra_length)
+    for flag in (8, 16):  # FNAME and FCOMMENT are zero-terminated
+        if flags & flag:
+            while f_in.read(1) not in (b'\x00', b''):
+                pass
+    if flags & 2:  # FHCRC
+        f_in.read(2)
+    return True
+
+
+class _PushbackReader:
+    """Reads from a binary file object, which need not be seekable, and
+        takes back data which were read beyond the point of interest - the
+        position is the offset in the file object's data."""
+
+    def __init__(self, f_in):
+        self.f_in = f_in
+        self.position = 0
+        self._pending = b''
+
+    def read(self, size: int) -> bytes:
+        data, self._pending = self._pending[:size], self._pending[size:]
+        while len(data) < size:
+            block = self.f_in.read(size - len(data))
+            if not block:
+                break
+            data += block
+        self.position += len(data)
+        return data
+
+    def unread(self, data: bytes):
+        self._pending = data + self._pending
+        self.position -= len(data)
+
+
+def _check_gzip(
+        file_in: Union[str, io.IOBase], block_size: int = 1024 ** 2
+) -> int:
+    """Fully decompresses a gzip file (or binary stream), checking the
+        CRC32 and ISIZE of every member - returns the number of members.
+        The input is read once, front to back: data following the end
+        of a member are passed on to the next one rather than seeked to."""
+    name = 'gzip stream' if _is_file_like(file_in) \
+        else os.fsdecode(file_in)
+    members = 0
+    opened = nullcontext(file_in) if _is_file_like(file_in) \
+        else open(name, 'rb')
+    with opened as f_raw:
+        f_in = _PushbackReader(f_raw)
+        while True:
+            offset = f_in.position
+            try:
+                if not _skip_gzip_header(f_in):
+                    break
+            except (ValueError, struct.error) as e:
+                raise CorruptGzipError(name, members + 1, offset, str(e))
+            members += 1
+            decompressor = zlib.decompressobj(-zlib.MAX_WBITS)
+            crc, size = 0, 0
+            try:
+                while not decompressor.eof:
+                    block = f_in.read(block_size)
+                    if not block:
+                        raise zlib.error('unexpected end of file')
+                    data = decompressor.decompress(block)
+                    crc = zlib.crc32(data, crc)
+                    size += len(data)
+            except zlib.error as e:
+                raise CorruptGzipError(name, members, offset, str(e))
+            f_in.unread(decompressor.unused_data)
+            trailer = f_in.read(8)
+            if len(trailer) < 8:
+                raise CorruptGzipError(
+                    name, members, offset, 'trailer is missing'
+                )
+            exp_crc, exp_size = struct.unpack('<II', trailer)
+            if exp_crc != crc:
+                raise CorruptGzipError(
+                    name, members, offset, 'CRC32 check failed'
+                )
+            if exp_size != size & 0xffffffff:
+                raise CorruptGzipError(
+                    name, members, offset, 'ISIZE check failed'
+                )
+    if not members:
+        raise CorruptGzipError(name, 1, 0, 'file is empty')
+    return members
+
+
+def verify_gzip(
+        file_in: Union[str, io.IOBase], raise_error: bool = False
+) -> bool:
+    """Checks the integrity of every member of a gzip (or BGZF) file.
+
+    Args:
+        file_in (Union[str, io.IOBase]): Path to the file - or a binary
+            stream, which is read once and need not be seekable.
+        raise_error (bool, default=False): Whether a CorruptGzipError
+            should be raised for corrupt files instead of returning False.
+
+    Returns:
+        bool: Whether the file could be fully decompressed and the CRC32
+            and ISIZE of all its members are correct.
+    """
+    try:
+        _check_gzip(file_in)
+    except CorruptGzipError as e:
+        if raise_error:
+            raise
+        LOGGER.warning(str(e))
+        return False
+    return True
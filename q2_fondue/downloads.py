@@ -0,0 +1,948 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import ftplib
+import http.client
+import json
+import os
+import random
+import shutil
+import socket
+import ssl
+import subprocess
+import time
+import urllib.error
+import urllib.parse
+import urllib.request
+from concurrent.futures import ThreadPoolExecutor
+from dataclasses import dataclass, replace
+from threading import Lock, Thread
+from typing import Dict, Iterable, List, Optional, Tuple, Union
+
+from tqdm import tqdm
+
+from q2_fondue.bam import _bam_to_fastq
+from q2_fondue.entrezpy_clients._utils import set_up_logger
+from q2_fondue.fastq import _deinterleave
+from q2_fondue.utils import (
+    DownloadError, FastqRewriteError, FastqStats, TEMP_FILES,
+    _casava_filename, _file_checksum, _remove_files
+)
+
+LOGGER = set_up_logger('INFO', logger_name=__name__)
+
+# NCBI's SRA data locator, resolving run accessions to download links
+SDL_URL = 'https://locate.ncbi.nlm.nih.gov/sdl/2/retrieve'
+# ENA's file reports, listing the FASTQ files of mirrored runs
+ENA_FILEREPORT_URL = 'https://www.ebi.ac.uk/ena/portal/api/filereport'
+DOWNLOAD_BLOCK_SIZE = 1024 ** 2
+# errors of the network (rather than the local file system) which are
+# worth retrying - HTTP errors are retried depending on their status
+RETRYABLE_ERRORS = (
+    urllib.error.URLError, ConnectionError, TimeoutError, socket.gaierror,
+    ssl.SSLError, http.client.HTTPException, ftplib.error_temp,
+    ftplib.error_reply, EOFError
+)
+DOWNLOAD_TIMEOUT = 60
+# rough ratios used to estimate disk footprints from .sra sizes: FASTQ files
+# dumped by fasterq-dump are several times larger than the .sra objects,
+# needing about as much scratch space again, and shrink to about a quarter
+# once compressed
+FASTQ_EXPANSION = 5
+SCRATCH_EXPANSION = 1
+GZIP_RATIO = 0.25
+
+
+@dataclass
+class RemoteFile:
+    """A file which can be downloaded for a run.
+
+    Attributes:
+        name (str): Name under which the file should be saved.
+        url (str): Download link.
+        size (int): Size of the file in bytes, if known.
+        md5 (str): MD5 digest of the file, if known.
+    """
+    name: str
+    url: str
+    size: int = None
+    md5: str = None
+
+
+@dataclass
+class RetryPolicy:
+    """Determines how failed network operations are retried.
+
+    Delays grow exponentially with every retry, up to max_delay, and are
+    shortened by a random fraction of up to jitter - concurrent downloads
+    failing together thus do not all retry at the same time.
+
+    Attributes:
+        max_attempts (int): Maximal number of attempts, including the
+            first one.
+        base_delay (float): Seconds to wait before the first retry.
+        max_delay (float): Maximal number of seconds to wait.
+        jitter (float): Maximal fraction (between 0 and 1) by which the
+            delays are randomly shortened.
+        retry_on_status (Tuple[int, ...]): HTTP status codes of errors
+            which should be retried - network errors without a response
+            (e.g., timeouts or connection resets) are always retried,
+            local errors (e.g., a full disk or missing permissions) never.
+    """
+    max_attempts: int = 3
+    base_delay: float = 5
+    max_delay: float = 300
+    jitter: float = 0.5
+    retry_on_status: Tuple[int, ...] = (408, 429, 500, 502, 503, 504)
+
+    def __post_init__(self):
+        if self.max_attempts < 1:
+            raise ValueError(
+                f'Maximal number of attempts must be at least 1 '
+                f'(got {self.max_attempts}).'
+            )
+        if not 0 <= self.jitter <= 1:
+            raise ValueError(
+                f'Jitter must be between 0 and 1 (got {self.jitter}).'
+            )
+
+    def delay(self, attempt: int) -> float:
+        """Seconds to wait after the given (0-based) failed attempt."""
+        delay = min(self.base_delay * 2 ** attempt, self.max_delay)
+        return delay * (1 - self.jitter * random.random())
+
+    def should_retry(self, error: Exception) -> bool:
+        if isinstance(error, urllib.error.HTTPError):
+            return error.code in self.retry_on_status
+        return isinstance(error, RETRYABLE_ERRORS)
+
+
+class _Throttle:
+    """Limits the rate at which data are transferred - shared by all the
+        segments of a download, so that the limit holds for their sum."""
+
+    def __init__(self, max_bandwidth: int):
+        if max_bandwidth <= 0:
+            raise ValueError(
+                f'Maximal bandwidth must be positive (got {max_bandwidth}).'
+            )
+        self.max_bandwidth = max_bandwidth
+        self.start = time.monotonic()
+        self.transferred = 0
+        self.lock = Lock()
+
+    def consume(self, size: int):
+        """Accounts for size transferred bytes and waits for as long as
+            the transfer is ahead of the bandwidth limit."""
+        with self.lock:
+            self.transferred += size
+            wait = self.start + self.transferred / self.max_bandwidth \
+                - time.monotonic()
+        if wait > 0:
+            time.sleep(wait)
+
+
+def _proxies(proxy: str = None) -> dict:
+    """Resolves the proxies to be used for requests per URL scheme.
+
+    An explicit proxy is used for all the requests. Otherwise, proxies
+    are taken from the environment (e.g., HTTPS_PROXY) - with ALL_PROXY
+    applying to the schemes without a proxy of their own.
+
+    Raises:
+        ValueError: If the explicit proxy is a SOCKS proxy, which is not
+            supported - SOCKS proxies from the environment are ignored.
+    """
+    if proxy:
+        if proxy.lower().startswith('socks'):
+            raise ValueError(
+                f'SOCKS proxies are not supported (got {proxy}) - please '
+                f'provide an HTTP(S) proxy.'
+            )
+        return {scheme: proxy for scheme in ('http', 'https', 'ftp')}
+    proxies = urllib.request.getproxies()
+    fallback = proxies.pop('all', None)
+    if fallback:
+        for scheme in ('http', 'https', 'ftp'):
+            proxies.setdefault(scheme, fallback)
+    for scheme, url in list(proxies.items()):
+        if scheme != 'no' and url.lower().startswith('socks'):
+            LOGGER.warning(
+                'SOCKS proxy %s is not supported and is ignored for %s '
+                'requests.', url, scheme
+            )
+            del proxies[scheme]
+    return proxies
+
+
+def _urlopen(request: urllib.request.Request, proxy: str = None):
+    """Opens a request through the proxy resolved by _proxies."""
+    opener = urllib.request.build_opener(
+        urllib.request.ProxyHandler(_proxies(proxy))
+    )
+    return opener.open(request, timeout=DOWNLOAD_TIMEOUT)
+
+
+def _fetch_json(
+        url: str, params: dict = None, retry: RetryPolicy = None,
+        proxy: str = None
+) -> dict:
+    """Retrieves a JSON document, sending params as form data - failed
+        requests are retried according to the retry policy and raise the
+        last error once they should not be retried anymore."""
+    retry = retry or RetryPolicy()
+    data = urllib.parse.urlencode(params).encode() if params else None
+    for attempt in range(retry.max_attempts):
+        request = urllib.request.Request(url, data=data)
+        try:
+            with _urlopen(request, proxy) as response:
+                return json.loads(response.read().decode())
+        except (urllib.error.URLError, OSError) as e:
+            if attempt == retry.max_attempts - 1 or not retry.should_retry(e):
+                raise
+            delay = retry.delay(attempt)
+            LOGGER.warning(
+                'Request to %s failed (%s) - retrying in %.1f s.',
+                url, e, delay
+            )
+            time.sleep(delay)
+
+
+def _is_accessible(location: dict) -> bool:
+    """Whether a location can be downloaded from free of charge and from
+        anywhere - i.e., it requires neither payment nor a specific cloud
+        computing environment."""
+    return not any(
+        str(location.get(x, False)).lower() == 'true'
+        for x in ('payRequired', 'ceRequired')
+    )
+
+
+def _pick_location(
+        locations: List[dict], location: str = None
+) -> Optional[str]:
+    """Picks the download link of an object from the preferred location,
+        given as service and region (as in s3.us-east-1 or gs.us-east1)
+        or as service only (as in s3) - falls back to NCBI (or any other
+        location) if the object is not available from there. Locations
+        requiring payment or a cloud environment are skipped - None is
+        returned if there are no others."""
+    locations = [x for x in locations if _is_accessible(x)]
+    for preferred in filter(None, (location, 'ncbi')):
+        service, _, region = preferred.partition('.')
+        for x in locations:
+            if x.get('service') == service \
+                    and (not region or x.get('region') == region):
+                return x['link']
+    return locations[0]['link'] if locations else None
+
+
+def _locate_sra(
+        accession: str, location: str = None, retry: RetryPolicy = None,
+        proxy: str = None
+) -> List[RemoteFile]:
+    """Resolves the .sra object (and its .vdbcache, if there is any)
+        of a run through NCBI's data locator.
+
+    Args:
+        accession (str): Run accession.
+        location (str, default=None): Preferred location of the objects,
+            e.g., s3.us-east-1 for the AWS or gs.us-east1 for the GCP
+            Open Data mirrors (see _pick_location) - the locator is asked
+            for links from there and falls back to NCBI otherwise.
+        retry (RetryPolicy, default=None): How failed requests should be
+            retried - with the defaults of RetryPolicy if not provided.
+        proxy (str, default=None): Proxy for the requests - see _proxies.
+
+    Raises:
+        DownloadError: If the run could not be located.
+    """
+    params = {
+        'acc': accession, 'filetype': 'sra,vdbcache',
+        'accept-alternate-locations': 'yes'
+    }
+    if location:
+        params['location'] = location
+    retry = retry or RetryPolicy()
+    try:
+        content = _fetch_json(SDL_URL, params, retry, proxy)
+    except (urllib.error.URLError, OSError, ValueError) as e:
+        raise DownloadError(
+            f'Run {accession} could not be located: {e}',
+            retryable=retry.should_retry(e)
+        )
+
+    files = []
+    for bundle in content.get('result', []):
+        if str(bundle.get('status')) != '200':
+            raise DownloadError(
+                f'Run {accession} could not be located: '
+                f'{bundle.get("msg", "unknown error")}'
+            )
+        for entry in bundle.get('files', []):
+            if entry.get('type') not in ('sra', 'vdbcache') \
+                    or not entry.get('locations'):
+                continue
+            suffix = '.sra' if entry['type'] == 'sra' else '.sra.vdbcache'
+            link = _pick_location(entry['locations'], location)
+            if link is None:
+                raise DownloadError(
+                    f'Run {accession} is only available from locations '
+                    f'which require payment or a cloud environment.'
+                )
+            files.append(RemoteFile(
+                f'{accession}{suffix}', link, entry.get('size'),
+                entry.get('md5')
+            ))
+    if not files:
+        raise DownloadError(f'No SRA objects were found for run {accession}.')
+    LOGGER.debug(
+        'Located %s: %s.', accession,
+        ', '.join(f'{x.name} at {x.url}' for x in files)
+    )
+    return files
+
+
+def _download_part(
+        url: str, part_out: str, start: int = 0, size: int = None,
+        segment: bool = False, retry: RetryPolicy = None,
+        pbar: tqdm = None, throttle: _Throttle = None, proxy: str = None
+):
+    """Downloads a file, or the segment of size bytes from start on, into
+        part_out - resuming from the data already in there.
+
+    Raises:
+        DownloadError: If the data could not be downloaded.
+    """
+    retry = retry or RetryPolicy()
+    for attempt in range(retry.max_attempts):
+        offset = os.path.getsize(part_out) \
+            if os.path.exists(part_out) else 0
+        if size is not None and offset >= size:
+            return
+        request = urllib.request.Request(url)
+        if offset or segment:
+            end = start + size - 1 if segment else ''
+            request.add_header('Range', f'bytes={start + offset}-{end}')
+        LOGGER.debug(
+            'Requesting %s (%s).', url,
+            request.get_header('Range') or 'whole file'
+        )
+        try:
+            with _urlopen(request, proxy) as response:
+                if response.status != 206 and segment:
+                    raise DownloadError(
+                        f'{url} cannot be downloaded in segments - the '
+                        f'server does not support Range requests.'
+                    )
+                if offset and response.status != 206:
+                    if pbar:
+                        pbar.update(-offset)
+                    offset = 0
+                with open(part_out, 'ab' if offset else 'wb') as f_out:
+                    for block in iter(
+                            lambda: response.read(DOWNLOAD_BLOCK_SIZE), b''
+                    ):
+                        f_out.write(block)
+                        if pbar:
+                            pbar.update(len(block))
+                        if throttle:
+                            throttle.consume(len(block))
+            if size is None or os.path.getsize(part_out) >= size:
+                return
+            error, retriable = 'connection closed early', True
+        except (urllib.error.URLError, OSError) as e:
+            error, retriable = str(e), retry.should_retry(e)
+        _wait_to_resume(url, error, retriable, attempt, retry)
+
+
+def _wait_to_resume(
+        url: str, error: str, retriable: bool, attempt: int,
+        retry: RetryPolicy
+):
+    """Waits before an interrupted download is resumed, as long as the
+        retry policy allows for it.
+
+    Raises:
+        DownloadError: If the download should not be resumed anymore.
+    """
+    if not retriable:
+        raise DownloadError(f'{url} could not be downloaded: {error}')
+    if attempt == retry.max_attempts - 1:
+        raise DownloadError(
+            f'{url} could not be downloaded after {attempt + 1} '
+            f'attempts: {error}', retryable=True
+        )
+    delay = retry.delay(attempt)
+    LOGGER.warning(
+        'Download of %s was interrupted (%s) - resuming in %.1f s.',
+        url, error, delay
+    )
+    time.sleep(delay)
+
+
+def _download_file(
+        url: str, file_out: str, size: int = None, retry: RetryPolicy = None,
+        progress: bool = False, segments: int = 1, max_bandwidth: int = None,
+        proxy: str = None
+):
+    """Downloads a file over HTTP(S), resuming interrupted downloads.
+
+    The data are written to `{file_out}.part`, which is only renamed
+    to file_out once it is complete. Any existing partial download is
+    continued with a Range request - if the server does not honour
+    that, the download starts over.
+
+    Files of a known size can be downloaded in multiple segments at once,
+    each with a Range request of its own - the segments are written to
+    `{file_out}.part.{n}` (and resumed from there) until they are all
+    complete and joined. Partial downloads are tracked as temporary files
+    (see TEMP_FILES) - they are only left behind for later runs to resume
+    if the process is killed or temporary files should be kept.
+
+    Args:
+        url (str): Download link.
+        file_out (str): Path where the file should be saved.
+        size (int, default=None): Expected size of the file, if known.
+        retry (RetryPolicy, default=None): How failed downloads (or
+            downloads of segments) should be resumed - with the defaults
+            of RetryPolicy if not provided.
+        progress (bool, default=False): Whether a progress bar should
+            be shown.
+        segments (int, default=1): Number of segments to be downloaded
+            concurrently - files of unknown size are always downloaded
+            in one piece.
+        max_bandwidth (int, default=None): Maximal download rate in
+            bytes per second, across all segments - unlimited if not
+            provided.
+        proxy (str, default=None): Proxy for the requests - see _proxies.
+
+    Raises:
+        DownloadError: If the file could not be downloaded.
+    """
+    if segments < 1:
+        raise ValueError(
+            f'Number of segments must be at least 1 (got {segments}).'
+        )
+    throttle = _Throttle(max_bandwidth) if max_bandwidth else None
+    part_out = f'{file_out}.part'
+    if size is None or size < segments:
+        segments = 1
+    if segments > 1:
+        # all the segments but the last one are of the same size
+        length = size // segments
+        bounds = [
+            (n * length, length if n < segments - 1 else size - n * length)
+            for n in range(segments)
+        ]
+        paths = [f'{part_out}.{n}' for n in range(segments)]
+    else:
+        bounds, paths = [(0, size)], [part_out]
+    for path in {part_out, *paths}:
+        TEMP_FILES.register(path)
+    initial = sum(
+        os.path.getsize(x) for x in paths if os.path.exists(x)
+    )
+    with tqdm(total=size, initial=initial, unit='B', unit_scale=True,
+              disable=not progress, desc=os.path.basename(file_out)) as pbar:
+        with ThreadPoolExecutor(max_workers=segments) as executor:
+            futures = [
+                executor.submit(
+                    _download_part, url, path, start, length, segments > 1,
+                    retry, pbar, throttle, proxy
+                ) for path, (start, length) in zip(paths, bounds)
+            ]
+            for future in futures:
+                future.result()
+    if segments > 1:
+        with open(part_out, 'wb') as f_out:
+            for path in paths:
+                with open(path, 'rb') as f_in:
+                    shutil.copyfileobj(f_in, f_out)
+        for path in paths:
+            os.remove(path)
+            TEMP_FILES.release(path)
+    os.replace(part_out, file_out)
+    TEMP_FILES.release(part_out)
+
+
+def _download_ftp(
+        url: str, file_out: str, size: int = None, retry: RetryPolicy = None,
+        progress: bool = False, max_bandwidth: int = None
+):
+    """Downloads a file over (anonymous) FTP, resuming interrupted
+        downloads - as _download_file does over HTTP(S), but always in
+        one piece and without any proxy.
+
+    Interrupted downloads are continued from the end of `{file_out}.part`
+    with a REST command, regardless of whether they were started over
+    FTP or HTTP(S).
+
+    Raises:
+        DownloadError: If the file could not be downloaded.
+    """
+    retry = retry or RetryPolicy()
+    throttle = _Throttle(max_bandwidth) if max_bandwidth else None
+    parsed = urllib.parse.urlparse(url)
+    part_out = f'{file_out}.part'
+    TEMP_FILES.register(part_out)
+    initial = os.path.getsize(part_out) if os.path.exists(part_out) else 0
+    with tqdm(total=size, initial=initial, unit='B', unit_scale=True,
+              disable=not progress, desc=os.path.basename(file_out)) as pbar:
+        for attempt in range(retry.max_attempts):
+            offset = os.path.getsize(part_out) \
+                if os.path.exists(part_out) else 0
+            if size is not None and offset >= size:
+                break
+            try:
+                with ftplib.FTP(timeout=DOWNLOAD_TIMEOUT) as ftp, \
+                        open(part_out, 'ab' if offset else 'wb') as f_out:
+                    ftp.connect(parsed.hostname, parsed.port or 21)
+                    ftp.login()
+                    LOGGER.debug(
+                        'Requesting %s over FTP (from byte %s).', url, offset
+                    )
+
+                    def _write(block):
+                        f_out.write(block)
+                        pbar.update(len(block))
+                        if throttle:
+                            throttle.consume(len(block))
+
+                    ftp.retrbinary(
+                        f'RETR {parsed.path}', _write, DOWNLOAD_BLOCK_SIZE,
+                        rest=offset or None
+                    )
+                if size is None or os.path.getsize(part_out) >= size:
+                    break
+                error, retriable = 'connection closed early', True
+            except ftplib.error_perm as e:
+                # e.g., the file does not exist
+                error, retriable = str(e), False
+            except ftplib.all_errors as e:
+                error, retriable = str(e), retry.should_retry(e)
+            _wait_to_resume(url, error, retriable, attempt, retry)
+    os.replace(part_out, file_out)
+    TEMP_FILES.release(part_out)
+
+
+def _download_remote(
+        remote: RemoteFile, file_out: str, retry: RetryPolicy = None,
+        progress: bool = False, segments: int = 1, max_bandwidth: int = None,
+        proxy: str = None
+):
+    """Downloads a remote file and verifies its MD5 checksum, if known -
+        files on FTP servers are downloaded with _download_ftp, in one
+        piece and without any proxy.
+
+    Raises:
+        DownloadError: If the file could not be downloaded or is not
+            intact - the file is removed then.
+    """
+    if urllib.parse.urlparse(remote.url).scheme == 'ftp':
+        _download_ftp(
+            remote.url, file_out, remote.size, retry, progress=progress,
+            max_bandwidth=max_bandwidth
+        )
+    else:
+        _download_file(
+            remote.url, file_out, remote.size, retry, progress=progress,
+            segments=segments, max_bandwidth=max_bandwidth, proxy=proxy
+        )
+    if remote.md5 and _file_checksum(file_out) != remote.md5:
+        os.remove(file_out)
+        raise DownloadError(
+            f'The MD5 checksum of {remote.name} does not match.'
+        )
+
+
+def _prefetch(
+        accession: str, out_dir: str, retry: RetryPolicy = None,
+        progress: bool = False, location: str = None, segments: int = 1,
+        max_bandwidth: int = None, proxy: str = None
+) -> List[str]:
+    """Downloads the .sra object of a run (and its .vdbcache, if there is
+        any) into `{out_dir}/{accession}`, as prefetch does - preferably
+        from the given location (see _locate_sra) and in the given number
+        of concurrently downloaded segments, at up to max_bandwidth bytes
+        per second (see _download_file) - through the given proxy or
+        those from the environment (see _proxies).
+
+    Returns:
+        List[str]: Paths to the downloaded files.
+
+    Raises:
+        DownloadError: If the run could not be located or downloaded, or
+            a downloaded file is not intact.
+    """
+    run_dir = os.path.join(out_dir, accession)
+    os.makedirs(run_dir, exist_ok=True)
+    paths = []
+    for remote in _locate_sra(accession, location, retry, proxy):
+        path = os.path.join(run_dir, remote.name)
+        _download_remote(
+            remote, path, retry, progress, segments, max_bandwidth, proxy
+        )
+        paths.append(path)
+    return paths
+
+
+def _dump_and_compress(
+        accession: str, sra_path: str, out_dir: str, threads: int = 1,
+        key_file: str = '', compression_level: int = 9,
+        min_free_space: int = None
+) -> Dict[str, FastqStats]:
+    """Runs fasterq-dump on a prefetched run and compresses its reads as
+        they are dumped.
+
+    fasterq-dump writes all the reads of every spot to its standard output,
+    from where they are split into forward, reverse and unpaired reads
+    (as with the --split-3 mode of fasterq-dump) and written to gzip files
+    right away - the uncompressed reads never touch the disk. Outputs
+    without any reads are removed again, such that single-end runs result
+    in `{accession}.fastq.gz` and paired-end runs in
+    `{accession}_1.fastq.gz` and `{accession}_2.fastq.gz`.
+
+    Args:
+        accession (str): Run accession.
+        sra_path (str): Path to the prefetched run (or its accession).
+        out_dir (str): Directory where the files should be written.
+        threads (int, default=1): Number of threads used by fasterq-dump.
+        key_file (str, default=''): Path to a dbGaP repository key.
+        compression_level (int, default=9): gzip compression level.
+        min_free_space (int, default=None): Minimal free space in bytes
+            on the output file system - fasterq-dump is stopped once less
+            space is left.
+
+    Returns:
+        Dict[str, FastqStats]: Statistics of all the written files,
+            by path.
+
+    Raises:
+        DownloadError: If fasterq-dump failed - no files are kept then.
+        InsufficientSpaceError: If the output file system ran low on
+            space - no files are kept then either.
+    """
+    key_params = ['--ngc', key_file] if key_file else []
+    if os.path.exists(sra_path):
+        # fasterq-dump runs in the output directory (where it also puts
+        # its temporary files), so relative paths would no longer resolve
+        sra_path = os.path.abspath(sra_path)
+    cmd = [
+        'fasterq-dump', '-e', str(threads), '--size-check', 'on',
+        '--stdout', '--split-spot', *key_params, sra_path
+    ]
+    paths = [
+        os.path.join(out_dir, f'{accession}{suffix}.fastq.gz')
+        for suffix in ('_1', '_2', '')
+    ]
+    stats, stderr = {}, []
+    LOGGER.debug('Running %s.', ' '.join(cmd))
+    proc = subprocess.Popen(
+        cmd, stdout=subprocess.PIPE, stderr=subprocess.PIPE, cwd=out_dir
+    )
+    # drain stderr concurrently so that fasterq-dump never blocks on it
+    stderr_reader = Thread(target=lambda: stderr.append(proc.stderr.read()))
+    stderr_reader.start()
+    try:
+        _deinterleave(
+            proc.stdout, *paths, compression_level=compression_level,
+            file_stats=stats, min_free_space=min_free_space
+        )
+    except BaseException:
+        proc.kill()
+        _remove_files(paths)
+        raise
+    finally:
+        proc.stdout.close()
+        returncode = proc.wait()
+        stderr_reader.join()
+    if returncode != 0:
+        _remove_files(paths)
+        raise DownloadError(
+            f'fasterq-dump failed for {accession} (exit code {returncode}): '
+            f'{b"".join(stderr).decode(errors="replace").strip()}'
+        )
+    for path in paths:
+        if not stats[path].reads:
+            os.remove(path)
+            del stats[path]
+    return stats
+
+
+def _locate_ena_fastq(
+        accession: str, retry: RetryPolicy = None, proxy: str = None
+) -> List[RemoteFile]:
+    """Resolves the FASTQ files of a run mirrored on ENA - or the BAM files
+        it was submitted as, for runs which have no FASTQ files (e.g.,
+        runs submitted as aligned reads only).
+
+    Returns:
+        List[RemoteFile]: Files of the run - an empty list if the run
+            is not available from ENA.
+
+    Raises:
+        DownloadError: If ENA could not be queried.
+    """
+    retry = retry or RetryPolicy()
+    try:
+        content = _fetch_json(ENA_FILEREPORT_URL, {
+            'accession': accession, 'result': 'read_run', 'format': 'json',
+            'fields': 'run_accession,fastq_ftp,fastq_md5,fastq_bytes,'
+                      'submitted_ftp,submitted_md5,submitted_bytes'
+        }, retry, proxy)
+    except (urllib.error.URLError, OSError, ValueError) as e:
+        # runs unknown to ENA are reported as missing
+        if isinstance(e, urllib.error.HTTPError) and e.code in (204, 404):
+            return []
+        raise DownloadError(
+            f'Run {accession} could not be located: {e}',
+            retryable=retry.should_retry(e)
+        )
+
+    files = []
+    for run in content or []:
+        kind = 'fastq' if run.get('fastq_ftp') else 'submitted'
+        if not run.get(f'{kind}_ftp'):
+            continue
+        md5s = run.get(f'{kind}_md5', '').split(';')
+        sizes = run.get(f'{kind}_bytes', '').split(';')
+        for i, link in enumerate(run[f'{kind}_ftp'].split(';')):
+            if kind == 'submitted' and not link.endswith('.bam'):
+                continue
+            files.append(RemoteFile(
+                os.path.basename(link), f'https://{link}',
+                int(sizes[i]) if i < len(sizes) and sizes[i] else None,
+                md5s[i] if i < len(md5s) and md5s[i] else None
+            ))
+    return files
+
+
+def _download_with_ftp_fallback(
+        remote: RemoteFile, path: str, retry: RetryPolicy, progress: bool,
+        max_bandwidth: int = None, proxy: str = None
+):
+    """Downloads a file from ENA over HTTPS - or from ENA's FTP service if
+        that fails, continuing from the data received so far."""
+    try:
+        _download_remote(
+            remote, path, retry, progress,
+            max_bandwidth=max_bandwidth, proxy=proxy
+        )
+    except DownloadError as e:
+        LOGGER.warning(
+            'Download of %s over HTTPS failed (%s) - falling back '
+            'to FTP.', remote.name, e
+        )
+        ftp_url = remote.url.replace('https://', 'ftp://', 1)
+        _download_remote(
+            replace(remote, url=ftp_url), path, retry, progress,
+            max_bandwidth=max_bandwidth
+        )
+
+
+def _fetch_bam_from_ena(
+        accession: str, remote: RemoteFile, out_dir: str,
+        retry: RetryPolicy = None, progress: bool = False,
+        max_bandwidth: int = None, proxy: str = None
+) -> List[str]:
+    """Downloads a submitted BAM file of a run from ENA and converts its
+        reads into a Casava directory (see _bam_to_fastq).
+
+    As for the runs ENA provides FASTQ files for, only the read pairs of
+    paired-end runs are kept - the BAM file itself is removed again.
+
+    Returns:
+        List[str]: Paths to the converted files.
+
+    Raises:
+        DownloadError: If the file could not be downloaded or converted.
+    """
+    bam_path = os.path.join(out_dir, f'{accession}.bam')
+    paths = [
+        os.path.join(out_dir, _casava_filename(accession, 1, True)),
+        os.path.join(out_dir, _casava_filename(accession, 2, True)),
+        os.path.join(out_dir, _casava_filename(accession))
+    ]
+    TEMP_FILES.register(bam_path)
+    try:
+        _download_with_ftp_fallback(
+            remote, bam_path, retry, progress, max_bandwidth, proxy
+        )
+        stats = _bam_to_fastq(bam_path, *paths)
+    except FastqRewriteError as e:
+        raise DownloadError(f'{remote.name} could not be converted: {e}')
+    finally:
+        _remove_files([bam_path])
+        TEMP_FILES.release(bam_path)
+    if not stats.pairs and not stats.singles + stats.orphans:
+        _remove_files(paths)
+        raise DownloadError(f'{remote.name} does not contain any reads.')
+    kept = paths[:2] if stats.pairs else paths[2:]
+    _remove_files(x for x in paths if x not in kept)
+    return kept
+
+
+def _fetch_from_ena(
+        accession: str, out_dir: str, retry: RetryPolicy = None,
+        progress: bool = False, max_bandwidth: int = None,
+        proxy: str = None
+) -> List[str]:
+    """Downloads the FASTQ files of a run from ENA straight into a Casava
+        directory (see _casava_filename), bypassing the SRA Toolkit.
+
+    ENA provides `{accession}.fastq.gz` for single-end runs and
+    `{accession}_1.fastq.gz` and `{accession}_2.fastq.gz` for paired-end
+    runs - reads without a mate, which ENA provides separately for
+    paired-end runs, are not downloaded. Runs without any FASTQ files
+    which were submitted as BAM files are converted from those instead
+    (see _fetch_bam_from_ena). The files are downloaded at up
+    to max_bandwidth bytes per second (see _download_file) - through the
+    given proxy or those from the environment (see _proxies). Files
+    which cannot be downloaded over HTTPS are downloaded from ENA's FTP
+    service instead, continuing from the data received so far.
+
+    Returns:
+        List[str]: Paths to the downloaded files - an empty list if the
+            run is not available from ENA.
+
+    Raises:
+        DownloadError: If a file could not be downloaded or is not intact.
+    """
+    remotes = {x.name: x for x in _locate_ena_fastq(accession, retry, proxy)}
+    paired = {f'{accession}_1.fastq.gz', f'{accession}_2.fastq.gz'}
+    if paired <= remotes.keys():
+        targets = [
+            (remotes[f'{accession}_{read}.fastq.gz'],
+             _casava_filename(accession, read, True))
+            for read in (1, 2)
+        ]
+    elif f'{accession}.fastq.gz' in remotes:
+        targets = [
+            (remotes[f'{accession}.fastq.gz'], _casava_filename(accession))
+        ]
+    elif any(x.endswith('.bam') for x in remotes):
+        bam = remotes[min(x for x in remotes if x.endswith('.bam'))]
+        LOGGER.info(
+            'Run %s is only available as aligned reads - converting %s.',
+            accession, bam.name
+        )
+        return _fetch_bam_from_ena(
+            accession, bam, out_dir, retry, progress, max_bandwidth, proxy
+        )
+    else:
+        LOGGER.debug('Run %s is not available from ENA.', accession)
+        return []
+
+    paths = []
+    try:
+        for remote, name in targets:
+            path = os.path.join(out_dir, name)
+            _download_with_ftp_fallback(
+                remote, path, retry, progress, max_bandwidth, proxy
+            )
+            paths.append(path)
+    except DownloadError:
+        # do not leave a single file of a pair behind
+        for path in paths:
+            os.remove(path)
+        raise
+    return paths
+
+
+@dataclass
+class SpaceEstimate:
+    """Predicted disk footprint of fetching a set of runs.
+
+    Attributes:
+        sra (int): Total size of the .sra objects in bytes.
+        temporary (int): Bytes needed at most while any one run is
+            processed - its .sra object, the uncompressed FASTQ files
+            and fasterq-dump's scratch space.
+        final (int): Total size of the compressed FASTQ files in bytes.
+    """
+    sra: int
+    temporary: int
+    final: int
+
+    @property
+    def total(self) -> int:
+        """Bytes needed at most - the compressed FASTQ files of all the
+            runs on top of the temporary space."""
+        return self.temporary + self.final
+
+
+def _remote_size(
+        url: str, retry: RetryPolicy = None, proxy: str = None
+) -> int:
+    """Determines the size of a remote file with a HEAD request.
+
+    Raises:
+        DownloadError: If the size could not be determined.
+    """
+    retry = retry or RetryPolicy()
+    for attempt in range(retry.max_attempts):
+        request = urllib.request.Request(url, method='HEAD')
+        try:
+            with _urlopen(request, proxy) as response:
+                size = response.headers.get('Content-Length')
+            if size is None:
+                raise DownloadError(f'The size of {url} is not known.')
+            return int(size)
+        except (urllib.error.URLError, OSError) as e:
+            _wait_to_resume(url, str(e), retry.should_retry(e), attempt, retry)
+
+
+def estimate_required_space(
+        accessions_or_sra_sizes: Union[Iterable[str], Dict[str, int]],
+        retry: RetryPolicy = None, proxy: str = None, location: str = None
+) -> SpaceEstimate:
+    """Predicts the disk space needed to fetch and dump a set of runs.
+
+    The estimate is derived from the sizes of the runs' .sra objects
+    (see FASTQ_EXPANSION, SCRATCH_EXPANSION and GZIP_RATIO), assuming
+    that the runs are processed one at a time and that only their
+    compressed FASTQ files are kept.
+
+    Args:
+        accessions_or_sra_sizes (Union[Iterable[str], Dict[str, int]]):
+            Run accessions, whose .sra objects (and .vdbcache files) are
+            then located (see _locate_sra) and HEAD-requested if their
+            size is not listed, or the .sra sizes of the runs in bytes
+            by accession.
+        retry (RetryPolicy, default=None): How failed requests should be
+            retried.
+        proxy (str, default=None): Proxy for the requests - see _proxies.
+        location (str, default=None): Preferred location of the .sra
+            objects (see _pick_location).
+
+    Returns:
+        SpaceEstimate: The predicted disk footprint in bytes.
+
+    Raises:
+        DownloadError: If the size of an .sra object could not be
+            determined.
+    """
+    if isinstance(accessions_or_sra_sizes, dict):
+        sizes = accessions_or_sra_sizes
+    else:
+        sizes = {}
+        for accession in accessions_or_sra_sizes:
+            sizes[accession] = sum(
+                x.size if x.size is not None
+                else _remote_size(x.url, retry, proxy)
+                for x in _locate_sra(accession, location, retry, proxy)
+            )
+
+    temporary = [
+        int(size * (1 + FASTQ_EXPANSION * (1 + SCRATCH_EXPANSION)))
+        for size in sizes.values()
+    ]
+    return SpaceEstimate(
+        sra=sum(sizes.values()), temporary=max(temporary, default=0),
+        final=int(sum(sizes.values()) * FASTQ_EXPANSION * GZIP_RATIO)
+    )
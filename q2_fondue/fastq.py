@@ -0,0 +1,1270 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import hashlib
+import io
+import itertools
+import json
+import lzma
+import math
+import os
+import zlib
+from collections import Counter
+from dataclasses import dataclass
+from typing import Dict, Iterable, List, Optional, Tuple
+
+from q2_fondue.compression import (
+    WRITE_BUFFER_SIZE, _PipelineReader, _ProgressReporter, _SpaceWatcher,
+    _available_cores, _compress_stream, _gzip_writer, _open_fastq
+)
+from q2_fondue.utils import (
+    CancellationToken, FastqRewriteError, FastqStats, FastqValidationError,
+    PairingError, TruncatedFastqError, _atomic_output, _is_stream,
+    _remove_files, _start_clock, _stop_clock
+)
+
+
+class _FastqStatsReader:
+    """Wraps a binary FASTQ (or two-line FASTA) stream and collects
+        statistics of all the records read through it - reading fails once
+        the optional cancellation token was cancelled. The amount of data
+        read is reported to the optional progress reporter. Lines spanning
+        multiple reads (e.g., of long reads) are not buffered - only their
+        length and qualities are kept track of."""
+
+    def __init__(
+            self, f_in, stats: FastqStats, lines_per_record: int = 4,
+            hasher=None, cancel: CancellationToken = None,
+            progress: _ProgressReporter = None
+    ):
+        self.f_in = f_in
+        self.stats = stats
+        self.lines_per_record = lines_per_record
+        self.hasher = hasher
+        self.cancel = cancel
+        self.progress = progress
+        self._line_no = 0
+        # length of the incomplete last line read and whether it ended
+        # with a carriage return, which may precede the newline
+        self._length = 0
+        self._cr = False
+        self._pending = False
+        self._qualities = set()
+
+    def read(self, size: int = -1) -> bytes:
+        if self.cancel:
+            self.cancel.check()
+        data = self.f_in.read(size)
+        if not data:
+            self._finish()
+            return data
+
+        if self.hasher:
+            self.hasher.update(data)
+        self.stats.uncompressed_bytes += len(data)
+        *lines, partial = data.split(b'\n')
+        if lines:
+            if self._pending:
+                self._extend_line(lines[0])
+                self._end_pending()
+            else:
+                self._end_line(lines[0].rstrip(b'\r'))
+            for line in itertools.islice(lines, 1, None):
+                self._end_line(line.rstrip(b'\r'))
+        self._extend_line(partial)
+        if self.progress:
+            self.progress.read(len(data))
+        return data
+
+    def _end_line(self, line: bytes):
+        line_type = self._line_no % self.lines_per_record
+        if line_type == 1:
+            self.stats.add_read(len(line))
+        # there is no need to look further once two scores were found
+        elif line_type == 3 and len(self._qualities) < 2:
+            self._qualities.update(line)
+        self._line_no += 1
+
+    def _extend_line(self, chunk: bytes):
+        if not chunk:
+            return
+        is_quality = self._line_no % self.lines_per_record == 3
+        # a carriage return followed by more data is part of the line
+        if self._cr:
+            self._length += 1
+            if is_quality and len(self._qualities) < 2:
+                self._qualities.add(ord('\r'))
+        self._cr = chunk.endswith(b'\r')
+        if self._cr:
+            chunk = memoryview(chunk)[:-1]
+        self._length += len(chunk)
+        if is_quality and len(self._qualities) < 2:
+            self._qualities.update(chunk)
+        self._pending = True
+
+    def _end_pending(self):
+        if self._line_no % self.lines_per_record == 1:
+            self.stats.add_read(self._length)
+        self._line_no += 1
+        self._length, self._cr, self._pending = 0, False, False
+
+    def _finish(self):
+        # account for a missing newline at the end of the file
+        if self._pending:
+            self._end_pending()
+        self.stats.uniform_quality = len(self._qualities) == 1
+
+    def close(self):
+        self.f_in.close()
+
+
+def _format_record(id: str, seq: str, qual: str) -> bytes:
+    return f'@{id}\n{seq}\n+\n{qual}\n'.encode()
+
+
+def _format_fasta_record(id: str, seq: str, qual: str) -> bytes:
+    return f'>{id}\n{seq}\n'.encode()
+
+
+RECORD_FORMATTERS = {
+    'fastq': (_format_record, 4),
+    'fasta': (_format_fasta_record, 2),
+}
+
+
+class FastqGzWriter:
+    """Writes FASTQ records into a gzip-compressed file one by one.
+
+    The writer can be used as a context manager, in which case it will
+    be closed automatically. In append mode, the records are written to
+    an existing file as an additional gzip member, which any gzip reader
+    will decompress as a continuation of the earlier data. Formatted
+    records are collected in a buffer of write_buffer_size bytes and
+    compressed together, rather than one by one - the compressed data
+    are written through a buffer of the same size.
+
+    Writing fails with an InsufficientSpaceError once less than
+    min_free_space bytes are left on the output file system, if given.
+    Records are written to a temporary file, which only replaces file_out
+    once the writer is closed (see _atomic_output) - it is removed instead
+    if the writer is discarded or its context is left with an error.
+
+    Attributes:
+        file_out (str): Path to the written file.
+        stats (FastqStats): Statistics of the records written so far.
+    """
+
+    def __init__(
+            self, file_out: str, compression_level: int = 9,
+            append: bool = False, min_free_space: int = None,
+            write_buffer_size: int = WRITE_BUFFER_SIZE
+    ):
+        self.file_out = os.fsdecode(file_out)
+        self.stats = FastqStats()
+        self._write_buffer_size = write_buffer_size
+        self._output = _atomic_output(self.file_out, append=append)
+        self._f_raw = open(
+            self._output.__enter__(), 'wb', buffering=write_buffer_size
+        )
+        if min_free_space:
+            self._f_raw = _SpaceWatcher(
+                self._f_raw, self.file_out, min_free_space
+            )
+        self._f_out = _gzip_writer(self._f_raw, compression_level)
+        self._buffer = bytearray()
+        # the quality all the bases had so far - None once they differ
+        self._quality = ''
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        if exc_type is None:
+            self.close()
+        else:
+            self.discard()
+
+    @property
+    def closed(self) -> bool:
+        return self._f_out.closed
+
+    def write_record(self, id: str, seq: str, qual: str):
+        """Writes a single record.
+
+        Args:
+            id (str): Read identifier (with an optional description),
+                without the leading '@'.
+            seq (str): Read sequence.
+            qual (str): Quality string - must be as long as the sequence.
+        """
+        if len(seq) != len(qual):
+            raise ValueError(
+                f'Sequence and quality of read {id} differ in length '
+                f'({len(seq)} vs. {len(qual)}).'
+            )
+        record = _format_record(id, seq, qual)
+        self._buffer += record
+        if len(self._buffer) >= self._write_buffer_size:
+            self._drain()
+        self.stats.add_read(len(seq))
+        self.stats.uncompressed_bytes += len(record)
+        if self._quality is not None and qual:
+            self._quality = self._quality or qual[0]
+            if qual.strip(self._quality):
+                self._quality = None
+            self.stats.uniform_quality = self._quality is not None
+
+    def _drain(self):
+        self._f_out.write(self._buffer)
+        self._buffer.clear()
+
+    def flush(self):
+        self._drain()
+        self._f_out.flush()
+
+    def close(self):
+        if not self._f_out.closed:
+            try:
+                try:
+                    self._drain()
+                    self._f_out.close()
+                finally:
+                    self._f_raw.close()
+            except BaseException as e:
+                self._output.__exit__(type(e), e, e.__traceback__)
+                raise
+            self._output.__exit__(None, None, None)
+            self.stats.compressed_bytes = os.path.getsize(self.file_out)
+
+    def discard(self):
+        """Closes the writer without keeping any of the written records."""
+        if not self._f_out.closed:
+            try:
+                self._f_out.close()
+            except Exception:
+                # the data are dropped anyway
+                pass
+            finally:
+                self._f_raw.close()
+                # leaving the output's context with an error removes it
+                self._output.__exit__(GeneratorExit, GeneratorExit(), None)
+
+
+def _close_writers(
+        writers: Iterable[Optional[FastqGzWriter]], discard: bool = False
+):
+    """Closes (or discards) all the writers of a set of outputs - all of
+        them are discarded if any of them cannot be closed, such that no
+        incomplete set of outputs is left behind."""
+    writers = [x for x in writers if x]
+    closed = []
+    try:
+        for writer in writers:
+            if discard:
+                writer.discard()
+            else:
+                writer.close()
+                closed.append(writer.file_out)
+    except BaseException:
+        for writer in writers:
+            writer.discard()
+        _remove_files(closed)
+        raise
+
+
+class FastqReader:
+    """Iterates over records of a plain or compressed FASTQ file.
+
+    Every record is returned as an (id, seq, qual) tuple, where id is
+    the header line without the leading '@'. Line endings (including
+    Windows-style ones) and any trailing whitespace are stripped from all
+    the lines. Blank lines between records (e.g., at the end of the file)
+    are skipped unless validating. Sequences and qualities wrapped over
+    multiple lines can be read when `multiline` is enabled. With
+    `prefetch`, the file is read (and decompressed) ahead in a background
+    thread, while the records are being parsed. The reader can be used as
+    a context manager, in which case it will be closed automatically.
+
+    Attributes:
+        file_in (str): Path to the read file.
+        validate (bool): Whether the structure and content of every
+            record should be validated.
+        multiline (bool): Whether sequences and qualities may be wrapped
+            over multiple lines - the sequence ends with the line starting
+            with '+' and the quality once it is as long as the sequence.
+        on_length_mismatch (str): What to do with records whose sequence
+            and quality lengths differ: 'error' raises (only when
+            validating), 'report' keeps and 'drop' skips those records -
+            both of the latter record them in length_mismatches.
+        on_truncation (str): What to do when the file ends in the middle
+            of a record: 'error' raises a TruncatedFastqError while 'stop'
+            ends the iteration at the last complete record and stores
+            the position of the truncated one in truncated_at.
+        fill_quality (int): Phred score assigned to every base of records
+            lacking the '+' and quality lines, including FASTA records
+            (with '>' headers and possibly wrapped sequences) - such
+            records are rejected when not set.
+        fill_offset (int): ASCII offset used to encode the filled-in
+            qualities.
+        records (int): Number of records read so far.
+        filled (int): Number of records whose qualities were filled in.
+        length_mismatches (List[Tuple[str, int, str]]): File, record number
+            and read ID of all the reported length mismatches.
+        truncated_at (int): Byte offset of the truncated record, if any.
+    """
+
+    def __init__(
+            self, file_in: str, validate: bool = False,
+            on_length_mismatch: str = 'error', on_truncation: str = 'error',
+            multiline: bool = False, fill_quality: int = None,
+            fill_offset: int = 33, prefetch: bool = False
+    ):
+        if fill_quality is not None and not \
+                0 <= fill_quality <= 126 - fill_offset:
+            raise ValueError(
+                f'The quality to fill in must be between 0 and '
+                f'{126 - fill_offset} (got {fill_quality}).'
+            )
+        if on_length_mismatch not in ('error', 'report', 'drop'):
+            raise ValueError(
+                f'Length mismatches can be either "error", "report" or '
+                f'"drop" (got "{on_length_mismatch}").'
+            )
+        if on_truncation not in ('error', 'stop'):
+            raise ValueError(
+                f'Truncation can be handled with either "error" or "stop" '
+                f'(got "{on_truncation}").'
+            )
+        self.file_in = file_in if _is_stream(file_in) \
+            else os.fsdecode(file_in)
+        self.validate = validate
+        self.multiline = multiline
+        self.on_length_mismatch = on_length_mismatch
+        self.on_truncation = on_truncation
+        self.fill_quality = fill_quality
+        self.fill_offset = fill_offset
+        self.records = 0
+        self.filled = 0
+        self.length_mismatches = []
+        self.truncated_at = None
+        self._offset = 0
+        self._pending = None
+        self._f_in = _open_fastq(file_in)
+        if prefetch:
+            self._f_in = io.BufferedReader(_PipelineReader(self._f_in))
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    def __iter__(self):
+        return self
+
+    def __next__(self) -> Tuple[str, str, str]:
+        while True:
+            lines, length = self._read_lines()
+            if not lines[0]:
+                raise StopIteration
+            self.records += 1
+            offset = self._offset
+            self._offset += length
+
+            header, seq, plus, qual = (x.rstrip().decode() for x in lines)
+            if not header.startswith('@') and not (
+                    self.fill_quality is not None and header.startswith('>')
+            ):
+                self._fail(None, 'header does not start with "@"')
+            # the last line may lack the newline only if it is complete
+            if not lines[3] or (
+                    not lines[3].endswith(b'\n') and len(qual) < len(seq)
+            ):
+                if self.on_truncation == 'stop':
+                    self.truncated_at = offset
+                    raise StopIteration
+                raise TruncatedFastqError(
+                    self.file_in, self.records, header[1:], offset
+                )
+            if self.validate:
+                self._validate(header[1:], seq, plus, qual)
+            if len(seq) != len(qual) and self._skip_mismatch(header[1:]):
+                continue
+            return header[1:], seq, qual
+
+    def _readline(self) -> bytes:
+        if self._pending is not None:
+            line, self._pending = self._pending, None
+            return line
+        return self._f_in.readline()
+
+    def _fill(self, header: bytes, seq: bytes) -> List[bytes]:
+        """Completes a record lacking qualities with a constant one.
+
+        The line which was read in place of the separator belongs to
+        the next record and must already have been put back.
+        """
+        self.filled += 1
+        qual = chr(self.fill_quality + self.fill_offset).encode()
+        qual *= len(seq.rstrip())
+        return [header, seq, b'+\n', qual + b'\n']
+
+    def _read_fasta(self, header: bytes) -> Tuple[List[bytes], int]:
+        """Reads a (possibly wrapped) FASTA record into FASTQ lines."""
+        length = len(header)
+        seq, line = b'', self._readline()
+        while line and not line.startswith(b'>'):
+            length += len(line)
+            seq += line.rstrip()
+            line = self._readline()
+        self._pending = line
+        return self._fill(header, seq + b'\n'), length
+
+    def _read_lines(self) -> Tuple[List[bytes], int]:
+        """Reads the four lines of the next record.
+
+        Wrapped sequences and qualities are joined into single lines.
+        The quality line only ends with a newline if it is complete.
+        When filling in qualities, a record ends after its sequence if
+        the next line does not start with '+' and FASTA records are
+        accepted as well.
+
+        Returns:
+            Tuple[List[bytes], int]: Lines of the record and the number
+                of bytes read from the file.
+        """
+        fill = self.fill_quality is not None
+        header = self._readline()
+        while not self.validate and header.isspace():
+            self._offset += len(header)
+            header = self._readline()
+        if fill and header.startswith(b'>'):
+            return self._read_fasta(header)
+
+        if not self.multiline:
+            seq, plus = self._readline(), self._readline()
+            if fill and header and not plus.startswith(b'+'):
+                self._pending = plus
+                return self._fill(header, seq), len(header) + len(seq)
+            lines = [header, seq, plus, self._readline()]
+            return lines, sum(len(x) for x in lines)
+
+        length = len(header)
+        seq, plus = b'', self._readline()
+        while plus and not plus.startswith(b'+'):
+            if fill and plus.startswith(b'@'):
+                break
+            length += len(plus)
+            seq += plus.rstrip()
+            plus = self._readline()
+        if fill and header and not plus.startswith(b'+'):
+            self._pending = plus
+            return self._fill(header, seq + b'\n'), length
+        length += len(plus)
+
+        qual = b''
+        while plus:
+            line = self._readline()
+            length += len(line)
+            qual += line.rstrip()
+            if not line or len(qual) >= len(seq):
+                break
+        # let an incomplete quality look like a truncated line
+        if plus and len(qual) >= len(seq):
+            qual += b'\n'
+        return [header, seq + b'\n', plus, qual], length
+
+    def _skip_mismatch(self, read_id: str) -> bool:
+        if self.on_length_mismatch == 'error':
+            return False
+        self.length_mismatches.append((self.file_in, self.records, read_id))
+        return self.on_length_mismatch == 'drop'
+
+    def _fail(self, read_id: str, reason: str):
+        raise FastqValidationError(
+            self.file_in, self.records, read_id, reason
+        )
+
+    def _validate(self, read_id: str, seq: str, plus: str, qual: str):
+        if not plus.startswith('+'):
+            self._fail(read_id, 'separator line does not start with "+"')
+        if not seq:
+            self._fail(read_id, 'sequence is empty')
+        if len(seq) != len(qual):
+            if self.on_length_mismatch != 'error':
+                return
+            self._fail(
+                read_id, f'sequence and quality lengths differ '
+                         f'({len(seq)} vs. {len(qual)})'
+            )
+        if min(qual) < '!' or max(qual) > '~':
+            self._fail(
+                read_id, 'quality string contains characters outside '
+                         'of the Phred+33 range'
+            )
+
+    def close(self):
+        self._f_in.close()
+
+
+class _FastqRecordStream:
+    """Exposes FASTQ records as a binary stream, so that they can be
+        compressed like any other input file. The records are formatted
+        with `formatter` (four-line FASTQ by default)."""
+
+    def __init__(
+            self, reader: FastqReader,
+            records: Iterable[Tuple[str, str, str]] = None,
+            formatter=_format_record
+    ):
+        self._reader = reader
+        self._records = iter(reader if records is None else records)
+        self._formatter = formatter
+        self._buffer = bytearray()
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    def read(self, size: int = -1) -> bytes:
+        while size < 0 or len(self._buffer) < size:
+            record = next(self._records, None)
+            if record is None:
+                break
+            self._buffer += self._formatter(*record)
+        size = len(self._buffer) if size < 0 else size
+        data = bytes(self._buffer[:size])
+        del self._buffer[:size]
+        return data
+
+    def close(self):
+        self._reader.close()
+
+
+class _ConcatenatedStream:
+    """Reads binary streams one after another, as if they were a single
+        one. A newline is inserted after every stream not ending with one,
+        so that no records get merged."""
+
+    def __init__(self, streams: Iterable):
+        self._streams = iter(streams)
+        self._current = next(self._streams, None)
+        self._last = b'\n'
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    def read(self, size: int = -1) -> bytes:
+        while self._current is not None:
+            data = self._current.read(size)
+            if data:
+                self._last = data[-1:]
+                return data
+            self._current.close()
+            self._current = next(self._streams, None)
+            if self._last != b'\n':
+                self._last = b'\n'
+                return b'\n'
+        return b''
+
+    def close(self):
+        if self._current is not None:
+            self._current.close()
+            self._current = None
+
+
+class _SeparatorStripper:
+    """Reads a binary stream of four-line FASTQ records with everything
+        but the '+' removed from their separator lines, which often repeat
+        the whole header (as in SRA dumps). The stream is processed in
+        chunks, so that long reads are never held in memory at once."""
+
+    def __init__(self, f_in):
+        self.f_in = f_in
+        self._line_no = 0
+        self._line_start = True
+        self._cr = False
+
+    def __enter__(self):
+        return self
+
+    def __exit__(self, exc_type, exc_val, exc_tb):
+        self.close()
+
+    def read(self, size: int = -1) -> bytes:
+        # chunks may be stripped entirely, which must not look like the end
+        while True:
+            data = self.f_in.read(size)
+            if not data:
+                return data
+            data = self._strip(data)
+            if data:
+                return data
+
+    def _strip(self, data: bytes) -> bytes:
+        *lines, partial = data.split(b'\n')
+        out = []
+        for line in lines:
+            self._add(out, line)
+            if self._line_no % 4 == 2:
+                out.append(b'\r\n' if self._cr else b'\n')
+            else:
+                out.append(b'\n')
+            self._line_no += 1
+            self._line_start, self._cr = True, False
+        self._add(out, partial)
+        if partial:
+            self._line_start = False
+        return b''.join(out)
+
+    def _add(self, out: List[bytes], line: bytes):
+        if self._line_no % 4 != 2:
+            out.append(line)
+        elif line:
+            if self._line_start:
+                out.append(line[:1])
+            self._cr = line.endswith(b'\r')
+
+    def close(self):
+        self.f_in.close()
+
+
+def _detect_quality_encoding(file_in: str, n_records: int = 10000) -> str:
+    """Guesses the quality encoding of a FASTQ file.
+
+    The decision is based on the range of quality characters found in
+    the first n_records records: Phred+33 scores are the only ones which
+    can go below ';', Solexa+64 scores the only ones between ';' and '?'.
+    As Phred+33 scores of current instruments reach up to 'J', files are
+    only reported as Phred+64- or Solexa-encoded if they contain higher
+    quality characters. Files with a single, constant quality (e.g.
+    SRA Lite files, where every base is '?') carry no usable information
+    on the encoding.
+
+    Args:
+        file_in (str): Path to the (compressed) FASTQ file.
+        n_records (int, default=10000): Number of records to be inspected.
+
+    Returns:
+        str: One of 'phred33', 'phred64', 'solexa', 'uniform' (if all the
+            qualities are the same) or 'unknown' (if no qualities were
+            found or they are out of any of the ranges).
+    """
+    lowest, highest = '~', '!'
+    with FastqReader(file_in) as reader:
+        for _, _, qual in itertools.islice(reader, n_records):
+            if qual:
+                lowest, highest = min(lowest, *qual), max(highest, *qual)
+
+    if lowest > highest or lowest < '!' or highest > '~':
+        return 'unknown'
+    elif lowest == highest:
+        return 'uniform'
+    elif lowest < ';' or highest <= 'J':
+        return 'phred33'
+    elif lowest < '@':
+        return 'solexa'
+    return 'phred64'
+
+
+def _histogram_quantile(histogram: Counter, q: float) -> int:
+    """Finds the q-quantile (nearest rank) of the values in a histogram."""
+    rank = max(1, math.ceil(q * sum(histogram.values())))
+    for value in sorted(histogram):
+        rank -= histogram[value]
+        if rank <= 0:
+            return value
+    return None
+
+
+class QualityReport:
+    """Collects quality-control metrics of FASTQ records.
+
+    The duplication rate is estimated from the first `duplication_sample`
+    reads only, to keep the memory use bounded.
+
+    Attributes:
+        reads (int): Number of reads.
+        bases (int): Total number of bases.
+        lengths (Counter): Histogram of the read lengths.
+        mean_qualities (Counter): Histogram of the mean Phred scores
+            of the reads, rounded down to integers.
+        gc_contents (Counter): Histogram of the GC contents of the reads,
+            in percent rounded down to integers.
+    """
+
+    def __init__(
+            self, quality_offset: int = 33, duplication_sample: int = 100000
+    ):
+        self.quality_offset = quality_offset
+        self.duplication_sample = duplication_sample
+        self.reads = 0
+        self.bases = 0
+        self.lengths = Counter()
+        self.mean_qualities = Counter()
+        self.gc_contents = Counter()
+        self._quality_sum = 0
+        self._gc_count = 0
+        self._n_count = 0
+        self._seen = set()
+        self._duplicates = 0
+
+    def add(self, id: str, seq: str, qual: str):
+        self.reads += 1
+        self.bases += len(seq)
+        self.lengths[len(seq)] += 1
+        quality = sum(qual.encode()) - self.quality_offset * len(qual)
+        self._quality_sum += quality
+        if qual:
+            self.mean_qualities[quality // len(qual)] += 1
+        self._n_count += seq.count('N') + seq.count('n')
+        gc = sum(seq.count(x) for x in 'GCgc')
+        self._gc_count += gc
+        if seq:
+            self.gc_contents[100 * gc // len(seq)] += 1
+        if self.reads <= self.duplication_sample:
+            digest = hashlib.blake2b(seq.encode(), digest_size=16).digest()
+            if digest in self._seen:
+                self._duplicates += 1
+            self._seen.add(digest)
+
+    def observe(self, records: Iterable[Tuple[str, str, str]]):
+        """Adds all the records passing through."""
+        for record in records:
+            self.add(*record)
+            yield record
+
+    def summary(self, histograms: bool = False) -> dict:
+        """Summarizes the metrics - including the histograms of the read
+            lengths and mean qualities (by their values), if requested."""
+        sampled = min(self.reads, self.duplication_sample)
+        summary = {
+            'reads': self.reads,
+            'bases': self.bases,
+            'mean_quality': self._quality_sum / self.bases
+            if self.bases else None,
+            'length_quantiles': {
+                str(q): _histogram_quantile(self.lengths, q)
+                for q in (0, 0.25, 0.5, 0.75, 1)
+            } if self.reads else None,
+            'n_percent': 100 * self._n_count / self.bases
+            if self.bases else 0.0,
+            'gc_percent': 100 * self._gc_count / self.bases
+            if self.bases else None,
+            'gc_percent_quantiles': {
+                str(q): _histogram_quantile(self.gc_contents, q)
+                for q in (0, 0.25, 0.5, 0.75, 1)
+            } if self.gc_contents else None,
+            'duplication_rate': self._duplicates / sampled
+            if sampled else 0.0,
+        }
+        if histograms:
+            summary['length_histogram'] = {
+                str(k): v for k, v in sorted(self.lengths.items())
+            }
+            summary['mean_quality_histogram'] = {
+                str(k): v for k, v in sorted(self.mean_qualities.items())
+            }
+        return summary
+
+    def write(self, file_out: str):
+        with open(file_out, 'w') as f_out:
+            json.dump(self.summary(), f_out, indent=2)
+
+
+def _read_name(read_id: str) -> str:
+    """Returns the name shared by both mates of a read, i.e., the first
+        word of its ID without a /1 or /2 suffix."""
+    name = read_id.split(maxsplit=1)[0] if read_id.strip() else ''
+    return name[:-2] if name[-2:] in ('/1', '/2') else name
+
+
+def _check_pairing(
+        file_r1: str, file_r2: str
+) -> Optional[Tuple[int, str, str]]:
+    """Verifies that the mates of paired-end reads are in sync.
+
+    Reads of both files are compared position by position, using read
+    names without the /1 and /2 suffixes (see _read_name). A file ending
+    earlier than the other one (also when truncated) counts as
+    a divergence.
+
+    Args:
+        file_r1 (str): Path to the forward reads.
+        file_r2 (str): Path to the reverse reads.
+
+    Returns:
+        Optional[Tuple[int, str, str]]: Number of the first record whose
+            read IDs differ with the IDs from both files (None for a file
+            which ended), or None if all the reads are paired.
+    """
+    with FastqReader(file_r1, on_truncation='stop') as r1, \
+            FastqReader(file_r2, on_truncation='stop') as r2:
+        pairs = itertools.zip_longest(r1, r2, fillvalue=(None,))
+        for i, ((id1, *_), (id2, *_)) in enumerate(pairs, 1):
+            if id1 is None or id2 is None \
+                    or _read_name(id1) != _read_name(id2):
+                return i, id1, id2
+    return None
+
+
+@dataclass
+class PairRepairStats:
+    """Summary of a repair of paired-end reads.
+
+    Attributes:
+        pairs (int): Number of read pairs which were kept.
+        orphans_r1 (int): Number of forward reads without a mate.
+        orphans_r2 (int): Number of reverse reads without a mate.
+    """
+    pairs: int = 0
+    orphans_r1: int = 0
+    orphans_r2: int = 0
+
+
+def _read_names(file_in: str) -> set:
+    with FastqReader(file_in) as reader:
+        return {_read_name(read_id) for read_id, _, _ in reader}
+
+
+def _repair_pairs(
+        r1_in: str, r2_in: str, r1_out: str, r2_out: str, orphans_out: str,
+        compression_level: int = 9
+) -> PairRepairStats:
+    """Resynchronizes paired-end reads by removing orphaned ones.
+
+    Only reads whose names (see _read_name) are found in both files are
+    written to the (gzip-compressed) paired outputs, while all the others
+    are written to the orphans file. Mates are expected to be in the same
+    relative order in both files, which is the case when some of the reads
+    were lost, e.g., during an interrupted dump.
+
+    Args:
+        r1_in (str): Path to the forward reads.
+        r2_in (str): Path to the reverse reads.
+        r1_out (str): Path where the paired forward reads should be written.
+        r2_out (str): Path where the paired reverse reads should be written.
+        orphans_out (str): Path where the reads without a mate should
+            be written.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        PairRepairStats: Numbers of the kept pairs and orphaned reads.
+    """
+    paired = _read_names(r1_in) & _read_names(r2_in)
+    stats = PairRepairStats()
+    writers = [
+        FastqGzWriter(path, compression_level)
+        for path in (r1_out, r2_out, orphans_out)
+    ]
+    *paired_writers, orphans = writers
+    try:
+        for file_in, writer, attr in zip(
+                (r1_in, r2_in), paired_writers, ('orphans_r1', 'orphans_r2')
+        ):
+            with FastqReader(file_in) as reader:
+                for record in reader:
+                    if _read_name(record[0]) in paired:
+                        writer.write_record(*record)
+                    else:
+                        orphans.write_record(*record)
+                        setattr(stats, attr, getattr(stats, attr) + 1)
+            stats.pairs = writer.stats.reads
+    except BaseException:
+        _close_writers(writers, discard=True)
+        raise
+    _close_writers(writers)
+    return stats
+
+
+def _interleave(
+        r1_in: str, r2_in: str, file_out: str, compression_level: int = 9
+) -> FastqStats:
+    """Merges paired-end reads into a single interleaved FASTQ file.
+
+    Every forward read is followed by its mate in the (gzip-compressed)
+    output. Mates are expected to be in sync (see _check_pairing) - the
+    output is only written once all of them were merged (see
+    FastqGzWriter).
+
+    Args:
+        r1_in (str): Path to the forward reads.
+        r2_in (str): Path to the reverse reads.
+        file_out (str): Path where the interleaved reads should be written.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        FastqStats: Statistics of all the written reads.
+
+    Raises:
+        PairingError: If the mates are not in sync.
+    """
+    with FastqReader(r1_in) as reader1, FastqReader(r2_in) as reader2, \
+            FastqGzWriter(file_out, compression_level) as writer:
+        pairs = itertools.zip_longest(reader1, reader2)
+        for i, (record1, record2) in enumerate(pairs, 1):
+            if record1 is None or record2 is None:
+                file_in, record = (r1_in, record2) if record1 is None \
+                    else (r2_in, record1)
+                raise PairingError(
+                    file_in, i, None, f'file ends before the mate of '
+                                      f'read {record[0]}'
+                )
+            if _read_name(record1[0]) != _read_name(record2[0]):
+                raise PairingError(
+                    r2_in, i, record2[0], f'read is not the mate of '
+                                          f'read {record1[0]}'
+                )
+            writer.write_record(*record1)
+            writer.write_record(*record2)
+    return writer.stats
+
+
+def _deinterleave(
+        file_in: str, r1_out: str, r2_out: str, orphans_out: str = None,
+        compression_level: int = 9, file_stats: Dict[str, FastqStats] = None,
+        min_free_space: int = None
+) -> PairRepairStats:
+    """Splits interleaved paired-end reads into separate files.
+
+    Consecutive reads with the same name (see _read_name) are written to
+    the (gzip-compressed) forward and reverse outputs, respectively. Reads
+    without an adjacent mate are orphans - they are counted as reverse
+    reads if their name ends with /2 and as forward ones otherwise.
+
+    Args:
+        file_in (str): Path to the interleaved reads.
+        r1_out (str): Path where the forward reads should be written.
+        r2_out (str): Path where the reverse reads should be written.
+        orphans_out (str, default=None): Path where the reads without
+            a mate should be written - they are discarded if not provided.
+        compression_level (int, default=9): gzip compression level.
+        file_stats (Dict[str, FastqStats], default=None): Dictionary to
+            which statistics of all the written files should be added,
+            by path.
+        min_free_space (int, default=None): Minimal free space in bytes
+            on the output file system (see FastqGzWriter).
+
+    Returns:
+        PairRepairStats: Numbers of the written pairs and orphaned reads.
+    """
+    stats = PairRepairStats()
+    writers = [
+        FastqGzWriter(
+            path, compression_level, min_free_space=min_free_space
+        ) if path else None
+        for path in (r1_out, r2_out, orphans_out)
+    ]
+    writer1, writer2, orphans = writers
+
+    def _orphan(record):
+        attr = 'orphans_r2' if record[0].split()[0].endswith('/2') \
+            else 'orphans_r1'
+        setattr(stats, attr, getattr(stats, attr) + 1)
+        if orphans:
+            orphans.write_record(*record)
+
+    try:
+        with FastqReader(file_in) as reader:
+            pending = None
+            for record in reader:
+                if pending and \
+                        _read_name(pending[0]) == _read_name(record[0]):
+                    writer1.write_record(*pending)
+                    writer2.write_record(*record)
+                    stats.pairs += 1
+                    pending = None
+                    continue
+                if pending:
+                    _orphan(pending)
+                pending = record
+            if pending:
+                _orphan(pending)
+    except BaseException:
+        _close_writers(writers, discard=True)
+        raise
+    _close_writers(writers)
+    if file_stats is not None:
+        file_stats.update({x.file_out: x.stats for x in writers if x})
+    return stats
+
+
+def _run_accession(file_in: str) -> str:
+    """Extracts the run accession from a name like SRR123456_1.fastq - file
+        objects are identified by their names, if they have any.
+
+    Raises:
+        ValueError: If no accession can be derived, e.g., for the standard
+            input or a file descriptor.
+    """
+    name = file_in
+    if _is_stream(file_in):
+        name = getattr(file_in, 'name', '')
+        # file descriptors and standard streams are not named after a run
+        if not isinstance(name, str) or name.startswith('<'):
+            name = ''
+    acc = os.path.basename(os.fsdecode(name)).split('_')[0].split('.')[0]
+    if not acc:
+        raise ValueError(
+            f'No run accession can be derived from the name of {file_in!r}.'
+        )
+    return acc
+
+
+def _concat_runs(
+        inputs: List[str], file_out: str, prefix_ids: bool = False,
+        compression_level: int = 9, threads: Optional[int] = 1,
+        overwrite: bool = False
+) -> FastqStats:
+    """Concatenates sequences of multiple runs into a single gzip file.
+
+    Args:
+        inputs (List[str]): Paths to the (plain or compressed) FASTQ files
+            of all the runs, in the order in which they should be written.
+        file_out (str): Path where the compressed file should be written.
+        prefix_ids (bool, default=False): Whether the read IDs should be
+            prefixed with the run accession (as in SRR123456:read_id, where
+            the accession is taken from the file name), so that reads from
+            different runs cannot end up with the same IDs. Records are
+            parsed when enabled.
+        compression_level (int, default=9): gzip compression level.
+        threads (int, default=1): Number of threads used to compress
+            the file - None to use all the available CPU cores.
+        overwrite (bool, default=False): Whether an existing file at
+            file_out may be replaced.
+
+    Returns:
+        FastqStats: Statistics of all the written sequences.
+
+    Raises:
+        ValueError: If prefix_ids is enabled and the run accession cannot
+            be derived from the name of an input file.
+        FileExistsError: If file_out exists and overwrite is not enabled.
+        FastqRewriteError: If any of the input files could not be read or
+            the output file could not be written.
+    """
+    inputs = [os.fsdecode(x) for x in inputs]
+    file_out = os.fsdecode(file_out)
+    if not overwrite and os.path.exists(file_out):
+        raise FileExistsError(
+            f'{file_out} already exists - use overwrite=True to replace it.'
+        )
+    if threads is None:
+        threads = _available_cores()
+
+    if prefix_ids:
+        accessions = [_run_accession(x) for x in inputs]
+
+    def _streams():
+        for i, file_in in enumerate(inputs):
+            if not prefix_ids:
+                yield _open_fastq(file_in)
+                continue
+            acc = accessions[i]
+            reader = FastqReader(file_in)
+            yield _FastqRecordStream(reader, (
+                (f'{acc}:{read_id}', seq, qual)
+                for read_id, seq, qual in reader
+            ))
+
+    stats, clock = FastqStats(), _start_clock()
+    try:
+        with _atomic_output(file_out) as tmp_out, \
+                _ConcatenatedStream(_streams()) as f_raw:
+            f_in = _FastqStatsReader(f_raw, stats)
+            stats.compressed_bytes, _ = _compress_stream(
+                f_in, tmp_out, compression_level, threads, 'gzip'
+            )
+    except (OSError, EOFError, zlib.error, lzma.LZMAError) as e:
+        raise FastqRewriteError(
+            f'Sequences from {", ".join(inputs)} could not be written '
+            f'to {file_out}: {e}'
+        ) from e
+    _stop_clock(stats, clock)
+    return stats
+
+
+def _split_fastq(
+        file_in: str, out_prefix: str, reads_per_chunk: int,
+        compression_level: int = 9
+) -> List[str]:
+    """Splits a FASTQ file into gzip-compressed chunks of the same size.
+
+    Chunks are written to `{out_prefix}_{n}.fastq.gz`, where n is the
+    1-based, zero-padded chunk number. All the chunks but the last one
+    contain exactly `reads_per_chunk` reads.
+
+    Args:
+        file_in (str): Path to the (plain or compressed) FASTQ file.
+        out_prefix (str): Path prefix of the written chunks.
+        reads_per_chunk (int): Number of reads in every chunk.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        List[str]: Paths to the written chunks - empty for an empty input.
+
+    Raises:
+        ValueError: If the number of reads per chunk is lower than 1.
+    """
+    out_prefix = os.fsdecode(out_prefix)
+    if reads_per_chunk < 1:
+        raise ValueError(
+            f'Number of reads per chunk must be at least 1 '
+            f'(got {reads_per_chunk}).'
+        )
+    chunks, writer = [], None
+    try:
+        with FastqReader(file_in) as reader:
+            # records are streamed into the current chunk, which is
+            # replaced by the next one once it is full
+            for i, record in enumerate(reader):
+                if i % reads_per_chunk == 0:
+                    if writer:
+                        writer.close()
+                    file_out = f'{out_prefix}_{len(chunks) + 1:04d}.fastq.gz'
+                    chunks.append(file_out)
+                    writer = FastqGzWriter(file_out, compression_level)
+                writer.write_record(*record)
+        if writer:
+            writer.close()
+    except BaseException:
+        # chunks of an incompletely split file are not kept
+        if writer:
+            writer.discard()
+        _remove_files(chunks)
+        raise
+    return chunks
+
+
+def _match_barcode(
+        seq: str, barcodes: List[str], max_mismatches: int
+) -> Optional[str]:
+    """Finds the barcode at the start of a read with the fewest mismatches
+        (up to max_mismatches) - None if no barcode or more than one matches
+        equally well."""
+    best, best_mismatches = None, max_mismatches + 1
+    for barcode in barcodes:
+        if len(seq) < len(barcode):
+            continue
+        mismatches = sum(x != y for x, y in zip(seq, barcode))
+        if mismatches < best_mismatches:
+            best, best_mismatches = barcode, mismatches
+        elif mismatches == best_mismatches:
+            best = None
+    return best
+
+
+def _demultiplex(
+        file_in: str, barcode_map: Dict[str, str], out_dir: str,
+        max_mismatches: int = 0, compression_level: int = 9
+) -> Tuple[Dict[str, FastqStats], int]:
+    """Splits multiplexed reads by their inline barcodes.
+
+    Reads are assigned to samples by the barcode they start with (see
+    _match_barcode), which is removed before they are written to
+    `{out_dir}/{sample}.fastq.gz`. Reads which cannot be assigned
+    unambiguously are discarded.
+
+    Args:
+        file_in (str): Path to the (plain or compressed) FASTQ file.
+        barcode_map (Dict[str, str]): Sample IDs by barcode sequence.
+        out_dir (str): Directory where the per-sample files should
+            be written.
+        max_mismatches (int, default=0): Maximum number of mismatches
+            between a barcode and the start of a read.
+        compression_level (int, default=9): gzip compression level.
+
+    Returns:
+        Tuple[Dict[str, FastqStats], int]: Statistics of the reads written
+            for every sample and the number of unassigned reads.
+    """
+    out_dir = os.fsdecode(out_dir)
+    barcodes = [x.upper() for x in barcode_map]
+    samples = {x.upper(): sample for x, sample in barcode_map.items()}
+    writers = {
+        sample: FastqGzWriter(
+            os.path.join(out_dir, f'{sample}.fastq.gz'), compression_level
+        ) for sample in set(barcode_map.values())
+    }
+    unassigned = 0
+    try:
+        with FastqReader(file_in) as reader:
+            for read_id, seq, qual in reader:
+                barcode = _match_barcode(
+                    seq.upper(), barcodes, max_mismatches
+                )
+                if barcode is None:
+                    unassigned += 1
+                    continue
+                writers[samples[barcode]].write_record(
+                    read_id, seq[len(barcode):], qual[len(barcode):]
+                )
+    except BaseException:
+        _close_writers(writers.values(), discard=True)
+        raise
+    _close_writers(writers.values())
+    return {x: writer.stats for x, writer in writers.items()}, unassigned
+
+
+def _fastq_stats(file_in: str, quality_offset: int = 33) -> QualityReport:
+    """Collects quality-control metrics of all the reads in a (plain or
+        compressed) FASTQ file, including histograms of their lengths
+        and mean qualities."""
+    report = QualityReport(quality_offset)
+    with FastqReader(file_in) as reader:
+        for record in reader:
+            report.add(*record)
+    return report
+
+
+def _count_reads(file_in: str, block_size: int = 1024 ** 2) -> int:
+    """Counts the four-line records of a (plain or compressed) FASTQ file
+        without parsing them - a record with an incomplete last line
+        counts as well."""
+    lines, last = 0, b'\n'
+    with _open_fastq(file_in) as f_in:
+        for block in iter(lambda: f_in.read(block_size), b''):
+            lines += block.count(b'\n')
+            last = block[-1:]
+    if last != b'\n':
+        lines += 1
+    return lines // 4
+
+
+def _check_spot_counts(
+        expected: Dict[str, int], files: List[str]
+) -> Dict[str, Tuple[int, List[int]]]:
+    """Verifies that the written files contain all the spots of every run.
+
+    Files are assigned to runs by the accessions at the start of their
+    names (see _run_accession). Every file of a run (i.e., both files of
+    paired-end runs) must contain as many reads as there are spots.
+
+    Args:
+        expected (Dict[str, int]): Expected spot counts by run accession,
+            e.g., from the SRA metadata.
+        files (List[str]): Paths to the written files.
+
+    Returns:
+        Dict[str, Tuple[int, List[int]]]: Expected spot count and read
+            counts of all the files of every mismatching run - the list
+            is empty for runs without any files.
+    """
+    counts = {acc: [] for acc in expected}
+    for file_in in sorted(files):
+        acc = _run_accession(file_in)
+        if acc in counts:
+            counts[acc].append(_count_reads(file_in))
+    return {
+        acc: (expected[acc], observed) for acc, observed in counts.items()
+        if not observed or any(x != expected[acc] for x in observed)
+    }
@@ -121,7 +121,9 @@ plugin.methods.register_function(
     parameters={
         **common_params,
         'retries': Int % Range(0, None),
-        'restricted_access': Bool
+        'restricted_access': Bool,
+        'prefer_ena': Bool,
+        'min_free_space': Int % Range(0, None)
     },
     outputs=[
         ('single_reads', SampleData[SequencesWithQuality]),
@@ -133,7 +135,14 @@ plugin.methods.register_function(
         **common_param_descr,
         'retries': 'Number of retries to fetch sequences.',
         'restricted_access': 'If sequence fetch requires dbGaP repository '
-        'key.'
+        'key.',
+        'prefer_ena': 'If runs mirrored on ENA should be downloaded from '
+        'there as they are, instead of being fetched from SRA with the '
+        'SRA Toolkit. Runs which cannot be downloaded from ENA are '
+        'fetched from SRA.',
+        'min_free_space': 'Minimal free disk space (in MB) to be left while '
+        'the reads of a run are dumped - runs which would use up more '
+        'space fail. Set to 0 to disable this check.'
     },
     output_descriptions={
         'single_reads': output_descriptions['single_reads'],
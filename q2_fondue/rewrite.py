@@ -0,0 +1,1195 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import gzip
+import hashlib
+import hmac
+import json
+import lzma
+import math
+import os
+import random
+import re
+import zlib
+from collections import Counter
+from concurrent.futures import ThreadPoolExecutor, wait
+from contextlib import closing, nullcontext
+from dataclasses import dataclass, replace
+from typing import Callable, Dict, Iterable, List, Optional, Tuple, Union
+
+from q2_fondue.compression import (
+    COMPRESSION_BLOCK_SIZE, COPY_BLOCK_SIZE, PROGRESS_INTERVAL,
+    WRITE_BUFFER_SIZE, _Bz2DecompressionError, _PipelineReader,
+    _ProgressReporter, _available_cores, _compress_stream, _open_fastq
+)
+from q2_fondue.entrezpy_clients._utils import set_up_logger
+from q2_fondue.fastq import (
+    FastqReader, QualityReport, RECORD_FORMATTERS, _FastqRecordStream,
+    _FastqStatsReader, _SeparatorStripper, _read_name, _run_accession
+)
+from q2_fondue.utils import (
+    CancellationToken, CompressionError, FastqRewriteError, FastqStats,
+    FastqValidationError, InsufficientSpaceError, _atomic_output,
+    _casava_filename, _is_stream, _start_clock, _stop_clock
+)
+
+LOGGER = set_up_logger('INFO', logger_name=__name__)
+
+# lowest Phred score and binned score of every bin of Illumina's 8-level
+# quality binning - scores below 2 (no calls) are not binned
+ILLUMINA_QUALITY_BINS = (
+    (2, 6), (10, 15), (20, 22), (25, 27), (30, 33), (35, 37), (40, 40)
+)
+# Illumina read IDs from Casava 1.8+ and older pipelines
+ILLUMINA_ID = re.compile(
+    r'^[^\s:]+:\d+:[^\s:]+:\d+:\d+:\d+:\d+$|^[^\s:]+:\d+:\d+:\d+:\d+(#\S*)?$'
+)
+
+
+def _quality_converter(offset_in: int, offset_out: int):
+    """Creates a processing step re-encoding quality scores with
+        a different ASCII offset (e.g., from Phred+64 to Phred+33)."""
+    table = {x: x - offset_in + offset_out for x in range(offset_in, 127)}
+
+    def _convert(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        if qual and min(qual) < chr(offset_in):
+            raise ValueError(
+                f'quality string contains scores below the expected '
+                f'offset of {offset_in}'
+            )
+        qual = qual.translate(table)
+        if qual and max(qual) > '~':
+            raise ValueError(
+                f'quality scores cannot be encoded with the offset '
+                f'of {offset_out}'
+            )
+        return id, seq, qual
+    return _convert
+
+
+def _quality_binner(offset: int):
+    """Creates a processing step quantizing quality scores into Illumina's
+        8-level bins (see ILLUMINA_QUALITY_BINS)."""
+    table = {}
+    for score in range(127 - offset):
+        binned = score
+        for lowest, value in ILLUMINA_QUALITY_BINS:
+            if score >= lowest:
+                binned = value
+        table[score + offset] = binned + offset
+
+    def _bin(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        return id, seq, qual.translate(table)
+    return _bin
+
+
+def _subsampler(fraction: float, rng: random.Random):
+    """Creates a processing step keeping every record with the given
+        probability."""
+    def _subsample(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        return (id, seq, qual) if rng.random() < fraction else None
+    return _subsample
+
+
+def _restore_illumina_id(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+    """Processing step replacing SRA read IDs (as in SRR123456.1) with the
+        original Illumina IDs found in their descriptions, if any. The SRA
+        length=N comment is removed along with the SRA ID."""
+    words = id.split()
+    for i, word in enumerate(words[1:], 1):
+        if ILLUMINA_ID.match(word):
+            rest = [
+                x for x in words[i + 1:] if not x.startswith('length=')
+            ]
+            return ' '.join([word, *rest]), seq, qual
+    return id, seq, qual
+
+
+def _id_anonymizer(salt: str):
+    """Creates a processing step replacing read IDs (and descriptions)
+        with salted hashes of the read names. Mates share their names
+        (see _read_name), so their hashes stay the same and a /1 or /2
+        suffix is kept."""
+    key = salt.encode()
+
+    def _anonymize(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        word = id.split(maxsplit=1)[0] if id.strip() else ''
+        name = _read_name(id)
+        digest = hmac.new(key, name.encode(), hashlib.sha256).hexdigest()
+        return digest[:32] + word[len(name):], seq, qual
+    return _anonymize
+
+
+def _accession_stamper(accession: str):
+    """Creates a processing step adding the run accession as
+        a run=accession comment to every read ID."""
+    def _stamp(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        return f'{id} run={accession}', seq, qual
+    return _stamp
+
+
+def _umi_extractor(length: int, pattern: str, umi_format: str):
+    """Creates a processing step moving UMIs from the start of the reads
+        into their headers.
+
+    The UMI is either the first `length` bases or the segment captured by
+    the first group of a regular expression matched against the start of
+    the read, in which case the whole match is clipped and reads which do
+    not match (or whose group did not take part in the match) are dropped.
+    The UMI is appended to the read name (format 'suffix', as in name_UMI)
+    or added as a SAM-style RX:Z:UMI comment (format 'rx') - reads with an
+    empty ID are rejected.
+    """
+    regex = re.compile(pattern) if pattern else None
+
+    def _extract(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        if regex:
+            match = regex.match(seq)
+            if not match or match.group(1) is None:
+                return None
+            umi, end = match.group(1), match.end()
+        else:
+            umi, end = seq[:length], length
+        if not id.strip():
+            raise ValueError('read ID is empty, so no UMI can be added to it')
+        name, *comment = id.split(maxsplit=1)
+        if umi_format == 'suffix':
+            id = ' '.join([f'{name}_{umi}', *comment])
+        else:
+            id = f'{id} RX:Z:{umi}'
+        return id, seq[end:], qual[end:]
+    return _extract
+
+
+def _fixed_trimmer(left: int, right: int):
+    """Creates a processing step cutting a fixed number of bases from
+        the start (left) and the end (right) of every read."""
+    def _trim(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        end = max(len(seq) - right, left)
+        return id, seq[left:end], qual[left:end]
+    return _trim
+
+
+def _find_adapter(
+        seq: str, adapter: str, max_error_rate: float, min_overlap: int
+) -> int:
+    """Finds the leftmost occurrence of an adapter in a read.
+
+    The adapter may be contained in the read or run past its 3' end, in
+    which case at least min_overlap of its bases must overlap the read.
+    Mismatches are allowed up to max_error_rate of the overlap length.
+
+    Returns:
+        int: Start of the adapter within the read, or its length if
+            the adapter was not found.
+    """
+    exact = seq.find(adapter)
+    stop = len(seq) if exact < 0 else exact
+    for start in range(0, min(stop, len(seq) - min_overlap + 1)):
+        overlap = min(len(adapter), len(seq) - start)
+        allowed = int(max_error_rate * overlap)
+        mismatches = 0
+        for x, y in zip(seq[start:start + overlap], adapter):
+            if x != y:
+                mismatches += 1
+                if mismatches > allowed:
+                    break
+        else:
+            return start
+    return stop
+
+
+def _adapter_trimmer(
+        adapters: List[str], max_error_rate: float, min_overlap: int
+):
+    """Creates a processing step clipping reads at the first occurrence
+        of any of the adapters (see _find_adapter)."""
+    adapters = [x.upper() for x in adapters]
+
+    def _trim(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        upper = seq.upper()
+        end = min(
+            _find_adapter(upper, x, max_error_rate, min_overlap)
+            for x in adapters
+        )
+        return id, seq[:end], qual[:end]
+    return _trim
+
+
+def _homopolymer_trimmer(base: str, min_run: int):
+    """Creates a processing step removing trailing runs of a single base
+        (e.g., poly-G tails of two-color chemistry) of at least min_run
+        bases."""
+    def _trim(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        end = len(seq.rstrip(base + base.lower()))
+        if len(seq) - end < min_run:
+            return id, seq, qual
+        return id, seq[:end], qual[:end]
+    return _trim
+
+
+def _sliding_window_trimmer(size: int, min_quality: float, offset: int):
+    """Creates a processing step trimming reads once the mean Phred score
+        within a sliding window drops below min_quality, like the
+        SLIDINGWINDOW step of Trimmomatic.
+
+    The read is scanned from its 5' end and cut at the start of the first
+    failing window, keeping the bases at the start of that window whose
+    scores are not lower than min_quality.
+    """
+    required = min_quality * size
+
+    def _trim(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        scores = [x - offset for x in qual.encode()]
+        total = sum(scores[:size])
+        for start in range(len(scores) - size + 1):
+            if start:
+                total += scores[start + size - 1] - scores[start - 1]
+            if total < required:
+                end = start
+                while end < len(scores) and scores[end] >= min_quality:
+                    end += 1
+                return id, seq[:end], qual[:end]
+        return id, seq, qual
+    return _trim
+
+
+def _length_filter(min_length: int, max_length: int):
+    """Creates a processing step dropping records shorter than min_length
+        or longer than max_length (either may be None)."""
+    def _filter(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        if min_length is not None and len(seq) < min_length:
+            return None
+        if max_length is not None and len(seq) > max_length:
+            return None
+        return id, seq, qual
+    return _filter
+
+
+def _n_filter(max_fraction: float):
+    """Creates a processing step dropping records in which the fraction
+        of ambiguous bases (N or '.') exceeds max_fraction."""
+    def _filter(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        n_count = sum(seq.count(x) for x in 'Nn.')
+        if seq and n_count / len(seq) > max_fraction:
+            return None
+        return id, seq, qual
+    return _filter
+
+
+def _mean_quality_filter(min_quality: float, offset: int):
+    """Creates a processing step dropping records whose mean Phred score
+        is lower than min_quality."""
+    def _filter(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        if qual and sum(qual.encode()) / len(qual) - offset < min_quality:
+            return None
+        return id, seq, qual
+    return _filter
+
+
+def _expected_error_filter(max_errors: float, offset: int):
+    """Creates a processing step dropping records whose number of expected
+        errors (the sum of error probabilities of all the bases, as used
+        by DADA2 and VSEARCH) is greater than max_errors."""
+    probabilities = {x: 10 ** (-(x - offset) / 10) for x in range(offset, 127)}
+
+    def _filter(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        try:
+            errors = sum(probabilities[x] for x in qual.encode())
+        except KeyError:
+            raise ValueError(
+                f'quality string contains scores which cannot be decoded '
+                f'with the offset of {offset}'
+            ) from None
+        if errors > max_errors:
+            return None
+        return id, seq, qual
+    return _filter
+
+
+def _complexity(seq: str) -> float:
+    """Computes the complexity of a sequence as the Shannon entropy of its
+        trinucleotides, scaled to the 0-1 range (as in PRINSEQ)."""
+    n_kmers = len(seq) - 2
+    if n_kmers < 2:
+        return 1.0
+    counts = Counter(seq[i:i + 3] for i in range(n_kmers))
+    base = math.log(min(64, n_kmers))
+    return -sum(x / n_kmers * math.log(x / n_kmers) for x in counts.values()) \
+        / base
+
+
+def _complexity_filter(min_complexity: float):
+    """Creates a processing step dropping low-complexity records, e.g.,
+        homopolymer or dimer repeats (see _complexity)."""
+    def _filter(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        if _complexity(seq.upper()) < min_complexity:
+            return None
+        return id, seq, qual
+    return _filter
+
+
+def _deduplicator(mode: str):
+    """Creates a processing step dropping records whose sequence (mode
+        'sequence') or sequence and quality (mode 'record') were already
+        seen. Only digests of the seen records are kept in memory."""
+    seen = set()
+
+    def _deduplicate(id: str, seq: str, qual: str) -> Tuple[str, str, str]:
+        key = seq if mode == 'sequence' else f'{seq}\n{qual}'
+        digest = hashlib.blake2b(key.encode(), digest_size=16).digest()
+        if digest in seen:
+            return None
+        seen.add(digest)
+        return id, seq, qual
+    return _deduplicate
+
+
+def _process_records(
+        reader: FastqReader, steps: Dict[str, Callable],
+        dropped: Dict[str, int] = None, trimmed: Dict[str, int] = None
+):
+    """Passes every record through all the processing steps.
+
+    Every step is a function taking the id, sequence and quality of
+    a record and returning the processed ones, or None if the record
+    should be dropped - the number of dropped records is then counted
+    in `dropped` under the name of the step. Similarly, the number of
+    bases removed from the sequences by every step is counted in
+    `trimmed`. A ValueError raised by a step is reported as invalid record.
+    """
+    dropped = {} if dropped is None else dropped
+    trimmed = {} if trimmed is None else trimmed
+    for record in reader:
+        try:
+            for name, step in steps.items():
+                processed = step(*record)
+                if processed is None:
+                    dropped[name] = dropped.get(name, 0) + 1
+                    break
+                if len(processed[1]) < len(record[1]):
+                    trimmed[name] = trimmed.get(name, 0) + \
+                        len(record[1]) - len(processed[1])
+                record = processed
+            else:
+                yield record
+        except ValueError as e:
+            raise FastqValidationError(
+                reader.file_in, reader.records, record[0], str(e)
+            ) from e
+
+
+def _reservoir_sample(
+        records: Iterable[Tuple[str, str, str]], n: int,
+        rng: random.Random, dropped: Dict[str, int]
+):
+    """Draws a uniform random sample of n records, keeping their order.
+
+    All the sampled records are kept in memory until the input is
+    exhausted and the number of the others is counted in `dropped`
+    under 'subsample'.
+    """
+    reservoir = []
+    for i, record in enumerate(records):
+        if i < n:
+            reservoir.append((i, record))
+            continue
+        j = rng.randrange(i + 1)
+        if j < n:
+            reservoir[j] = (i, record)
+        dropped['subsample'] = dropped.get('subsample', 0) + 1
+    for _, record in sorted(reservoir, key=lambda x: x[0]):
+        yield record
+
+
+def _renumber(records: Iterable[Tuple[str, str, str]], accession: str):
+    """Replaces the IDs (and descriptions) of all the records with
+        sequential ones, as in accession.1, accession.2, etc."""
+    for i, (_, seq, qual) in enumerate(records, 1):
+        yield f'{accession}.{i}', seq, qual
+
+
+def _limit_bases(records: Iterable[Tuple[str, str, str]], target: int):
+    """Yields records until their total length reaches the target number
+        of bases - the input is not read any further afterwards."""
+    bases = 0
+    for record in records:
+        if bases >= target:
+            break
+        bases += len(record[1])
+        yield record
+
+
+def _verify_written(
+        file_out: str, reads: int, lines_per_record: int, digest: str,
+        block_size: int = 1024 ** 2
+):
+    """Decompresses a written file again and checks that it contains the
+        expected number of records and data with the expected digest."""
+    hasher, lines, last = hashlib.sha256(), 0, b'\n'
+    with gzip.open(file_out, 'rb') as f_in:
+        for block in iter(lambda: f_in.read(block_size), b''):
+            hasher.update(block)
+            lines += block.count(b'\n')
+            last = block[-1:]
+    if last != b'\n':
+        lines += 1
+    # count the sequence lines, like _FastqStatsReader does
+    records = (lines + lines_per_record - 2) // lines_per_record
+    if records != reads:
+        raise FastqRewriteError(
+            f'{file_out} contains {records} records instead of {reads}.'
+        )
+    if hasher.hexdigest() != digest:
+        raise FastqRewriteError(
+            f'The content of {file_out} differs from the written data.'
+        )
+
+
+@dataclass
+class RewriteOptions:
+    """Determines how _rewrite_fastq writes and processes a FASTQ file.
+
+    Attributes:
+        compression_level (Union[int, str], default=9): gzip compression
+            level, from 0 (no compression, fastest) to 9 (best compression,
+            slowest) - or 'auto' to adjust the level of every compressed
+            block between AUTO_COMPRESSION_LEVELS, such that compression
+            keeps up with reading and writing the data.
+        threads (int, default=1): Number of threads used to compress
+            the file - None to use all the available CPU cores. With
+            multiple threads, blocks of the input are compressed in
+            parallel into independent gzip members (or BGZF blocks).
+        output_format (str, default='gzip'): Either 'gzip' or 'bgzf'.
+            BGZF output is accompanied by a bgzip-compatible index
+            written to `{file_out}.gzi`.
+        mode (str, default='auto'): Either 'copy', 'parse' or 'auto'. With
+            'copy', the input is compressed as it is, line by line, which
+            is fastest but does not allow any of the options below which
+            require records to be parsed. With 'parse', every record is
+            parsed and written again (see FastqReader), which is required
+            to validate, process or report on them. With 'auto', records
+            are parsed only when any of those options are used.
+        validate (bool, default=False): Whether every record should be
+            parsed and validated (see FastqReader) before it is written.
+            Parsed records are written with a bare "+" separator line.
+        on_length_mismatch (str, default='error'): Either 'error', 'report'
+            or 'drop' - see FastqReader. When not 'error', records are
+            parsed and the mismatches are included in the returned stats.
+        on_truncation (str, default='error'): Either 'error' or 'stop' -
+            see FastqReader. When 'stop', records are parsed and the offset
+            of a truncated last record is included in the returned stats.
+        normalize (bool, default=False): Whether records should be parsed
+            to normalize them, i.e., convert Windows line endings and strip
+            trailing whitespace from all the lines.
+        multiline (bool, default=False): Whether records with sequences and
+            qualities wrapped over multiple lines should be parsed and
+            written as standard four-line records.
+        quality_offset_in (int, default=33): ASCII offset of the quality
+            scores in the input file, either 33 or 64.
+        quality_offset_out (int, default=33): ASCII offset with which the
+            quality scores should be written, either 33 or 64. If different
+            from quality_offset_in, records are parsed and re-encoded.
+        fill_quality (int, default=None): Phred score with which records
+            lacking qualities should be written, which also allows FASTA
+            files to be converted to FASTQ - see FastqReader. When
+            set, records are parsed and the number of filled-in records
+            is included in the returned stats.
+        bin_qualities (bool, default=False): Whether quality scores should
+            be quantized into Illumina's 8-level bins (2-9 to 6, 10-19
+            to 15, 20-24 to 22, 25-29 to 27, 30-34 to 33, 35-39 to 37 and
+            40 or higher to 40), after all the processing steps - binned
+            qualities compress much better.
+        bare_separator (bool, default=False): Whether the separator lines
+            of copied records should be written as a bare "+", without
+            the header they repeat (parsed records are always written
+            that way).
+        record_format (str, default='fastq'): Either 'fastq' or 'fasta'.
+            When 'fasta', records are parsed and written as two-line
+            FASTA records, i.e., without their qualities.
+        subsample_fraction (float, default=None): Fraction of the records
+            which should be randomly kept, each with this probability.
+        subsample_n (int, default=None): Exact number of records which
+            should be randomly kept (all of them if there are fewer). The
+            sampled records are kept in memory until the whole input has
+            been read. Cannot be combined with subsample_fraction.
+        seed (int, default=None): Seed of the random number generator used
+            for subsampling, so that the same records are kept every time.
+        target_bases (int, default=None): Approximate number of bases which
+            should be written - records (remaining after any subsampling)
+            are written until their total length reaches this number and
+            the rest of the input is skipped.
+        deduplicate (str, default=None): Either 'sequence' or 'record'.
+            When set, records whose sequence (or both sequence and quality
+            for 'record') is the same as that of an earlier record are
+            dropped before any subsampling.
+        min_length (int, default=None): Minimum length of the written
+            reads - shorter reads are dropped.
+        max_length (int, default=None): Maximum length of the written
+            reads - longer reads are dropped.
+        max_n_fraction (float, default=None): Maximum fraction of ambiguous
+            bases (N) in the written reads, between 0 and 1 - reads with
+            more ambiguous bases are dropped.
+        min_mean_quality (float, default=None): Minimum mean Phred score
+            of the written reads - reads of lower quality are dropped.
+        sliding_window (Tuple[int, float], default=None): Size of a window
+            and the minimum mean Phred score within it, used to trim reads
+            from the first low-quality window on (like the SLIDINGWINDOW
+            step of Trimmomatic). Trimming happens before all the filters
+            and the number of trimmed bases is included in the returned
+            stats.
+        max_expected_errors (float, default=None): Maximum number of
+            expected errors in the written reads, computed from their
+            quality scores (maxEE) - reads with more errors are dropped.
+        trim_left (int, default=0): Number of bases which should be cut
+            from the start of every read, before any other trimming.
+        trim_right (int, default=0): Number of bases which should be cut
+            from the end of every read, before any other trimming.
+        trim_homopolymer (Tuple[str, int], default=None): Base and minimum
+            length of trailing homopolymer runs (e.g., ('G', 10) for poly-G
+            tails) which should be trimmed, after any adapter clipping.
+        adapters (List[str], default=None): 3' adapter sequences - reads
+            are clipped at the first occurrence of any of them, after
+            the fixed-length trimming. Adapters running past the end of
+            a read are found as well.
+        adapter_error_rate (float, default=0.1): Maximum fraction of
+            mismatches within a found adapter.
+        adapter_min_overlap (int, default=3): Minimum number of adapter
+            bases overlapping the end of a read for it to be clipped.
+        min_complexity (float, default=None): Minimum complexity of the
+            written reads, between 0 (homopolymers) and 1, computed as
+            the scaled entropy of their trinucleotides - reads of lower
+            complexity are dropped.
+        umi_length (int, default=None): Length of the UMIs at the start of
+            the reads, which should be clipped and moved into the headers
+            before any trimming.
+        umi_pattern (str, default=None): Regular expression matching the
+            start of the reads, with the UMI captured by its first group,
+            which can be used instead of umi_length. The whole match is
+            clipped and reads which do not match are dropped.
+        umi_format (str, default='suffix'): Either 'suffix' (the UMI is
+            appended to the read name, as in name_UMI) or 'rx' (the UMI
+            is added as an RX:Z:UMI comment).
+        restore_illumina_ids (bool, default=False): Whether SRA read IDs
+            should be replaced with the original instrument IDs, for reads
+            whose descriptions contain them (e.g., @SRR123456.1
+            M00123:45:000000000-A1B2C:1:1101:15589:1333 length=151).
+        stamp_accession (bool, default=False): Whether the run accession
+            (taken from the name of the input file) should be added to
+            every read ID as a run=accession comment, so that the origin
+            of the reads can be traced after runs were merged.
+        renumber (bool, default=False): Whether the whole headers of the
+            written reads should be replaced with sequential IDs (as in
+            SRR123456.1, SRR123456.2, with the run accession taken from
+            the name of the input file), after all the other processing.
+        anonymize_salt (str, default=None): Secret salt with which read IDs
+            should be replaced by hashes of the read names - forward and
+            reverse reads get the same hashes, so that their pairing is
+            kept. Read descriptions are removed.
+        qc_report (bool, default=False): Whether a JSON report with
+            quality-control metrics of the written reads (see
+            QualityReport) should be written to `{file_out}.qc.json`.
+        checksums (Iterable[str], default=()): Hash algorithms (e.g., 'md5'
+            or 'sha256') with which digests of the written file should be
+            computed while it is being written. The digests are included
+            in the returned stats.
+        verify (bool, default=False): Whether the written file should be
+            decompressed again to check that it contains all the written
+            records and exactly the written data.
+        overwrite (bool, default=False): Whether an existing file at
+            file_out may be replaced.
+        append (bool, default=False): Whether the sequences should be
+            appended to an existing file at file_out as additional gzip
+            members instead - the compressed size and checksums in the
+            returned stats then refer to the appended data only. Not
+            supported for BGZF output.
+        min_free_space (int, default=None): Minimal free space in bytes
+            on the output file system - writing is aborted (and the
+            incomplete output removed) once less space is left. Not
+            checked for streamed outputs.
+        progress_interval (int, default=PROGRESS_INTERVAL): Number of
+            uncompressed bytes read between progress reports.
+        read_buffer_size (int, default=COPY_BLOCK_SIZE): Number of bytes
+            read from the input at a time (when compressing with a single
+            thread).
+        write_buffer_size (int, default=WRITE_BUFFER_SIZE): Size of the
+            buffer through which the output is written - multiple MB
+            perform best on parallel file systems (e.g., Lustre or GPFS).
+        pipeline (bool, default=False): Whether reading (and decompressing)
+            the input, processing the records, compressing and writing
+            the output should run in separate threads, connected by
+            bounded queues - the stages then overlap instead of running
+            one after another on a single core.
+        compression_block_size (int, default=COMPRESSION_BLOCK_SIZE):
+            Number of uncompressed bytes in every gzip member compressed
+            in parallel - smaller blocks need less memory but compress
+            slightly worse. BGZF blocks always have the standard size.
+
+    Raises:
+        ValueError: If the compression level, the thread count, the buffer
+            sizes, the output or record format, the quality offsets, the
+            checksum algorithms or the subsampling, UMI, trimming,
+            deduplication or filtering parameters are not supported.
+    """
+    compression_level: Union[int, str] = 9
+    threads: Optional[int] = 1
+    output_format: str = 'gzip'
+    mode: str = 'auto'
+    validate: bool = False
+    on_length_mismatch: str = 'error'
+    on_truncation: str = 'error'
+    normalize: bool = False
+    multiline: bool = False
+    quality_offset_in: int = 33
+    quality_offset_out: int = 33
+    fill_quality: int = None
+    bin_qualities: bool = False
+    bare_separator: bool = False
+    record_format: str = 'fastq'
+    subsample_fraction: float = None
+    subsample_n: int = None
+    seed: int = None
+    target_bases: int = None
+    deduplicate: str = None
+    min_length: int = None
+    max_length: int = None
+    max_n_fraction: float = None
+    min_mean_quality: float = None
+    sliding_window: Tuple[int, float] = None
+    max_expected_errors: float = None
+    trim_left: int = 0
+    trim_right: int = 0
+    trim_homopolymer: Tuple[str, int] = None
+    adapters: List[str] = None
+    adapter_error_rate: float = 0.1
+    adapter_min_overlap: int = 3
+    min_complexity: float = None
+    umi_length: int = None
+    umi_pattern: str = None
+    umi_format: str = 'suffix'
+    restore_illumina_ids: bool = False
+    stamp_accession: bool = False
+    renumber: bool = False
+    anonymize_salt: str = None
+    qc_report: bool = False
+    checksums: Iterable[str] = ()
+    verify: bool = False
+    overwrite: bool = False
+    append: bool = False
+    min_free_space: int = None
+    progress_interval: int = PROGRESS_INTERVAL
+    read_buffer_size: int = COPY_BLOCK_SIZE
+    write_buffer_size: int = WRITE_BUFFER_SIZE
+    pipeline: bool = False
+    compression_block_size: int = COMPRESSION_BLOCK_SIZE
+
+    def __post_init__(self):
+        if self.compression_level != 'auto' \
+                and self.compression_level not in range(0, 10):
+            raise ValueError(
+                f'Compression level must be between 0 and 9 or "auto" '
+                f'(got {self.compression_level}).'
+            )
+        if self.threads is not None and self.threads < 1:
+            raise ValueError(
+                f'Thread count must be at least 1 (got {self.threads}).'
+            )
+        if self.compression_block_size < 1:
+            raise ValueError(
+                f'Compression block size must be at least 1 byte '
+                f'(got {self.compression_block_size}).'
+            )
+        if self.read_buffer_size < 1 or self.write_buffer_size < 1:
+            raise ValueError(
+                f'Buffer sizes must be at least 1 byte '
+                f'(got {self.read_buffer_size} and {self.write_buffer_size}).'
+            )
+        if self.output_format not in ('gzip', 'bgzf'):
+            raise ValueError(
+                f'Output format must be either "gzip" or "bgzf" '
+                f'(got "{self.output_format}").'
+            )
+        if {self.quality_offset_in, self.quality_offset_out} - {33, 64}:
+            raise ValueError('Quality offsets must be either 33 or 64.')
+        unsupported = set(self.checksums) - hashlib.algorithms_available
+        if unsupported:
+            raise ValueError(
+                f'Unsupported hash algorithms: '
+                f'{", ".join(sorted(unsupported))}.'
+            )
+        if self.record_format not in RECORD_FORMATTERS:
+            raise ValueError(
+                f'Record format must be either "fastq" or "fasta" '
+                f'(got "{self.record_format}").'
+            )
+        if self.subsample_fraction is not None \
+                and self.subsample_n is not None:
+            raise ValueError(
+                'Only one of subsample_fraction and subsample_n can be used.'
+            )
+        if self.subsample_fraction is not None \
+                and not 0 < self.subsample_fraction <= 1:
+            raise ValueError(
+                f'Subsampling fraction must be greater than 0 and at most 1 '
+                f'(got {self.subsample_fraction}).'
+            )
+        if self.subsample_n is not None and self.subsample_n < 0:
+            raise ValueError(
+                f'Number of subsampled records cannot be negative '
+                f'(got {self.subsample_n}).'
+            )
+        if self.umi_length is not None and self.umi_pattern is not None:
+            raise ValueError(
+                'Only one of umi_length and umi_pattern can be used.'
+            )
+        if self.umi_length is not None and self.umi_length < 1:
+            raise ValueError(
+                f'UMI length must be at least 1 (got {self.umi_length}).'
+            )
+        if self.umi_pattern is not None \
+                and re.compile(self.umi_pattern).groups < 1:
+            raise ValueError(
+                f'UMI pattern must capture the UMI in a group '
+                f'(got "{self.umi_pattern}").'
+            )
+        if self.umi_format not in ('suffix', 'rx'):
+            raise ValueError(
+                f'UMI format must be either "suffix" or "rx" '
+                f'(got "{self.umi_format}").'
+            )
+        if self.trim_left < 0 or self.trim_right < 0:
+            raise ValueError(
+                f'Numbers of trimmed bases cannot be negative '
+                f'(got {self.trim_left} and {self.trim_right}).'
+            )
+        if self.adapters is not None and (
+                not all(self.adapters)
+                or not 0 <= self.adapter_error_rate < 1
+                or self.adapter_min_overlap < 1
+        ):
+            raise ValueError(
+                'Adapters cannot be empty, their error rate must be between '
+                '0 and 1 and their minimum overlap at least 1.'
+            )
+        if self.trim_homopolymer is not None and (
+                len(self.trim_homopolymer[0]) != 1
+                or self.trim_homopolymer[1] < 1
+        ):
+            raise ValueError(
+                f'Homopolymer trimming requires a single base and a minimum '
+                f'run length of at least 1 (got {self.trim_homopolymer}).'
+            )
+        if self.sliding_window is not None and self.sliding_window[0] < 1:
+            raise ValueError(
+                f'Sliding window size must be at least 1 '
+                f'(got {self.sliding_window[0]}).'
+            )
+        if self.min_length is not None and self.min_length < 0 or \
+                self.max_length is not None \
+                and self.max_length < (self.min_length or 0):
+            raise ValueError(
+                f'Length limits must not be negative and the maximum length '
+                f'must not be lower than the minimum one '
+                f'(got {self.min_length} and {self.max_length}).'
+            )
+        if self.max_n_fraction is not None \
+                and not 0 <= self.max_n_fraction <= 1:
+            raise ValueError(
+                f'Maximum fraction of ambiguous bases must be between 0 '
+                f'and 1 (got {self.max_n_fraction}).'
+            )
+        if self.min_complexity is not None \
+                and not 0 <= self.min_complexity <= 1:
+            raise ValueError(
+                f'Minimum complexity must be between 0 and 1 '
+                f'(got {self.min_complexity}).'
+            )
+        if self.deduplicate not in (None, 'sequence', 'record'):
+            raise ValueError(
+                f'Deduplication mode must be either "sequence" or "record" '
+                f'(got "{self.deduplicate}").'
+            )
+        if self.target_bases is not None and self.target_bases < 1:
+            raise ValueError(
+                f'Target number of bases must be at least 1 '
+                f'(got {self.target_bases}).'
+            )
+        if self.mode not in ('copy', 'parse', 'auto'):
+            raise ValueError(
+                f'Mode must be either "copy", "parse" or "auto" '
+                f'(got "{self.mode}").'
+            )
+        if self.append and self.output_format == 'bgzf':
+            raise ValueError('BGZF files cannot be appended to.')
+
+
+def _rewrite_fastq(
+        file_in: Union[str, int], file_out: Union[str, int],
+        options: RewriteOptions = None, cancel: CancellationToken = None,
+        progress: Callable[[int, int, int], None] = None, **kwargs
+) -> FastqStats:
+    """Writes a gzip-compressed copy of a FASTQ file.
+
+    The copy is written to `{file_out}.tmp` first and only renamed to
+    (or appended to) file_out once it is complete.
+
+    Args:
+        file_in (str): Path to the FASTQ file - compressed inputs
+            are decompressed on the fly. Standard input ('-'), a file
+            descriptor (e.g., of a pipe from fasterq-dump), a binary file
+            object or an in-memory buffer can be read instead.
+        file_out (Union[str, int]): Path where the compressed file should
+            be written - '-' or a file descriptor to write the compressed
+            stream there instead (e.g., to pipe it into other tools), or
+            a binary file object or a bytearray to write it into.
+        options (RewriteOptions, default=None): How the file should be
+            written and processed - the defaults if None.
+        cancel (CancellationToken, default=None): Token through which the
+            rewrite can be cancelled (e.g., from another thread) - the
+            incomplete output is removed then.
+        progress (Callable[[int, int, int], None], default=None): Function
+            called with the numbers of records written, uncompressed bytes
+            read and compressed bytes written so far - every
+            options.progress_interval bytes read and once the file was
+            written.
+        **kwargs: Attributes of RewriteOptions overriding those
+            of options.
+
+    Returns:
+        FastqStats: Statistics of the written sequences.
+
+    Raises:
+        ValueError: If any of the options are not supported (see
+            RewriteOptions), if output which is streamed should be
+            verified, reported on or appended to, if any of the options
+            requiring parsed records are used in the 'copy' mode, or if
+            the run accession needed to stamp or renumber the reads cannot
+            be derived from the input (see _run_accession).
+        FileExistsError: If file_out exists and may not be overwritten.
+        FastqValidationError: If records were parsed and a malformed
+            record was found.
+        InsufficientSpaceError: If the output file system ran low on space.
+        RewriteCancelledError: If the rewrite was cancelled.
+        CompressionError: If the input file could not be decompressed.
+        FastqRewriteError: If the input file could not be read or the
+            output file could not be written.
+    """
+    options = replace(options or RewriteOptions(), **kwargs)
+    if not _is_stream(file_in):
+        file_in = os.fsdecode(file_in)
+    if not _is_stream(file_out):
+        file_out = os.fsdecode(file_out)
+    threads = options.threads
+    if threads is None:
+        threads = _available_cores()
+    formatter, lines_per_record = RECORD_FORMATTERS[options.record_format]
+    streaming = _is_stream(file_out)
+    if streaming and (options.verify or options.qc_report or options.append):
+        raise ValueError(
+            'Output cannot be verified, reported on or appended to when '
+            'written to standard output or a file descriptor.'
+        )
+    if not streaming and not options.overwrite and not options.append \
+            and os.path.exists(file_out):
+        raise FileExistsError(
+            f'{file_out} already exists - use overwrite=True to replace it.'
+        )
+
+    rng = random.Random(options.seed)
+    offset_in, offset_out = options.quality_offset_in, \
+        options.quality_offset_out
+    steps = {}
+    if offset_in != offset_out:
+        steps['quality_conversion'] = _quality_converter(
+            offset_in, offset_out
+        )
+    if options.restore_illumina_ids:
+        steps['illumina_ids'] = _restore_illumina_id
+    if options.anonymize_salt is not None:
+        steps['anonymize'] = _id_anonymizer(options.anonymize_salt)
+    if options.stamp_accession or options.renumber:
+        accession = _run_accession(file_in)
+    if options.stamp_accession:
+        steps['accession_stamp'] = _accession_stamper(accession)
+    if options.umi_length is not None or options.umi_pattern is not None:
+        steps['umi'] = _umi_extractor(
+            options.umi_length, options.umi_pattern, options.umi_format
+        )
+    if options.trim_left or options.trim_right:
+        steps['fixed_trim'] = _fixed_trimmer(
+            options.trim_left, options.trim_right
+        )
+    if options.adapters:
+        steps['adapter'] = _adapter_trimmer(
+            options.adapters, options.adapter_error_rate,
+            options.adapter_min_overlap
+        )
+    if options.trim_homopolymer is not None:
+        steps['homopolymer'] = _homopolymer_trimmer(*options.trim_homopolymer)
+    if options.sliding_window is not None:
+        steps['sliding_window'] = _sliding_window_trimmer(
+            *options.sliding_window, offset_out
+        )
+    if options.min_length is not None or options.max_length is not None:
+        steps['length'] = _length_filter(
+            options.min_length, options.max_length
+        )
+    if options.max_n_fraction is not None:
+        steps['n_content'] = _n_filter(options.max_n_fraction)
+    if options.min_mean_quality is not None:
+        steps['mean_quality'] = _mean_quality_filter(
+            options.min_mean_quality, offset_out
+        )
+    if options.max_expected_errors is not None:
+        steps['expected_errors'] = _expected_error_filter(
+            options.max_expected_errors, offset_out
+        )
+    if options.min_complexity is not None:
+        steps['complexity'] = _complexity_filter(options.min_complexity)
+    if options.deduplicate:
+        steps['deduplicate'] = _deduplicator(options.deduplicate)
+    if options.subsample_fraction is not None:
+        steps['subsample'] = _subsampler(options.subsample_fraction, rng)
+    if options.bin_qualities:
+        steps['quality_binning'] = _quality_binner(offset_out)
+    parse = bool(steps) or options.validate or options.normalize \
+        or options.multiline or options.on_length_mismatch != 'error' \
+        or options.on_truncation != 'error' \
+        or options.fill_quality is not None \
+        or options.record_format != 'fastq' \
+        or options.subsample_n is not None \
+        or options.target_bases is not None \
+        or options.renumber or options.qc_report
+    if options.mode == 'copy' and parse:
+        raise ValueError(
+            'Records cannot be validated, processed or reported on when '
+            'copied - use mode="parse" or "auto" instead.'
+        )
+
+    stats, clock = FastqStats(), _start_clock()
+    report = QualityReport(offset_out) if options.qc_report else None
+    try:
+        reader = None
+        if parse or options.mode == 'parse':
+            reader = FastqReader(
+                file_in, options.validate, options.on_length_mismatch,
+                options.on_truncation, options.multiline,
+                options.fill_quality, offset_in, options.pipeline
+            )
+            stats.length_mismatches = reader.length_mismatches
+            records = _process_records(
+                reader, steps, stats.dropped, stats.trimmed
+            )
+            if options.subsample_n is not None:
+                records = _reservoir_sample(
+                    records, options.subsample_n, rng, stats.dropped
+                )
+            if options.target_bases is not None:
+                records = _limit_bases(records, options.target_bases)
+            if options.renumber:
+                records = _renumber(records, accession)
+            if report:
+                records = report.observe(records)
+            f_raw = _FastqRecordStream(reader, records, formatter)
+        else:
+            f_raw = _open_fastq(file_in)
+            if options.bare_separator:
+                f_raw = _SeparatorStripper(f_raw)
+        if streaming:
+            output = nullcontext(file_out)
+        else:
+            output = _atomic_output(file_out, ['.gzi'], options.append)
+        LOGGER.debug(
+            'Rewriting %s to %s (%s output, %s, %s threads%s).', file_in,
+            file_out, options.output_format,
+            'parsing records' if reader else 'copying data', threads,
+            ', pipelined' if options.pipeline else ''
+        )
+        with output as path_out:
+            hasher = hashlib.sha256() if options.verify else None
+            reporter = _ProgressReporter(
+                progress, stats, options.progress_interval
+            ) if progress else None
+            f_in = _FastqStatsReader(
+                f_raw, stats, lines_per_record, hasher, cancel, reporter
+            )
+            if options.pipeline:
+                f_in = _PipelineReader(f_in, options.read_buffer_size)
+            with closing(f_in):
+                stats.compressed_bytes, stats.checksums = _compress_stream(
+                    f_in, path_out, options.compression_level, threads,
+                    options.output_format, options.checksums,
+                    options.min_free_space, reporter,
+                    options.read_buffer_size, options.write_buffer_size,
+                    options.pipeline, options.compression_block_size
+                )
+                if reporter:
+                    reporter.report()
+            if options.verify:
+                _verify_written(
+                    path_out, stats.reads, lines_per_record,
+                    hasher.hexdigest()
+                )
+        LOGGER.debug(
+            'Wrote %s reads (%s bytes) from %s to %s.', stats.reads,
+            stats.compressed_bytes, file_in, file_out
+        )
+        if report:
+            report.write(f'{file_out}.qc.json')
+        if stats.uniform_quality:
+            LOGGER.warning(
+                'All quality scores of sequences from %s are the same (as '
+                'in SRA Lite runs) - quality-based analyses of those '
+                'sequences will not be meaningful.', file_in
+            )
+        if reader:
+            stats.filled_qualities = reader.filled
+        if reader and reader.truncated_at is not None:
+            stats.truncated_at = reader.truncated_at
+            LOGGER.warning(
+                'Sequences from %s are truncated - only the first %s '
+                'complete records were written.', file_in, stats.reads
+            )
+    except (EOFError, zlib.error, lzma.LZMAError, gzip.BadGzipFile,
+            _Bz2DecompressionError) as e:
+        raise CompressionError(
+            f'Sequences from {file_in} could not be written '
+            f'to {file_out}: {e}'
+        ) from e
+    except OSError as e:
+        raise FastqRewriteError(
+            f'Sequences from {file_in} could not be written '
+            f'to {file_out}: {e}'
+        ) from e
+    _stop_clock(stats, clock)
+    return stats
+
+
+def _write_multiqc_summary(
+        stats: Dict[str, FastqStats], file_out: str
+):
+    """Writes statistics of multiple files as MultiQC custom content.
+
+    MultiQC picks up JSON files whose names end with _mqc.json and shows
+    them as a table with a row for every file.
+    """
+    data = {
+        name: {
+            'reads': x.reads, 'bases': x.bases,
+            'mean_length': x.mean_length, 'min_length': x.min_length,
+            'max_length': x.max_length,
+            'dropped_reads': sum(x.dropped.values()),
+            'compressed_bytes': x.compressed_bytes,
+        } for name, x in stats.items()
+    }
+    content = {
+        'id': 'q2_fondue_stats',
+        'section_name': 'q2-fondue',
+        'description': 'Statistics of the sequences fetched by q2-fondue.',
+        'plot_type': 'table',
+        'pconfig': {'id': 'q2_fondue_stats_table'},
+        'data': data,
+    }
+    with open(file_out, 'w') as f_out:
+        json.dump(content, f_out, indent=2)
+
+
+def _rewrite_fastq_batch(
+        pairs: List[Tuple[str, str]], n_threads: int = 1,
+        multiqc_summary: str = None, options: RewriteOptions = None,
+        cancel: CancellationToken = None, **kwargs
+) -> List[FastqStats]:
+    """Writes gzip-compressed copies of multiple FASTQ files in parallel.
+
+    Args:
+        pairs (List[Tuple[str, str]]): List of (input, output) file paths.
+        n_threads (int, default=1): Number of files to be compressed
+            concurrently.
+        multiqc_summary (str, default=None): Path where statistics of all
+            the written files should be saved as MultiQC custom content
+            (see _write_multiqc_summary) - the name should end with
+            _mqc.json. Files are named after the outputs, without
+            the extensions.
+        options (RewriteOptions, default=None): How every file should be
+            written and processed - the defaults if None.
+        cancel (CancellationToken, default=None): Token through which all
+            the rewrites can be cancelled.
+        **kwargs: Attributes of RewriteOptions overriding those
+            of options.
+
+    Returns:
+        List[FastqStats]: Statistics of the sequences written to every file.
+
+    Raises:
+        FastqRewriteError: If any of the files could not be rewritten. All
+            the remaining files are still processed before the first
+            encountered error is raised.
+        InsufficientSpaceError: If the output file system ran low on space
+            (see the min_free_space option of RewriteOptions) - the
+            paths to all the completed files are reported with the error.
+        KeyboardInterrupt: If interrupted - the files which are still
+            being written are cancelled (see CancellationToken) and
+            removed before the interrupt is raised again.
+    """
+    options = replace(options or RewriteOptions(), **kwargs)
+    cancel = cancel or CancellationToken()
+    with ThreadPoolExecutor(max_workers=n_threads) as executor:
+        futures = [
+            executor.submit(
+                _rewrite_fastq, file_in, file_out, options, cancel=cancel
+            ) for file_in, file_out in pairs
+        ]
+        try:
+            wait(futures)
+        except KeyboardInterrupt:
+            cancel.cancel()
+            executor.shutdown(cancel_futures=True)
+            raise
+    for future in futures:
+        error = future.exception()
+        if isinstance(error, InsufficientSpaceError):
+            error.completed = [
+                file_out for (_, file_out), x in zip(pairs, futures)
+                if x.exception() is None
+            ]
+            raise error
+    stats = [future.result() for future in futures]
+    if multiqc_summary:
+        _write_multiqc_summary({
+            os.path.basename(file_out).split('.')[0]: x
+            for (_, file_out), x in zip(pairs, stats)
+        }, multiqc_summary)
+    return stats
+
+
+def _rewrite_to_casava(
+        file_in: str, out_dir: str, sample_id: str, read: int = 1,
+        paired: bool = False, **kwargs
+) -> Tuple[str, FastqStats]:
+    """Writes a gzip-compressed copy of a FASTQ file into a Casava
+        directory, named after the sample (see _casava_filename).
+
+    Args:
+        file_in (str): Path to the FASTQ file.
+        out_dir (str): Casava directory where the file should be written.
+        sample_id (str): ID of the sample.
+        read (int, default=1): Read direction, either 1 or 2.
+        paired (bool, default=False): Whether the file holds
+            paired-end reads.
+        **kwargs: Further parameters of _rewrite_fastq (e.g., options).
+
+    Returns:
+        Tuple[str, FastqStats]: Path to the written file and statistics
+            of its sequences.
+    """
+    out_dir = os.fsdecode(out_dir)
+    if read not in (1, 2):
+        raise ValueError(f'Read direction must be either 1 or 2 (got {read}).')
+    file_out = os.path.join(
+        out_dir, _casava_filename(sample_id, read, paired)
+    )
+    return file_out, _rewrite_fastq(file_in, file_out, **kwargs)
@@ -10,7 +10,6 @@ from multiprocessing.managers import SyncManager
 import glob
 from multiprocessing import Pool, Queue, Process, Manager, cpu_count
 
-import gzip
 import os
 import dotenv
 import re
@@ -19,6 +18,7 @@ import subprocess
 import tempfile
 import threading
 import time
+from typing import Optional
 
 from qiime2 import Metadata
 from warnings import warn
@@ -28,11 +28,18 @@ from q2_types.per_sample_sequences import \
     (CasavaOneEightSingleLanePerSampleDirFmt)
 from tqdm import tqdm
 
+from q2_fondue.downloads import (
+    _dump_and_compress, _fetch_from_ena, _prefetch
+)
 from q2_fondue.entrezpy_clients._pipelines import _get_run_ids
 from q2_fondue.entrezpy_clients._utils import set_up_logger
+from q2_fondue.fastq import (
+    _detect_quality_encoding, _check_pairing, FastqGzWriter
+)
 from q2_fondue.utils import (
     _determine_id_type, handle_threaded_exception, DownloadError,
-    _has_enough_space, _find_next_id, _rewrite_fastq
+    _has_enough_space, _find_next_id, _casava_filename,
+    FastqRewriteError, TEMP_FILES, _set_log_level
 )
 
 threading.excepthook = handle_threaded_exception
@@ -41,36 +48,101 @@ LOGGER = set_up_logger('INFO', logger_name=__name__)
 
 
 def _run_cmd_fasterq(
-        acc: str, output_dir: str, threads: int, key_file: str):
-    """Runs fasterq-dump command on a single accession."""
+        acc: str, output_dir: str, threads: int, key_file: str,
+        prefer_ena: bool = False, min_free_space: int = None
+) -> Optional[str]:
+    """Fetches a single accession and dumps its compressed reads.
+
+    The .sra object of the run is downloaded with _prefetch - except for
+    runs with restricted access, which can only be located with the dbGaP
+    repository key, and runs _prefetch fails for, which are downloaded
+    with prefetch instead. Its reads are
+    then dumped into gzip files by fasterq-dump (see _dump_and_compress)
+    and the downloaded objects are removed - dumping is stopped once less
+    than min_free_space bytes are left in output_dir. If prefer_ena is
+    enabled,
+    public runs mirrored on ENA are downloaded from there as they are
+    (see _fetch_from_ena) - falling back to SRA if that fails. Public runs
+    without any reads in SRA (e.g., runs submitted as BAM files only) are
+    converted from the BAM files on ENA.
+
+    Returns:
+        Optional[str]: Error message if the run could not be fetched.
+    """
     if key_file != '':
         key_params = ['--ngc', key_file]
     else:
         key_params = []
 
     cmd_prefetch = ['prefetch', '-X', 'u', '-O', acc, *key_params, acc]
-    cmd_fasterq = [
-        'fasterq-dump', '-e', str(threads), '--size-check', 'on', '-x',
-        *key_params, acc]
-
-    result = subprocess.run(
-        cmd_prefetch, text=True, capture_output=True, cwd=output_dir)
-
-    if result.returncode == 0:
-        result = subprocess.run(
-            cmd_fasterq, text=True, capture_output=True, cwd=output_dir)
-        # clean up prefetch files on success
-        if result.returncode == 0:
-            sra_path = os.path.join(output_dir, acc)
-            if os.path.isdir(sra_path):
-                shutil.rmtree(sra_path)
-            elif os.path.isfile(f'{sra_path}.sra'):
-                os.remove(f'{sra_path}.sra')
-        elif result.returncode == 3 and 'disk-limit exeeded' in result.stderr:
+
+    if prefer_ena and not key_file:
+        try:
+            if _fetch_from_ena(acc, output_dir):
+                return None
+        except DownloadError as e:
+            LOGGER.warning(
+                'Run %s could not be fetched from ENA (%s) - fetching it '
+                'from SRA instead.', acc, e
+            )
+
+    error = None
+    if not key_file:
+        try:
+            _prefetch(acc, output_dir)
+        except DownloadError as e:
+            LOGGER.warning(
+                'Run %s could not be downloaded (%s) - falling back to '
+                'prefetch.', acc, e
+            )
+            error = str(e)
+    if key_file or error:
+        try:
+            result = subprocess.run(
+                cmd_prefetch, text=True, capture_output=True,
+                cwd=output_dir)
+        except OSError as e:
+            # e.g., the SRA Toolkit is not installed
+            return error or str(e)
+        if result.returncode != 0:
+            return result.stderr
+
+    sra_path = os.path.join(output_dir, acc)
+    if not os.path.isdir(sra_path) and os.path.isfile(f'{sra_path}.sra'):
+        sra_path = f'{sra_path}.sra'
+    try:
+        stats = _dump_and_compress(
+            acc, sra_path, output_dir, threads, key_file,
+            min_free_space=min_free_space
+        )
+    except DownloadError as e:
+        if 'disk-limit exeeded' in str(e):
             LOGGER.error(
                 'Not enough space for fasterq-dump to process ID=%s.', acc
             )
-    return result
+        return str(e)
+    except (FastqRewriteError, OSError) as e:
+        # the dumped reads could not be parsed or written
+        return str(e)
+
+    # clean up prefetch files on success
+    if os.path.isdir(sra_path):
+        shutil.rmtree(sra_path)
+    elif os.path.isfile(sra_path):
+        os.remove(sra_path)
+
+    if not stats and not key_file:
+        # runs submitted as aligned reads only may come without any reads
+        # in SRA - ENA provides their submitted BAM files instead
+        LOGGER.warning(
+            'fasterq-dump found no reads for %s - fetching its submitted '
+            'files from ENA instead.', acc
+        )
+        try:
+            _fetch_from_ena(acc, output_dir)
+        except DownloadError as e:
+            return str(e)
+    return None
 
 
 def _get_remaining_ids_with_storage_error(acc_id: str, progress_bar: tqdm):
@@ -85,9 +157,9 @@ def _get_remaining_ids_with_storage_error(acc_id: str, progress_bar: tqdm):
 
 def _run_fasterq_dump_for_all(
         accession_ids, tmpdirname, threads, key_file, retries,
-        fetched_queue, done_queue
+        fetched_queue, done_queue, prefer_ena=False, min_free_space=None
 ):
-    """Runs prefetch & fasterq-dump for all ids in accession_ids.
+    """Fetches & runs fasterq-dump for all ids in accession_ids.
 
     Args:
         accession_ids (list): List of all run IDs to be fetched.
@@ -99,6 +171,10 @@ def _run_fasterq_dump_for_all(
             that were successfully fetched.
         done_queue (SyncManager.Queue): Queue communicating filenames
             that were completely processed.
+        prefer_ena (bool): Whether runs mirrored on ENA should be
+            downloaded from there.
+        min_free_space (int): Minimal free space in bytes which should be
+            left in tmpdirname while dumping a run.
 
     Returns:
         failed_ids (dict): Failed run IDs with corresponding errors.
@@ -118,10 +194,11 @@ def _run_fasterq_dump_for_all(
                 f'Downloading sequences for run {acc} '
                 f'(attempt {-retries + init_retries + 1})'
             )
-            result = _run_cmd_fasterq(
-                acc, tmpdirname, threads, key_file)
-            if result.returncode != 0:
-                failed_ids[acc] = result.stderr
+            error = _run_cmd_fasterq(
+                acc, tmpdirname, threads, key_file, prefer_ena,
+                min_free_space)
+            if error is not None:
+                failed_ids[acc] = error
             else:
                 fetched_queue.put(acc)
             pbar.postfix = f'{len(failed_ids)} failed'
@@ -172,18 +249,22 @@ def _process_one_sequence(filename, output_dir):
     """Renames sequence files to follow the required naming convention."""
     # Renaming
     new_name, is_paired = None, False
-    if filename.endswith('_1.fastq'):
+    casava = re.search(r'_(00|01)_L001_R[12]_001\.fastq\.gz$', filename)
+    if casava:
+        # fetched from ENA - named already
+        return filename, casava.group(1) == '00'
+    elif filename.endswith('_1.fastq.gz'):
         # paired-end _1: barcode 00
-        acc = re.search(r'(.*)_1\.fastq$', filename).group(1)
-        new_name, is_paired = '%s_00_L001_R1_001.fastq' % acc, True
-    elif filename.endswith('_2.fastq'):
+        acc = re.search(r'(.*)_1\.fastq\.gz$', filename).group(1)
+        new_name, is_paired = _casava_filename(acc, 1, True), True
+    elif filename.endswith('_2.fastq.gz'):
         # paired-end _2
-        acc = re.search(r'(.*)_2\.fastq$', filename).group(1)
-        new_name, is_paired = '%s_00_L001_R2_001.fastq' % acc, True
-    elif filename.endswith('.fastq'):
+        acc = re.search(r'(.*)_2\.fastq\.gz$', filename).group(1)
+        new_name, is_paired = _casava_filename(acc, 2, True), True
+    elif filename.endswith('.fastq.gz'):
         # single-reads: barcode 01
-        acc = re.search(r'(.*)\.fastq$', filename).group(1)
-        new_name, is_paired = '%s_01_L001_R1_001.fastq' % acc, False
+        acc = re.search(r'(.*)\.fastq\.gz$', filename).group(1)
+        new_name = _casava_filename(acc, 1, False)
     else:
         return new_name, is_paired
     os.rename(os.path.join(output_dir, filename),
@@ -200,7 +281,7 @@ def _process_downloaded_sequences(
     them and inserts processed filenames into the renaming_queue when finished.
     """
     for _id in iter(fetched_queue.get, None):
-        filenames = glob.glob(os.path.join(output_dir, f'{_id}*.fastq'))
+        filenames = glob.glob(os.path.join(output_dir, f'{_id}*.fastq.gz'))
         filenames = [
             _process_one_sequence(f, output_dir) for f in filenames
         ]
@@ -225,39 +306,96 @@ def _write_empty_casava(read_type, casava_out_path):
     )
 
     if read_type == 'single':
-        ls_file_names = ['xxx_01_L001_R1_001.fastq.gz']
+        ls_file_names = [_casava_filename('xxx')]
     else:
-        ls_file_names = ['xxx_00_L001_R1_001.fastq.gz',
-                         'xxx_00_L001_R2_001.fastq.gz']
+        ls_file_names = [_casava_filename('xxx', 1, True),
+                         _casava_filename('xxx', 2, True)]
     # create empty CasavaDirFmt due to Q2 not supporting optional
     # output types
     for new_empty_name in ls_file_names:
         path_out = str(casava_out_path.path / new_empty_name)
-        with gzip.open(str(path_out), mode='w'):
-            pass
+        FastqGzWriter(path_out).close()
+
+
+def _warn_on_quality_encoding(path: str):
+    """Warns if a file seems to be Phred+64- or Solexa-encoded or if all of
+        its quality scores are the same (as in SRA Lite runs) - files whose
+        encoding cannot be detected are imported as they are."""
+    try:
+        encoding = _detect_quality_encoding(path)
+    except (FastqRewriteError, UnicodeDecodeError) as e:
+        LOGGER.warning(
+            'Quality encoding of %s could not be detected: %s',
+            os.path.basename(path), e
+        )
+        return
+    if encoding in ('phred64', 'solexa'):
+        LOGGER.warning(
+            'Quality scores in %s do not seem to be Phred+33-encoded '
+            '(detected encoding: %s). They may not be interpreted '
+            'correctly once imported.', os.path.basename(path), encoding
+        )
+    elif encoding == 'uniform':
+        LOGGER.warning(
+            'All quality scores in %s are the same (as in SRA Lite runs) - '
+            'quality-based analyses of those sequences will not be '
+            'meaningful.', os.path.basename(path)
+        )
+
+
+def _warn_on_desync(path_r1: str, path_r2: str, acc: str):
+    """Warns if the forward and reverse reads of a run are out of sync -
+        files which cannot be compared are imported as they are."""
+    try:
+        divergence = _check_pairing(path_r1, path_r2)
+    except FastqRewriteError as e:
+        LOGGER.warning('Pairing of %s could not be checked: %s', acc, e)
+        return
+    if divergence:
+        LOGGER.warning(
+            'Forward and reverse reads of %s are out of sync from '
+            'record %s on (%s vs. %s).', acc, *divergence
+        )
 
 
 def _copy_to_casava(
-        filenames: list, tmp_dir: str, casava_result_path: str
+        filenames: list, tmp_dir: str, casava_result_path: str,
+        check_pairing: bool = False
 ):
-    """Copies single/paired-end sequences to Casava directory.
-
-    Downloaded sequence files (single- or paired-end) will be
-    copied from tmp_dir to casava_result_path.
+    """Moves single/paired-end sequences to Casava directory.
+
+    Downloaded (compressed) sequence files, single- or paired-end, will be
+    moved from tmp_dir to casava_result_path. If any of the files cannot be
+    moved, all the outputs are removed to not leave incomplete samples
+    behind. A warning is issued for files which seem to be Phred+64- or
+    Solexa-encoded or only have a single quality score (see
+    _detect_quality_encoding) and, if check_pairing is enabled, for
+    paired-end files
+    whose reads are out of sync - which takes an extra pass over both
+    files.
     """
-    fwd_path_in = os.path.join(tmp_dir, filenames[0])
-    fwd_path_out = os.path.join(casava_result_path, f'{filenames[0]}.gz')
-    _rewrite_fastq(fwd_path_in, fwd_path_out)
-
-    if len(filenames) > 1:
-        rev_path_in = os.path.join(tmp_dir, filenames[1])
-        rev_path_out = os.path.join(casava_result_path, f'{filenames[1]}.gz')
-        _rewrite_fastq(rev_path_in, rev_path_out)
+    paths = [
+        (os.path.join(tmp_dir, filename),
+         os.path.join(casava_result_path, filename))
+        for filename in filenames
+    ]
+    for path_in, _ in paths:
+        _warn_on_quality_encoding(path_in)
+    if check_pairing and len(paths) == 2:
+        _warn_on_desync(paths[0][0], paths[1][0], filenames[0].split('_')[0])
+    try:
+        for path_in, path_out in paths:
+            shutil.move(path_in, path_out)
+    except OSError:
+        for _, path_out in paths:
+            if os.path.isfile(path_out):
+                os.remove(path_out)
+        raise
 
 
 def _write2casava_dir(
         tmp_dir, casava_out_single, casava_out_paired,
-        renaming_queue, done_queue
+        renaming_queue, done_queue, check_pairing=False
 ):
     """Writes single- or paired-end files to casava directory.
 
@@ -266,18 +404,44 @@ def _write2casava_dir(
     For example, [('fileA', False)] would be processed as single-end,
     while [('fileB_1', True), ('fileB_2', True)] as paired-end.
     When done, it inserts filenames into the done_queue to announce completion.
+    If the files could not be written (or a job contains more than two
+    files), the corresponding run ID will be inserted into the done_queue
+    as failed, together with the error message.
+    Paired-end reads are checked for being in sync if check_pairing is
+    enabled (see _copy_to_casava).
     """
     for filenames in iter(renaming_queue.get, None):
         if len(filenames) == 1:
-            filename = os.path.split(filenames[0][0])[-1]
-            _copy_to_casava([filename], tmp_dir, casava_out_single)
-            done_queue.put([filename])
+            filenames = [os.path.split(filenames[0][0])[-1]]
+            casava_out = casava_out_single
         elif len(filenames) == 2:
             filenames = [
                 os.path.split(x[0])[-1] for x in sorted(filenames)
             ]
-            _copy_to_casava(filenames, tmp_dir, casava_out_paired)
+            casava_out = casava_out_paired
+        else:
+            # a run should never result in more than two files per read
+            # type - rather than picking some of them, the run is failed
+            names = sorted(os.path.split(x[0])[-1] for x in filenames)
+            acc = names[0].split('_')[0]
+            LOGGER.error(
+                'Sequences of run %s could not be written: found %s files.',
+                acc, len(names)
+            )
+            done_queue.put({'failed_ids': {
+                acc: f'Expected one or two files of the same read type but '
+                     f'found {len(names)}: {", ".join(names)}.'
+            }})
+            renaming_queue.task_done()
+            continue
+
+        try:
+            _copy_to_casava(filenames, tmp_dir, casava_out, check_pairing)
             done_queue.put(filenames)
+        except OSError as e:
+            acc = filenames[0].split('_')[0]
+            LOGGER.error('Sequences of run %s could not be written.', acc)
+            done_queue.put({'failed_ids': {acc: str(e)}})
         renaming_queue.task_done()
     return True
 
@@ -287,8 +451,8 @@ def _announce_completion(queue: SyncManager.Queue):
         a queue and retrieve its all elements.
 
     List of filenames will be retrieved and assigned to either a single- or
-    paired-end list of outputs. The single dictionary containing failed IDs
-    will also be retrieved and returned.
+    paired-end list of outputs. All the dictionaries containing failed IDs
+    will also be retrieved, merged and returned.
 
     Args:
         queue (SyncManager.Queue): an instance of a queue which should
@@ -306,7 +470,7 @@ def _announce_completion(queue: SyncManager.Queue):
         if isinstance(i, list):
             results.append(i)
         elif isinstance(i, dict):
-            failed_ids = i['failed_ids']
+            failed_ids.update(i['failed_ids'])
     single_files = [x for x in results if len(x) == 1]
     paired_files = [x for x in results if len(x) == 2]
     return failed_ids, single_files, paired_files
@@ -315,7 +479,8 @@ def _announce_completion(queue: SyncManager.Queue):
 def get_sequences(
         accession_ids: Metadata, email: str, retries: int = 2,
         n_jobs: int = 1, log_level: str = 'INFO',
-        restricted_access: bool = False
+        restricted_access: bool = False, prefer_ena: bool = False,
+        min_free_space: int = 1024
 ) -> (CasavaOneEightSingleLanePerSampleDirFmt,
       CasavaOneEightSingleLanePerSampleDirFmt,
       pd.DataFrame):
@@ -334,6 +499,10 @@ def get_sequences(
         retries (int, default=2): Number of retries to fetch sequences.
         restricted_access (bool, default=False): If sequence fetch requires
         dbGaP repository key.
+        prefer_ena (bool, default=False): If runs mirrored on ENA should be
+        downloaded from there.
+        min_free_space (int, default=1024): Minimal free space (in MB) to be
+        left on the disk while a run is dumped - 0 disables this check.
         n_jobs (int, default=1): Number of threads to be used in parallel.
         log_level (str, default='INFO'): Logging level.
 
@@ -346,6 +515,7 @@ def get_sequences(
         failed_ids (pd.DataFrame): Run IDs that failed to download with errors.
     """
     LOGGER.setLevel(log_level.upper())
+    _set_log_level(log_level)
 
     casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
     casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
@@ -359,11 +529,12 @@ def get_sequences(
         )
 
     fetched_q = Queue()
-    manager = Manager()
-    renamed_q = manager.Queue()
-    processed_q = manager.Queue()
 
-    with tempfile.TemporaryDirectory() as tmp_dir:
+    # clean up the temporary data when cancelled by a job scheduler
+    with TEMP_FILES.remove_on_termination(), Manager() as manager, \
+            tempfile.TemporaryDirectory() as tmp_dir:
+        renamed_q = manager.Queue()
+        processed_q = manager.Queue()
         # get dbGAP key for restricted access sequences
         if restricted_access:
             dotenv.load_dotenv()
@@ -381,7 +552,8 @@ def get_sequences(
             target=_run_fasterq_dump_for_all,
             args=(
                 sorted(accession_ids), tmp_dir, n_jobs, key_file, retries,
-                fetched_q, processed_q
+                fetched_q, processed_q, prefer_ena,
+                min_free_space * 1024 ** 2 or None
             ),
             daemon=True
         )
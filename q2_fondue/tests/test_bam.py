@@ -0,0 +1,252 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import os
+import struct
+import unittest
+from unittest.mock import patch
+
+from parameterized import parameterized
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.bam import (
+    BamConversionStats, _bam_to_fastq, _cram_to_fastq, _expand_ref_template,
+    _locate_reference, _reference_locations
+)
+from q2_fondue.compression import BGZF_EOF, _compress_bgzf_block
+from q2_fondue.fastq import FastqReader
+from q2_fondue.utils import FastqParseError, FastqRewriteError
+
+
+class BamTestCase(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def setUp(self):
+        super().setUp()
+        self.bam = os.path.join(self.temp_dir.name, 'SRR123456.bam')
+        self.outputs = [
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1', 'r2', 'singles')
+        ]
+
+    @staticmethod
+    def _record(name, flag, seq, qual=None):
+        codes = ['=ACMGRSVTWYHKDBN'.index(x) for x in seq]
+        codes += [0] * (len(codes) % 2)
+        packed = bytes(
+            x << 4 | y for x, y in zip(codes[::2], codes[1::2])
+        )
+        qual = bytes(ord(x) - 33 for x in qual) if qual \
+            else b'\xff' * len(seq)
+        name = name.encode() + b'\x00'
+        body = struct.pack(
+            '<iiBBHHHiiii', 0, 100, len(name), 60, 4680, 0, flag,
+            len(seq), 0, 200, 0
+        ) + name + packed + qual
+        return struct.pack('<i', len(body)) + body
+
+    def write_bam(self, *records, truncate=0):
+        text = b'@HD\tVN:1.6\tSO:coordinate\n'
+        data = b'BAM\x01' + struct.pack('<i', len(text)) + text + \
+            struct.pack('<ii', 1, 5) + b'chr1\x00' + \
+            struct.pack('<i', 1000) + b''.join(records)
+        data = data[:len(data) - truncate]
+        with open(self.bam, 'wb') as f_out:
+            for i in range(0, len(data), 50):
+                f_out.write(_compress_bgzf_block(data[i:i + 50], 6))
+            f_out.write(BGZF_EOF)
+
+    def read_outputs(self):
+        obs = []
+        for file_out in self.outputs:
+            with FastqReader(file_out) as reader:
+                obs.append(list(reader))
+        return obs
+
+
+class TestBamConversion(BamTestCase):
+
+    def test_bam_to_fastq(self):
+        self.write_bam(
+            self._record('p1', 0x1 | 0x40, 'ACGTN', 'IIII#'),
+            self._record('p2', 0x1 | 0x80 | 0x10, 'AACC', 'ABCD'),
+            self._record('s1', 0x10, 'ACG', '123'),
+            self._record('p1', 0x1 | 0x80 | 0x100, 'ACGTN', 'IIII#'),
+            self._record('p1', 0x1 | 0x80, 'TTT', 'FFF'),
+            self._record('p2', 0x1 | 0x40, 'GGG', 'HHH'),
+            self._record('p3', 0x1 | 0x40 | 0x800, 'A', 'I'),
+            self._record('p4', 0x1 | 0x40, 'CA', 'II'),
+        )
+
+        obs = _bam_to_fastq(self.bam, *self.outputs)
+
+        self.assertEqual(obs, BamConversionStats(
+            pairs=2, singles=1, orphans=1, skipped=2
+        ))
+        r1, r2, singles = self.read_outputs()
+        self.assertListEqual(r1, [
+            ('p1', 'ACGTN', 'IIII#'), ('p2', 'GGG', 'HHH')
+        ])
+        self.assertListEqual(r2, [
+            ('p1', 'TTT', 'FFF'), ('p2', 'GGTT', 'DCBA')
+        ])
+        self.assertListEqual(singles, [
+            ('s1', 'CGT', '321'), ('p4', 'CA', 'II')
+        ])
+
+    def test_bam_to_fastq_missing_qualities(self):
+        self.write_bam(self._record('s1', 0, 'ACGTA'))
+
+        _bam_to_fastq(self.bam, *self.outputs)
+
+        _, _, singles = self.read_outputs()
+        self.assertListEqual(singles, [('s1', 'ACGTA', '"""""')])
+
+    def test_bam_to_fastq_not_bam(self):
+        with self.assertRaisesRegex(FastqParseError, 'is not a BAM file'):
+            _bam_to_fastq(self.get_data_path('SRR123456.fastq'), *self.outputs)
+        self.assertFalse(any(os.path.exists(x) for x in self.outputs))
+
+    def test_bam_to_fastq_truncated(self):
+        self.write_bam(self._record('s1', 0, 'ACGT', 'IIII'), truncate=2)
+
+        with self.assertRaisesRegex(FastqParseError, 'is truncated'):
+            _bam_to_fastq(self.bam, *self.outputs)
+        self.assertListEqual(
+            os.listdir(self.temp_dir.name), ['SRR123456.bam']
+        )
+
+
+class TestCramConversion(BamTestCase):
+    md5 = '0123456789abcdef0123456789abcdef'
+
+    def setUp(self):
+        super().setUp()
+        self.cram = os.path.join(self.temp_dir.name, 'SRR123456.cram')
+        with open(self.cram, 'wb') as f_out:
+            f_out.write(b'CRAM\x03\x00' + b'\x00' * 20)
+        self.write_bam(self._record('s1', 0, 'ACGT', 'IIII'))
+        self.log = os.path.join(self.temp_dir.name, 'samtools.log')
+        self.write_samtools()
+
+    def write_samtools(self, returncode=0):
+        bin_dir = os.path.join(self.temp_dir.name, 'bin')
+        os.makedirs(bin_dir, exist_ok=True)
+        samtools = os.path.join(bin_dir, 'samtools')
+        with open(samtools, 'w') as f_out:
+            f_out.write(
+                f'#!/bin/sh\n'
+                f'echo "$@ REF_PATH=$REF_PATH" >> {self.log}\n'
+                f'case "$*" in *-H*)\n'
+                f'  printf "@SQ\\tSN:chr1\\tLN:1000\\tM5:{self.md5}\\n"\n'
+                f'  exit 0;;\n'
+                f'esac\n'
+                f'cat {self.bam}\n'
+                f'[ {returncode} -eq 0 ] || echo "no reference" >&2\n'
+                f'exit {returncode}\n'
+            )
+        os.chmod(samtools, 0o755)
+        path = f'{bin_dir}{os.pathsep}{os.environ["PATH"]}'
+        patcher = patch.dict(os.environ, {'PATH': path})
+        patcher.start()
+        self.addCleanup(patcher.stop)
+
+    def test_cram_to_fastq_reference(self):
+        reference = os.path.join(self.temp_dir.name, 'ref.fa')
+
+        obs = _cram_to_fastq(self.cram, *self.outputs, reference=reference)
+
+        self.assertEqual(obs, BamConversionStats(singles=1))
+        _, _, singles = self.read_outputs()
+        self.assertListEqual(singles, [('s1', 'ACGT', 'IIII')])
+        with open(self.log) as f_log:
+            self.assertIn(f'view -u --no-PG -T {reference}', f_log.read())
+
+    def test_cram_to_fastq_local_reference(self):
+        refs = os.path.join(self.temp_dir.name, 'refs')
+        os.makedirs(os.path.join(refs, '01'))
+        open(os.path.join(refs, '01', self.md5[2:]), 'w').close()
+
+        with patch.dict(os.environ, {'REF_PATH': f'{refs}/%2s/%s'}):
+            obs = _cram_to_fastq(self.cram, *self.outputs)
+
+        self.assertEqual(obs.singles, 1)
+        with open(self.log) as f_log:
+            self.assertIn(f'REF_PATH={refs}/%2s/%s', f_log.read())
+
+    def test_cram_to_fastq_missing_reference(self):
+        refs = os.path.join(self.temp_dir.name, 'refs')
+
+        with patch.dict(os.environ, {'REF_PATH': f'{refs}/%s'}), \
+                self.assertRaisesRegex(FastqRewriteError, self.md5):
+            _cram_to_fastq(self.cram, *self.outputs)
+        self.assertFalse(any(os.path.exists(x) for x in self.outputs))
+
+    def test_cram_to_fastq_samtools_error(self):
+        self.write_samtools(returncode=1)
+
+        with self.assertRaisesRegex(
+                FastqRewriteError, r'\(exit code 1\): no reference'
+        ):
+            _cram_to_fastq(self.cram, *self.outputs)
+        self.assertFalse(any(os.path.exists(x) for x in self.outputs))
+
+    @patch('shutil.which', return_value=None)
+    def test_cram_to_fastq_no_samtools(self, mock_which):
+        with self.assertRaisesRegex(FastqRewriteError, 'samtools is required'):
+            _cram_to_fastq(self.cram, *self.outputs)
+
+    def test_cram_to_fastq_not_cram(self):
+        with self.assertRaisesRegex(FastqParseError, 'is not a CRAM file'):
+            _cram_to_fastq(self.bam, *self.outputs)
+
+    @parameterized.expand([
+        ('nested', '/refs/%2s/%2s/%s',
+         '/refs/01/23/456789abcdef0123456789abcdef'),
+        ('flat', '/refs/%s.fa', f'/refs/{md5}.fa'),
+        ('url', 'https://ref.org/%4s?id=%s',
+         'https://ref.org/0123?id=456789abcdef0123456789abcdef'),
+    ])
+    def test_expand_ref_template(self, name, template, exp):
+        self.assertEqual(_expand_ref_template(template, self.md5), exp)
+
+    def test_reference_locations(self):
+        env = {
+            'REF_PATH': '/refs/%s:https://ref.org/%s:/more/%s',
+            'REF_CACHE': '/cache/%s'
+        }
+        with patch.dict(os.environ, env):
+            obs = _reference_locations()
+
+        self.assertEqual(obs, (
+            ['/refs/%s', 'https://ref.org/%s', '/more/%s'], '/cache/%s'
+        ))
+
+    @patch.dict(os.environ, {'XDG_CACHE_HOME': '/cache'})
+    def test_reference_locations_default(self):
+        with patch.dict(os.environ):
+            os.environ.pop('REF_PATH', None)
+            os.environ.pop('REF_CACHE', None)
+            obs = _reference_locations()
+
+        self.assertEqual(obs, (
+            ['https://www.ebi.ac.uk/ena/cram/md5/%s'],
+            '/cache/hts-ref/%2s/%2s/%s'
+        ))
+
+    def test_locate_reference_cache(self):
+        cached = os.path.join(self.temp_dir.name, self.md5)
+        open(cached, 'w').close()
+        env = {'REF_CACHE': f'{self.temp_dir.name}/%s', 'REF_PATH': ''}
+
+        with patch.dict(os.environ, env):
+            self.assertEqual(_locate_reference(self.md5), cached)
+            self.assertIsNone(_locate_reference('f' * 32))
+
+
+if __name__ == "__main__":
+    unittest.main()
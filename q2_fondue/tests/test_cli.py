@@ -0,0 +1,154 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import gzip
+import io
+import json
+import os
+from unittest.mock import patch
+
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.cli import main
+
+
+class TestCli(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def setUp(self):
+        super().setUp()
+        self.file_in = self.get_data_path('SRR123456.fastq')
+        self.file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+    def run_main(self, *argv):
+        with patch('sys.stdout', new_callable=io.StringIO) as stdout:
+            code = main(list(argv))
+        return code, json.loads(stdout.getvalue())
+
+    def test_rewrite(self):
+        code, obs = self.run_main(
+            'rewrite', self.file_in, self.file_out, '--min-length', '270',
+            '--checksum', 'md5'
+        )
+
+        self.assertEqual(code, 0)
+        self.assertEqual(obs['reads'], 2)
+        self.assertEqual(obs['dropped'], {'length': 1})
+        self.assertEqual(obs['compressed_bytes'],
+                         os.path.getsize(self.file_out))
+        self.assertIn('md5', obs['checksums'])
+
+    def test_rewrite_existing_output(self):
+        open(self.file_out, 'w').close()
+
+        with patch('sys.stderr', new_callable=io.StringIO) as stderr, \
+                self.assertRaises(SystemExit) as cm:
+            main(['rewrite', self.file_in, self.file_out])
+
+        self.assertEqual(cm.exception.code, 1)
+        self.assertIn('fondue-fastq: error:', stderr.getvalue())
+        self.assertIn('already exists', stderr.getvalue())
+
+    def test_rewrite_auto_compression_level(self):
+        code, obs = self.run_main(
+            'rewrite', self.file_in, self.file_out,
+            '--compression-level', 'auto'
+        )
+
+        self.assertEqual(obs['reads'], 3)
+        with open(self.file_in, 'rb') as fin, \
+                gzip.open(self.file_out, 'rb') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    def test_stats(self):
+        code, obs = self.run_main('stats', self.file_in)
+
+        self.assertEqual(code, 0)
+        self.assertEqual(obs['reads'], 3)
+        self.assertNotIn('length_histogram', obs)
+
+    def test_stats_histograms(self):
+        code, obs = self.run_main('stats', self.file_in, '--histograms')
+
+        self.assertEqual(code, 0)
+        self.assertEqual(sum(obs['length_histogram'].values()), 3)
+        self.assertEqual(sum(obs['mean_quality_histogram'].values()), 3)
+
+    def test_pair_check(self):
+        code, obs = self.run_main(
+            'pair-check', self.get_data_path('SRR123457_1.fastq'),
+            self.get_data_path('SRR123457_2.fastq')
+        )
+
+        self.assertEqual(code, 0)
+        self.assertDictEqual(obs, {'paired': True})
+
+    def test_pair_check_diverging(self):
+        file_r1 = os.path.join(self.temp_dir.name, 'r1.fastq')
+        file_r2 = os.path.join(self.temp_dir.name, 'r2.fastq')
+        with open(file_r1, 'w') as f1, open(file_r2, 'w') as f2:
+            f1.write('@a/1\nA\n+\nI\n@b/1\nA\n+\nI\n')
+            f2.write('@a/2\nA\n+\nI\n@c/2\nA\n+\nI\n')
+
+        code, obs = self.run_main('pair-check', file_r1, file_r2)
+
+        self.assertEqual(code, 1)
+        self.assertDictEqual(obs, {
+            'paired': False, 'record': 2,
+            'forward_id': 'b/1', 'reverse_id': 'c/2'
+        })
+
+    def test_missing_input(self):
+        missing = os.path.join(self.temp_dir.name, 'missing.fastq')
+        for argv in (['stats', missing], ['pair-check', missing, missing]):
+            with self.subTest(command=argv[0]), \
+                    patch('sys.stderr', new_callable=io.StringIO) as stderr, \
+                    self.assertRaises(SystemExit) as cm:
+                main(argv)
+
+            self.assertEqual(cm.exception.code, 1)
+            self.assertIn('fondue-fastq: error:', stderr.getvalue())
+            self.assertIn('missing.fastq', stderr.getvalue())
+
+    def test_subsample(self):
+        code, obs = self.run_main(
+            'subsample', self.file_in, self.file_out, '-n', '2',
+            '--seed', '42'
+        )
+
+        self.assertEqual(obs['reads'], 2)
+        self.assertEqual(obs['dropped'], {'subsample': 1})
+
+    def test_merge(self):
+        code, obs = self.run_main(
+            'merge', self.file_out, self.file_in,
+            self.get_data_path('SRR123457_1.fastq'), '--prefix-ids'
+        )
+
+        self.assertEqual(code, 0)
+        with gzip.open(self.file_out, 'rt') as fout:
+            headers = fout.readlines()[::4]
+        self.assertEqual(obs['reads'], len(headers))
+        self.assertTrue(headers[0].startswith('@SRR123456:'))
+        self.assertTrue(headers[-1].startswith('@SRR123457:'))
+
+    def test_merge_existing_output(self):
+        open(self.file_out, 'w').close()
+
+        with patch('sys.stderr', new_callable=io.StringIO) as stderr, \
+                self.assertRaises(SystemExit) as cm:
+            main(['merge', self.file_out, self.file_in])
+
+        self.assertEqual(cm.exception.code, 1)
+        self.assertIn('already exists', stderr.getvalue())
+
+        code, obs = self.run_main(
+            'merge', self.file_out, self.file_in, '--overwrite'
+        )
+
+        self.assertEqual(code, 0)
+        self.assertEqual(obs['reads'], 3)
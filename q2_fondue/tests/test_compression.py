@@ -0,0 +1,187 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import gzip
+import io
+import mmap
+import os
+import unittest
+from unittest.mock import patch
+
+from parameterized import parameterized
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.compression import (
+    _AdaptiveLevel, _PipelineReader, _concat_gzip_files, _open_fastq,
+    verify_gzip
+)
+from q2_fondue.rewrite import _rewrite_fastq
+from q2_fondue.utils import CorruptGzipError, FastqRewriteError
+
+
+class TestCompression(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def test_open_fastq_mapped(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+
+        with _open_fastq(file_in) as f_in, open(file_in, 'rb') as f_exp:
+            self.assertIsInstance(f_in, mmap.mmap)
+            self.assertEqual(f_in.readline(), f_exp.readline())
+            self.assertEqual(f_in.read(), f_exp.read())
+
+    def test_open_fastq_mapped_empty(self):
+        file_in = os.path.join(self.temp_dir.name, 'empty.fastq')
+        open(file_in, 'w').close()
+
+        with _open_fastq(file_in) as f_in:
+            self.assertNotIsInstance(f_in, mmap.mmap)
+            self.assertEqual(f_in.read(), b'')
+
+    def test_pipeline_reader(self):
+        with _PipelineReader(io.BytesIO(b'abcdefgh'), 3, depth=1) as f_in:
+            self.assertEqual(f_in.read(4), b'abcd')
+            self.assertEqual(f_in.read(1), b'e')
+            self.assertEqual(f_in.read(), b'fgh')
+            self.assertEqual(f_in.read(2), b'')
+
+    def test_pipeline_reader_closed_early(self):
+        f_src = io.BytesIO(b'abcdefgh' * 10)
+        f_in = _PipelineReader(f_src, 1, depth=1)
+
+        self.assertEqual(f_in.read(2), b'ab')
+        f_in.close()
+
+        self.assertFalse(f_in._thread.is_alive())
+        self.assertTrue(f_src.closed)
+
+    def test_adaptive_level(self):
+        adaptive = _AdaptiveLevel(2, 6)
+        self.assertEqual(adaptive.level, 4)
+
+        # compression is the slowest stage
+        adaptive.update(io_time=1.0, wait_time=2.0)
+        self.assertEqual(adaptive.level, 3)
+        # neither stage is much slower
+        adaptive.update(io_time=1.0, wait_time=0.5)
+        self.assertEqual(adaptive.level, 3)
+        # reading and writing are the slowest stages
+        for _ in range(5):
+            adaptive.update(io_time=1.0, wait_time=0.1)
+        self.assertEqual(adaptive.level, 6)
+        for _ in range(5):
+            adaptive.update(io_time=0.0, wait_time=0.1)
+        self.assertEqual(adaptive.level, 2)
+
+    def test_concat_gzip_files(self):
+        inputs = []
+        for i, output_format in enumerate(('gzip', 'bgzf', 'gzip')):
+            file_out = os.path.join(self.temp_dir.name, f'in{i}.fastq.gz')
+            _rewrite_fastq(
+                self.get_data_path('SRR123456.fastq'), file_out,
+                output_format=output_format
+            )
+            inputs.append(file_out)
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        obs = _concat_gzip_files(inputs, file_out)
+
+        self.assertEqual(
+            obs, sum(os.path.getsize(x) for x in inputs)
+        )
+        with open(self.get_data_path('SRR123456.fastq'), 'rb') as fin, \
+                gzip.open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), fin.read() * 3)
+
+    def test_concat_gzip_files_not_gzipped(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with self.assertRaisesRegex(
+                FastqRewriteError, 'SRR123456.fastq are not gzip-compressed'
+        ):
+            _concat_gzip_files(
+                [self.get_data_path('SRR123456.fastq')], file_out
+            )
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    @parameterized.expand([
+        ('gzip', {}), ('threads', {'threads': 2}),
+        ('bgzf', {'output_format': 'bgzf'})
+    ])
+    @patch('q2_fondue.compression.BGZF_BLOCK_SIZE', 1000)
+    def test_verify_gzip(self, name, kwargs):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        _rewrite_fastq(self.get_data_path('SRR123456.fastq'), file_out,
+                       compression_block_size=1000, **kwargs)
+        # zero padding after the last member is ignored
+        with open(file_out, 'ab') as f_out:
+            f_out.write(b'\x00' * 4)
+
+        self.assertTrue(verify_gzip(file_out))
+
+    @patch('q2_fondue.compression.BGZF_BLOCK_SIZE', 1000)
+    def test_verify_gzip_stream(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        _rewrite_fastq(self.get_data_path('SRR123456.fastq'), file_out,
+                       output_format='bgzf')
+
+        class _Unseekable(io.RawIOBase):
+            def __init__(self, data):
+                self.f_in = io.BytesIO(data)
+
+            def readable(self):
+                return True
+
+            def readinto(self, buffer):
+                # hand out the data in small pieces, as a pipe would
+                data = self.f_in.read(min(len(buffer), 7))
+                buffer[:len(data)] = data
+                return len(data)
+
+        with open(file_out, 'rb') as f_out:
+            data = f_out.read()
+        self.assertTrue(verify_gzip(_Unseekable(data), raise_error=True))
+        with self.assertRaisesRegex(CorruptGzipError, 'gzip stream'):
+            verify_gzip(_Unseekable(data[:-40]), raise_error=True)
+
+    @parameterized.expand([
+        ('crc', -8, 'CRC32 check failed'),
+        ('isize', -4, 'ISIZE check failed'),
+        ('header', 0, 'invalid gzip header'),
+        ('deflate', 10, 'invalid code lengths set'),
+    ])
+    def test_verify_gzip_corrupt(self, name, position, msg):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        with open(file_out, 'wb') as f_out:
+            f_out.write(gzip.compress(b'@r1\nACGT\n+\nIIII\n'))
+            data = bytearray(gzip.compress(b'@r2\nACGT\n+\nIIII\n' * 10))
+            data[position] ^= 0xff
+            member_offset = f_out.tell()
+            f_out.write(data)
+
+        self.assertFalse(verify_gzip(file_out))
+        with self.assertRaisesRegex(CorruptGzipError, msg) as cm:
+            verify_gzip(file_out, raise_error=True)
+        self.assertEqual(cm.exception.member, 2)
+        self.assertEqual(cm.exception.offset, member_offset)
+
+    @parameterized.expand([
+        ('truncated', lambda x: x[:-12], 'unexpected end of file'),
+        ('trailer', lambda x: x[:-4], 'trailer is missing'),
+        ('empty', lambda x: b'', 'file is empty'),
+    ])
+    def test_verify_gzip_incomplete(self, name, cut, msg):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        with open(file_out, 'wb') as f_out:
+            f_out.write(cut(gzip.compress(b'@r1\nACGT\n+\nIIII\n' * 10)))
+
+        with self.assertRaisesRegex(CorruptGzipError, msg):
+            verify_gzip(file_out, raise_error=True)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,832 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import errno
+import hashlib
+import ftplib
+import io
+import json
+import os
+import struct
+import subprocess
+import urllib.error
+import urllib.request
+from unittest.mock import MagicMock, patch
+
+from parameterized import parameterized
+
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.downloads import (
+    _locate_sra, _download_file, _download_ftp, _prefetch, _locate_ena_fastq,
+    _fetch_from_ena, RemoteFile, RetryPolicy, _Throttle, _proxies, _urlopen,
+    estimate_required_space, SpaceEstimate, _dump_and_compress
+)
+from q2_fondue.compression import BGZF_EOF, _compress_bgzf_block
+from q2_fondue.fastq import FastqReader
+from q2_fondue.tests.test_bam import BamTestCase
+from q2_fondue.utils import DownloadError, InsufficientSpaceError, TEMP_FILES
+
+
+class FakeResponse(io.BytesIO):
+    def __init__(self, content: bytes, status: int = 200, fail_after=None):
+        super().__init__(content)
+        self.status = status
+        self.fail_after = fail_after
+
+    def read(self, size=-1):
+        if self.fail_after is not None and self.tell() >= self.fail_after:
+            raise ConnectionResetError('connection reset by peer')
+        if self.fail_after is not None:
+            size = min(size, self.fail_after - self.tell())
+        return super().read(size)
+
+
+class TestDownloads(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def setUp(self):
+        super().setUp()
+        self.content = bytes(range(256)) * 40
+        self.requests = []
+
+    def _serve(self, responses):
+        """Patches _urlopen to return the given responses in order, which
+            are either FakeResponses or callables of the Range offset."""
+        responses = list(responses)
+
+        def _urlopen(request, proxy=None):
+            self.requests.append(request)
+            response = responses.pop(0)
+            if isinstance(response, Exception):
+                raise response
+            if callable(response):
+                header = request.get_header('Range') or 'bytes=0-'
+                return response(int(header[6:-1]))
+            return response
+        return patch('q2_fondue.downloads._urlopen', _urlopen)
+
+    @parameterized.expand([
+        ('scheme', {'HTTPS_PROXY': 'http://proxy:3128'},
+         {'https': 'http://proxy:3128'}),
+        ('all', {'ALL_PROXY': 'http://all:3128',
+                 'HTTP_PROXY': 'http://proxy:3128'},
+         {'http': 'http://proxy:3128', 'https': 'http://all:3128',
+          'ftp': 'http://all:3128'}),
+        ('socks', {'ALL_PROXY': 'socks5://all:1080',
+                   'HTTPS_PROXY': 'http://proxy:3128'},
+         {'https': 'http://proxy:3128'}),
+    ])
+    def test_proxies_environment(self, name, env, exp_proxies):
+        with patch.dict(os.environ, env, clear=True):
+            self.assertDictEqual(_proxies(), exp_proxies)
+
+    def test_proxies_explicit(self):
+        with patch.dict(os.environ, {'HTTPS_PROXY': 'http://env:3128'}):
+            obs = _proxies('http://proxy:8080')
+        self.assertDictEqual(obs, {
+            'http': 'http://proxy:8080', 'https': 'http://proxy:8080',
+            'ftp': 'http://proxy:8080'
+        })
+
+    def test_proxies_explicit_socks(self):
+        with self.assertRaisesRegex(ValueError, 'SOCKS proxies'):
+            _proxies('socks5://proxy:1080')
+
+    @patch('urllib.request.build_opener')
+    def test_urlopen_proxy(self, mock_opener):
+        request = urllib.request.Request('https://x/SRR123')
+        _urlopen(request, 'http://proxy:8080')
+
+        handler, = mock_opener.call_args.args
+        self.assertEqual(handler.proxies['https'], 'http://proxy:8080')
+        mock_opener.return_value.open.assert_called_once_with(
+            request, timeout=60
+        )
+
+    def _serve_ftp(self, files, fail_after=None):
+        """Patches ftplib.FTP to serve the given files by path - the first
+            transfer of every file is cut short after fail_after bytes."""
+        test, failed = self, set()
+
+        class FakeFTP:
+            def __init__(self, timeout=None):
+                pass
+
+            def __enter__(self):
+                return self
+
+            def __exit__(self, *args):
+                pass
+
+            def connect(self, host, port):
+                self.host = host
+
+            def login(self):
+                pass
+
+            def retrbinary(self, cmd, callback, blocksize, rest=None):
+                path = cmd[5:]
+                test.ftp_requests.append((self.host, path, rest))
+                if path not in files:
+                    raise ftplib.error_perm('550 No such file')
+                content = files[path][rest or 0:]
+                if fail_after is not None and path not in failed:
+                    failed.add(path)
+                    callback(content[:fail_after])
+                    raise ConnectionResetError('connection reset by peer')
+                callback(content)
+
+        self.ftp_requests = []
+        return patch('ftplib.FTP', FakeFTP)
+
+    def _locator_response(self, *files, status=200):
+        return FakeResponse(json.dumps({
+            'version': '2',
+            'result': [{
+                'bundle': 'SRR123', 'status': status, 'msg': 'not found',
+                'files': list(files)
+            }]
+        }).encode())
+
+    def test_locate_sra(self):
+        response = self._locator_response(
+            {'type': 'sra', 'size': 10, 'md5': 'abc',
+             'locations': [{'service': 'ncbi', 'link': 'https://x/SRR123'}]},
+            {'type': 'vdbcache', 'size': 5,
+             'locations': [{'service': 'ncbi', 'link': 'https://x/cache'}]},
+            {'type': 'fastq', 'locations': [{'link': 'https://x/fq'}]},
+        )
+        with self._serve([response]):
+            obs = _locate_sra('SRR123')
+
+        self.assertListEqual(obs, [
+            RemoteFile('SRR123.sra', 'https://x/SRR123', 10, 'abc'),
+            RemoteFile('SRR123.sra.vdbcache', 'https://x/cache', 5, None),
+        ])
+        self.assertIn(b'acc=SRR123', self.requests[0].data)
+
+    @parameterized.expand([
+        ('region', 's3.us-east-1', 'https://s3/east'),
+        ('service', 'gs', 'https://gs/central'),
+        ('unavailable', 'gs.us-west1', 'https://ncbi/SRR123'),
+        ('default', None, 'https://ncbi/SRR123'),
+    ])
+    def test_locate_sra_location(self, name, location, exp_link):
+        response = self._locator_response({
+            'type': 'sra', 'locations': [
+                {'service': 'ncbi', 'region': 'be-md',
+                 'link': 'https://ncbi/SRR123'},
+                {'service': 's3', 'region': 'us-west-2',
+                 'link': 'https://s3/west'},
+                {'service': 's3', 'region': 'us-east-1',
+                 'link': 'https://s3/east'},
+                {'service': 'gs', 'region': 'us-central1',
+                 'link': 'https://gs/central'},
+            ]
+        })
+
+        with self._serve([response]):
+            obs = _locate_sra('SRR123', location)
+
+        self.assertEqual(obs[0].url, exp_link)
+        self.assertEqual(
+            b'location=' in self.requests[0].data, location is not None
+        )
+
+    @parameterized.expand([
+        ('default', None, 'https://ncbi/SRR123'),
+        ('paid', 's3.us-east-1', 'https://ncbi/SRR123'),
+        ('free', 'gs', 'https://gs/central'),
+    ])
+    def test_locate_sra_location_access(self, name, location, exp_link):
+        response = self._locator_response({
+            'type': 'sra', 'locations': [
+                {'service': 's3', 'region': 'us-east-1', 'payRequired': True,
+                 'link': 'https://s3/east'},
+                {'service': 'gs', 'region': 'us-central1',
+                 'ceRequired': 'false', 'link': 'https://gs/central'},
+                {'service': 'ncbi', 'region': 'be-md',
+                 'link': 'https://ncbi/SRR123'},
+            ]
+        })
+
+        with self._serve([response]):
+            obs = _locate_sra('SRR123', location)
+
+        self.assertEqual(obs[0].url, exp_link)
+
+    def test_locate_sra_no_accessible_location(self):
+        response = self._locator_response({
+            'type': 'sra', 'locations': [
+                {'service': 's3', 'payRequired': True, 'link': 'https://s3'},
+                {'service': 'gs', 'ceRequired': True, 'link': 'https://gs'},
+            ]
+        })
+
+        with self._serve([response]):
+            with self.assertRaisesRegex(DownloadError, 'require payment'):
+                _locate_sra('SRR123')
+
+    def test_locate_sra_not_found(self):
+        with self._serve([self._locator_response(status=404)]):
+            with self.assertRaisesRegex(DownloadError, 'located: not found'):
+                _locate_sra('SRR123')
+
+    def test_locate_sra_no_objects(self):
+        with self._serve([self._locator_response()]):
+            with self.assertRaisesRegex(DownloadError, 'No SRA objects'):
+                _locate_sra('SRR123')
+
+    def test_retry_policy_delay(self):
+        retry = RetryPolicy(base_delay=1, max_delay=3, jitter=0)
+        self.assertListEqual(
+            [retry.delay(x) for x in range(4)], [1, 2, 3, 3]
+        )
+
+    @patch('random.random', return_value=0.5)
+    def test_retry_policy_jitter(self, mock_random):
+        retry = RetryPolicy(base_delay=4, jitter=0.5)
+        self.assertEqual(retry.delay(1), 6)
+
+    @parameterized.expand([
+        ('attempts', {'max_attempts': 0}, 'attempts must be at least 1'),
+        ('jitter', {'jitter': 2}, 'between 0 and 1'),
+    ])
+    def test_retry_policy_invalid(self, name, params, exp_error):
+        with self.assertRaisesRegex(ValueError, exp_error):
+            RetryPolicy(**params)
+
+    @parameterized.expand([
+        ('http_retryable', urllib.error.HTTPError(
+            'https://x', 503, 'Unavailable', {}, None), True),
+        ('http_final', urllib.error.HTTPError(
+            'https://x', 404, 'Not Found', {}, None), False),
+        ('url', urllib.error.URLError('timed out'), True),
+        ('reset', ConnectionResetError('connection reset by peer'), True),
+        ('timeout', TimeoutError('timed out'), True),
+        ('ftp_temp', ftplib.error_temp('421 too many users'), True),
+        ('no_space', OSError(errno.ENOSPC, 'No space left on device'),
+         False),
+        ('permissions', PermissionError(errno.EACCES, 'Permission denied'),
+         False),
+    ])
+    def test_retry_policy_should_retry(self, name, error, exp_retry):
+        self.assertEqual(RetryPolicy().should_retry(error), exp_retry)
+
+    @patch('time.sleep')
+    def test_locate_sra_retry(self, mock_sleep):
+        response = self._locator_response(
+            {'type': 'sra', 'locations': [{'link': 'https://x/SRR123'}]}
+        )
+        with self._serve([
+            urllib.error.HTTPError('https://x', 503, 'Unavailable', {}, None),
+            urllib.error.URLError('timed out'),
+            response
+        ]):
+            obs = _locate_sra('SRR123', retry=RetryPolicy(jitter=0))
+
+        self.assertEqual(obs[0].url, 'https://x/SRR123')
+        self.assertListEqual(
+            [x.args[0] for x in mock_sleep.call_args_list], [5, 10]
+        )
+
+    @patch('time.sleep')
+    def test_locate_sra_retry_exhausted(self, mock_sleep):
+        with self._serve([urllib.error.URLError('timed out')] * 2):
+            with self.assertRaisesRegex(DownloadError, 'timed out') as cm:
+                _locate_sra('SRR123', retry=RetryPolicy(2))
+        self.assertEqual(mock_sleep.call_count, 1)
+        self.assertTrue(cm.exception.retryable)
+
+    @patch('time.sleep')
+    def test_download_file_no_retry(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([
+            urllib.error.HTTPError('https://x', 403, 'Forbidden', {}, None)
+        ]):
+            with self.assertRaisesRegex(
+                    DownloadError, 'downloaded: HTTP Error 403'
+            ) as cm:
+                _download_file('https://x/SRR123', file_out)
+        mock_sleep.assert_not_called()
+        self.assertFalse(cm.exception.retryable)
+
+    def test_download_file(self):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([FakeResponse(self.content)]):
+            _download_file('https://x/SRR123', file_out, len(self.content))
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertFalse(os.path.exists(f'{file_out}.part'))
+        self.assertIsNone(self.requests[0].get_header('Range'))
+
+    @patch('time.sleep')
+    def test_download_file_resume(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([
+            FakeResponse(self.content, fail_after=3000),
+            urllib.error.URLError('timed out'),
+            lambda offset: FakeResponse(self.content[offset:], 206),
+        ]):
+            _download_file(
+                'https://x/SRR123', file_out, len(self.content),
+                RetryPolicy(3)
+            )
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertListEqual(
+            [x.get_header('Range') for x in self.requests],
+            [None, 'bytes=3000-', 'bytes=3000-']
+        )
+        self.assertEqual(mock_sleep.call_count, 2)
+
+    @patch('time.sleep')
+    def test_download_file_resume_unsupported(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+        with open(f'{file_out}.part', 'wb') as fout:
+            fout.write(b'stale')
+
+        # the server ignores the Range header and sends the whole file
+        with self._serve([FakeResponse(self.content, 200)]):
+            _download_file('https://x/SRR123', file_out)
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertEqual(self.requests[0].get_header('Range'), 'bytes=5-')
+
+    @patch('time.sleep')
+    def test_download_file_failed(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([
+            FakeResponse(self.content, fail_after=100),
+            FakeResponse(self.content[100:], 206, fail_after=100),
+        ]):
+            with self.assertRaisesRegex(
+                    DownloadError, 'after 2 attempts: connection reset'
+            ) as cm:
+                _download_file(
+                    'https://x/SRR123', file_out, len(self.content),
+                    RetryPolicy(2)
+                )
+
+        # the partial download is kept so that it can be resumed later -
+        # until the process exits
+        self.assertFalse(os.path.exists(file_out))
+        self.assertEqual(os.path.getsize(f'{file_out}.part'), 200)
+        self.assertIn(f'{file_out}.part', TEMP_FILES.paths)
+        self.assertTrue(cm.exception.retryable)
+
+    @patch('time.sleep')
+    @patch('time.monotonic', return_value=100)
+    def test_throttle(self, mock_time, mock_sleep):
+        throttle = _Throttle(100)
+        throttle.consume(50)
+        throttle.consume(50)
+        mock_time.return_value = 102
+        throttle.consume(50)
+
+        # the transfer is behind the limit after 2 s - no need to wait
+        self.assertListEqual(
+            [x.args[0] for x in mock_sleep.call_args_list], [0.5, 1.0]
+        )
+
+    def test_throttle_invalid(self):
+        with self.assertRaisesRegex(ValueError, 'must be positive'):
+            _Throttle(0)
+
+    @patch('time.sleep')
+    def test_download_file_max_bandwidth(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([FakeResponse(self.content)]):
+            _download_file(
+                'https://x/SRR123', file_out, len(self.content),
+                max_bandwidth=1024
+            )
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        mock_sleep.assert_called_once()
+        self.assertAlmostEqual(mock_sleep.call_args.args[0], 10, places=1)
+
+    def _serve_ranges(self, fail=()):
+        """Patches _urlopen to serve the requested ranges of the content -
+            the first request for every range in fail is cut short."""
+        failed = set()
+
+        def _urlopen(request, proxy=None):
+            self.requests.append(request)
+            header = request.get_header('Range')
+            start, end = header[6:].split('-')
+            start, end = int(start), int(end) if end else len(self.content)
+            data = self.content[start:end + 1]
+            if header in fail and header not in failed:
+                failed.add(header)
+                return FakeResponse(data, 206, fail_after=len(data) // 2)
+            return FakeResponse(data, 206)
+        return patch('q2_fondue.downloads._urlopen', _urlopen)
+
+    @patch('time.sleep')
+    def test_download_file_segments(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve_ranges(fail=['bytes=3413-6825']):
+            _download_file(
+                'https://x/SRR123', file_out, len(self.content), segments=3
+            )
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertListEqual(
+            sorted(x.get_header('Range') for x in self.requests),
+            ['bytes=0-3412', 'bytes=3413-6825', 'bytes=5119-6825',
+             'bytes=6826-10239']
+        )
+        self.assertListEqual(os.listdir(self.temp_dir.name), ['SRR123.sra'])
+        self.assertFalse(
+            [x for x in TEMP_FILES.paths if x.startswith(file_out)]
+        )
+
+    def test_download_file_segments_resume(self):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+        with open(f'{file_out}.part.1', 'wb') as fout:
+            fout.write(self.content[5120:5130])
+
+        with self._serve_ranges():
+            _download_file(
+                'https://x/SRR123', file_out, len(self.content), segments=2
+            )
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertListEqual(
+            sorted(x.get_header('Range') for x in self.requests),
+            ['bytes=0-5119', 'bytes=5130-10239']
+        )
+
+    def test_download_file_segments_unsupported(self):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.sra')
+
+        with self._serve([FakeResponse(self.content)] * 2):
+            with self.assertRaisesRegex(DownloadError, 'Range requests'):
+                _download_file(
+                    'https://x/SRR123', file_out, len(self.content),
+                    segments=2
+                )
+
+    def test_download_file_segments_invalid(self):
+        with self.assertRaisesRegex(ValueError, r'at least 1 \(got 0\)'):
+            _download_file('https://x/SRR123', 'out', 10, segments=0)
+
+    def test_prefetch(self):
+        md5 = hashlib.md5(self.content).hexdigest()
+        locator = self._locator_response({
+            'type': 'sra', 'size': len(self.content), 'md5': md5,
+            'locations': [{'link': 'https://x/SRR123'}]
+        })
+
+        with self._serve([locator, FakeResponse(self.content)]):
+            obs = _prefetch('SRR123', self.temp_dir.name)
+
+        exp = os.path.join(self.temp_dir.name, 'SRR123', 'SRR123.sra')
+        self.assertListEqual(obs, [exp])
+        with open(exp, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+
+    def test_prefetch_checksum_mismatch(self):
+        locator = self._locator_response({
+            'type': 'sra', 'md5': '0' * 32,
+            'locations': [{'link': 'https://x/SRR123'}]
+        })
+
+        with self._serve([locator, FakeResponse(self.content)]):
+            with self.assertRaisesRegex(DownloadError, 'does not match'):
+                _prefetch('SRR123', self.temp_dir.name)
+
+        self.assertListEqual(
+            os.listdir(os.path.join(self.temp_dir.name, 'SRR123')), []
+        )
+
+    def _ena_response(self, names, contents, kind='fastq'):
+        return FakeResponse(json.dumps([{
+            'run_accession': 'SRR123',
+            f'{kind}_ftp': ';'.join(
+                f'ftp.sra.ebi.ac.uk/vol1/{kind}/SRR123/{x}' for x in names
+            ),
+            f'{kind}_md5': ';'.join(
+                hashlib.md5(x).hexdigest() for x in contents
+            ),
+            f'{kind}_bytes': ';'.join(str(len(x)) for x in contents),
+        }]).encode())
+
+    @staticmethod
+    def _bam(*records):
+        text = b'@HD\tVN:1.6\tSO:queryname\n'
+        data = b'BAM\x01' + struct.pack('<i', len(text)) + text + \
+            struct.pack('<i', 0) + b''.join(records)
+        return _compress_bgzf_block(data, 6) + BGZF_EOF
+
+    def test_locate_ena_fastq(self):
+        response = self._ena_response(
+            ['SRR123_1.fastq.gz', 'SRR123_2.fastq.gz'], [b'a', b'bc']
+        )
+
+        with self._serve([response]):
+            obs = _locate_ena_fastq('SRR123')
+
+        self.assertListEqual(obs, [
+            RemoteFile(
+                'SRR123_1.fastq.gz', 'https://ftp.sra.ebi.ac.uk/vol1/fastq/'
+                'SRR123/SRR123_1.fastq.gz', 1, hashlib.md5(b'a').hexdigest()
+            ),
+            RemoteFile(
+                'SRR123_2.fastq.gz', 'https://ftp.sra.ebi.ac.uk/vol1/fastq/'
+                'SRR123/SRR123_2.fastq.gz', 2, hashlib.md5(b'bc').hexdigest()
+            ),
+        ])
+        self.assertIn(b'result=read_run', self.requests[0].data)
+
+    @parameterized.expand([
+        ('empty', FakeResponse(b'[{"run_accession": "SRR123", '
+                               b'"fastq_ftp": ""}]')),
+        ('unknown', urllib.error.HTTPError(
+            'https://x', 404, 'Not Found', {}, None
+        )),
+    ])
+    def test_locate_ena_fastq_missing(self, name, response):
+        with self._serve([response]):
+            self.assertListEqual(_locate_ena_fastq('SRR123'), [])
+
+    @parameterized.expand([
+        ('single', ['SRR123.fastq.gz'], ['SRR123_01_L001_R1_001.fastq.gz']),
+        ('paired', ['SRR123.fastq.gz', 'SRR123_1.fastq.gz',
+                    'SRR123_2.fastq.gz'],
+         ['SRR123_00_L001_R1_001.fastq.gz',
+          'SRR123_00_L001_R2_001.fastq.gz']),
+    ])
+    def test_fetch_from_ena(self, name, remote_names, exp_names):
+        contents = [x.encode() for x in remote_names]
+        # unpaired reads of paired-end runs are not downloaded
+        downloads = [FakeResponse(x) for x in contents[-len(exp_names):]]
+
+        with self._serve([
+            self._ena_response(remote_names, contents), *downloads
+        ]):
+            obs = _fetch_from_ena('SRR123', self.temp_dir.name)
+
+        self.assertListEqual(
+            obs, [os.path.join(self.temp_dir.name, x) for x in exp_names]
+        )
+        self.assertListEqual(sorted(os.listdir(self.temp_dir.name)), exp_names)
+        with open(obs[-1], 'rb') as fout:
+            self.assertEqual(fout.read(), contents[-1])
+
+    def test_fetch_from_ena_not_mirrored(self):
+        with self._serve([FakeResponse(b'[]')]):
+            self.assertListEqual(_fetch_from_ena('SRR123', 'out'), [])
+
+    def test_fetch_from_ena_checksum_mismatch(self):
+        names = ['SRR123_1.fastq.gz', 'SRR123_2.fastq.gz']
+
+        with self._serve([
+            self._ena_response(names, [b'a', b'b']),
+            FakeResponse(b'a'), FakeResponse(b'c'),
+        ]), self._serve_ftp({'/vol1/fastq/SRR123/SRR123_2.fastq.gz': b'c'}):
+            with self.assertRaisesRegex(DownloadError, 'does not match'):
+                _fetch_from_ena('SRR123', self.temp_dir.name)
+
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    @patch('time.sleep')
+    def test_fetch_from_ena_ftp_fallback(self, mock_sleep):
+        names = ['SRR123_1.fastq.gz', 'SRR123_2.fastq.gz']
+        path = '/vol1/fastq/SRR123/SRR123_2.fastq.gz'
+        error = urllib.error.HTTPError('https://x', 503, 'Busy', {}, None)
+
+        with self._serve([
+            self._ena_response(names, [b'a', self.content]),
+            FakeResponse(b'a'),
+            FakeResponse(self.content, fail_after=1000), error, error
+        ]), self._serve_ftp({path: self.content}):
+            obs = _fetch_from_ena('SRR123', self.temp_dir.name)
+
+        with open(obs[1], 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        # the FTP download continues from the data received over HTTPS
+        self.assertListEqual(
+            self.ftp_requests, [('ftp.sra.ebi.ac.uk', path, 1000)]
+        )
+
+    def test_locate_ena_fastq_submitted_bam(self):
+        response = self._ena_response(
+            ['SRR123.bam', 'SRR123.bam.bai'], [b'bam', b'bai'], 'submitted'
+        )
+
+        with self._serve([response]):
+            obs = _locate_ena_fastq('SRR123')
+
+        self.assertListEqual(obs, [RemoteFile(
+            'SRR123.bam', 'https://ftp.sra.ebi.ac.uk/vol1/submitted/'
+            'SRR123/SRR123.bam', 3, hashlib.md5(b'bam').hexdigest()
+        )])
+
+    @parameterized.expand([
+        ('paired', [('p1', 0x1 | 0x40, 'ACGT'), ('s1', 0, 'GG'),
+                    ('p1', 0x1 | 0x80, 'TT')],
+         ['SRR123_00_L001_R1_001.fastq.gz',
+          'SRR123_00_L001_R2_001.fastq.gz'], ['TT']),
+        ('single', [('s1', 0, 'GG'), ('s2', 0, 'CC')],
+         ['SRR123_01_L001_R1_001.fastq.gz'], ['GG', 'CC']),
+    ])
+    def test_fetch_from_ena_bam(self, name, records, exp_names, exp_seqs):
+        bam = self._bam(*(BamTestCase._record(*x) for x in records))
+
+        with self._serve([
+            self._ena_response(['SRR123.bam'], [bam], 'submitted'),
+            FakeResponse(bam)
+        ]):
+            obs = _fetch_from_ena('SRR123', self.temp_dir.name)
+
+        self.assertListEqual(
+            obs, [os.path.join(self.temp_dir.name, x) for x in exp_names]
+        )
+        # the BAM file is removed once converted
+        self.assertListEqual(sorted(os.listdir(self.temp_dir.name)), exp_names)
+        with FastqReader(obs[-1]) as reader:
+            self.assertListEqual([x[1] for x in reader], exp_seqs)
+
+    @parameterized.expand([
+        ('corrupt', b'not a BAM file', 'could not be converted'),
+        ('empty', None, 'does not contain any reads'),
+    ])
+    def test_fetch_from_ena_bam_error(self, name, bam, exp_error):
+        bam = bam or self._bam()
+
+        with self._serve([
+            self._ena_response(['SRR123.bam'], [bam], 'submitted'),
+            FakeResponse(bam)
+        ]):
+            with self.assertRaisesRegex(DownloadError, exp_error):
+                _fetch_from_ena('SRR123', self.temp_dir.name)
+
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    @patch('time.sleep')
+    def test_download_ftp_resume(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.fastq.gz')
+
+        with self._serve_ftp({'/SRR123.fastq.gz': self.content}, 3000):
+            _download_ftp(
+                'ftp://x/SRR123.fastq.gz', file_out, len(self.content)
+            )
+
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), self.content)
+        self.assertListEqual(
+            [x[2] for x in self.ftp_requests], [None, 3000]
+        )
+        self.assertEqual(mock_sleep.call_count, 1)
+
+    @patch('time.sleep')
+    def test_download_ftp_missing(self, mock_sleep):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123.fastq.gz')
+
+        with self._serve_ftp({}):
+            with self.assertRaisesRegex(DownloadError, '550 No such file'):
+                _download_ftp('ftp://x/SRR123.fastq.gz', file_out)
+        mock_sleep.assert_not_called()
+
+    def test_estimate_required_space_sizes(self):
+        obs = estimate_required_space({'SRR123': 100, 'SRR124': 300})
+        self.assertEqual(obs, SpaceEstimate(400, 3300, 500))
+        self.assertEqual(obs.total, 3800)
+
+    def test_estimate_required_space_empty(self):
+        self.assertEqual(
+            estimate_required_space([]), SpaceEstimate(0, 0, 0)
+        )
+
+    def test_estimate_required_space_accessions(self):
+        locator = self._locator_response(
+            {'type': 'sra', 'size': 100,
+             'locations': [{'link': 'https://x/SRR123'}]},
+            {'type': 'vdbcache',
+             'locations': [{'link': 'https://x/cache'}]},
+        )
+        head = FakeResponse(b'')
+        head.headers = {'Content-Length': '20'}
+
+        with self._serve([locator, head]):
+            obs = estimate_required_space(['SRR123'])
+
+        self.assertEqual(obs.sra, 120)
+        self.assertEqual(self.requests[1].get_method(), 'HEAD')
+        self.assertEqual(self.requests[1].full_url, 'https://x/cache')
+
+    def test_estimate_required_space_unknown_size(self):
+        head = FakeResponse(b'')
+        head.headers = {}
+
+        with self._serve([self._locator_response(
+            {'type': 'sra', 'locations': [{'link': 'https://x/SRR123'}]}
+        ), head]):
+            with self.assertRaisesRegex(DownloadError, 'size of .* known'):
+                estimate_required_space(['SRR123'])
+
+
+class TestDumpAndCompress(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def _fake_fasterq_dump(self, content, returncode=0):
+        file_in = os.path.join(self.temp_dir.name, 'dump.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write(content)
+        popen = subprocess.Popen
+        self.fasterq_cmds, self.fasterq_kwargs = [], []
+
+        def _popen(cmd, **kwargs):
+            self.fasterq_cmds.append(cmd)
+            self.fasterq_kwargs.append(kwargs)
+            return popen(
+                ['sh', '-c', 'cat "$1"; echo "$2" >&2; exit "$3"', 'sh',
+                 file_in, 'some error', str(returncode)], **kwargs
+            )
+        return patch('subprocess.Popen', _popen)
+
+    @parameterized.expand([
+        ('single', '@SRR1.1 1\nACGT\n+\nIIII\n@SRR1.2 2\nAC\n+\nII\n',
+         {'SRR1.fastq.gz': 2}),
+        ('paired', '@SRR1.1 1\nACGT\n+\nIIII\n@SRR1.1 1\nTT\n+\nII\n'
+                   '@SRR1.2 2\nAC\n+\nII\n@SRR1.2 2\nGG\n+\nII\n',
+         {'SRR1_1.fastq.gz': 2, 'SRR1_2.fastq.gz': 2}),
+        ('hybrid', '@SRR1.1 1\nACGT\n+\nIIII\n@SRR1.1 1\nTT\n+\nII\n'
+                   '@SRR1.2 2\nAC\n+\nII\n',
+         {'SRR1_1.fastq.gz': 1, 'SRR1_2.fastq.gz': 1, 'SRR1.fastq.gz': 1}),
+    ])
+    def test_dump_and_compress(self, name, content, exp_reads):
+        out_dir = os.path.join(self.temp_dir.name, 'out')
+        os.mkdir(out_dir)
+
+        with self._fake_fasterq_dump(content):
+            obs = _dump_and_compress('SRR1', 'SRR1/SRR1.sra', out_dir, 4)
+
+        self.assertListEqual(self.fasterq_cmds, [[
+            'fasterq-dump', '-e', '4', '--size-check', 'on', '--stdout',
+            '--split-spot', 'SRR1/SRR1.sra'
+        ]])
+        self.assertEqual(self.fasterq_kwargs[0]['cwd'], out_dir)
+        self.assertDictEqual(
+            {os.path.basename(path): x.reads for path, x in obs.items()},
+            exp_reads
+        )
+        self.assertListEqual(sorted(os.listdir(out_dir)), sorted(exp_reads))
+        with FastqReader(os.path.join(out_dir, sorted(exp_reads)[-1])) as r:
+            self.assertEqual(next(r)[0], 'SRR1.1 1')
+
+    def test_dump_and_compress_error(self):
+        out_dir = os.path.join(self.temp_dir.name, 'out')
+        os.mkdir(out_dir)
+
+        with self._fake_fasterq_dump('@SRR1.1\nA\n+\nI\n', 3):
+            with self.assertRaisesRegex(
+                    DownloadError, r'SRR1 \(exit code 3\): some error'
+            ):
+                _dump_and_compress('SRR1', 'SRR1', out_dir, key_file='k.ngc')
+
+        self.assertIn('--ngc', self.fasterq_cmds[0])
+        self.assertListEqual(os.listdir(out_dir), [])
+
+    def test_dump_and_compress_prefetched_path(self):
+        out_dir = os.path.join(self.temp_dir.name, 'out')
+        os.makedirs(os.path.join(out_dir, 'SRR1'))
+        sra_path = os.path.relpath(os.path.join(out_dir, 'SRR1'))
+
+        with self._fake_fasterq_dump('@SRR1.1\nA\n+\nI\n'):
+            _dump_and_compress('SRR1', sra_path, out_dir)
+
+        self.assertEqual(self.fasterq_cmds[0][-1], os.path.abspath(sra_path))
+        self.assertEqual(self.fasterq_kwargs[0]['cwd'], out_dir)
+
+    @patch('shutil.disk_usage')
+    def test_dump_and_compress_low_space(self, mock_usage):
+        mock_usage.return_value = MagicMock(free=100)
+        out_dir = os.path.join(self.temp_dir.name, 'out')
+        os.mkdir(out_dir)
+
+        with self._fake_fasterq_dump('@SRR1.1\nA\n+\nI\n'):
+            with self.assertRaises(InsufficientSpaceError):
+                _dump_and_compress(
+                    'SRR1', 'SRR1', out_dir, min_free_space=500
+                )
+        self.assertListEqual(os.listdir(out_dir), [])
@@ -0,0 +1,745 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import gzip
+import os
+import unittest
+import zlib
+
+from parameterized import parameterized
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.fastq import (
+    FastqGzWriter, FastqReader, PairRepairStats, _check_pairing,
+    _check_spot_counts, _concat_runs, _count_reads, _deinterleave,
+    _demultiplex, _detect_quality_encoding, _fastq_stats, _format_record,
+    _interleave, _repair_pairs, _split_fastq
+)
+from q2_fondue.rewrite import _rewrite_fastq
+from q2_fondue.utils import (
+    FastqRewriteError, FastqStats, FastqValidationError, PairingError,
+    TruncatedFastqError
+)
+
+
+class TestFastqUtils(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    @parameterized.expand([
+        ('phred33', '!+I#', '@IIJ'),
+        ('phred33', 'IIHG', 'JJ@I'),
+        ('phred64', '@Jh^', 'hhhh'),
+        ('solexa', ';@Jh', 'hhhh'),
+        ('unknown', '', ''),
+        ('unknown', 'II I', 'IIII'),
+        ('uniform', '????', '????'),
+    ])
+    def test_detect_quality_encoding(self, exp_encoding, qual1, qual2):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write(f'@r1\nACGT\n+\n{qual1}\n@r2\nACGT\n+\n{qual2}\n')
+
+        self.assertEqual(_detect_quality_encoding(file_in), exp_encoding)
+
+    def test_detect_quality_encoding_sample(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nhhJh\n@r2\nACGT\n+\n####\n')
+
+        obs = _detect_quality_encoding(file_in, n_records=1)
+
+        self.assertEqual(obs, 'phred64')
+
+    def test_detect_quality_encoding_real_data(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        self.assertEqual(_detect_quality_encoding(file_in), 'phred33')
+
+    def test_concat_runs(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000001.fastq.gz')
+        with FastqGzWriter(file_in) as writer:
+            writer.write_record('r1', 'ACGT', 'II#I')
+        file_in_plain = os.path.join(self.temp_dir.name, 'SRR000002_1.fastq')
+        with open(file_in_plain, 'w') as fin:
+            fin.write('@r1\nAC\n+\nI#')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        obs = _concat_runs([file_in, file_in_plain], file_out)
+
+        self.assertEqual(obs.reads, 2)
+        self.assertEqual(obs.bases, 6)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(), '@r1\nACGT\n+\nII#I\n@r1\nAC\n+\nI#\n'
+            )
+
+    def test_concat_runs_prefix_ids(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        obs = _concat_runs(
+            [self.get_data_path('SRR123457_1.fastq'),
+             self.get_data_path('SRR123456.fastq')],
+            file_out, prefix_ids=True, threads=2
+        )
+
+        self.assertEqual(obs.reads, 6)
+        with FastqReader(file_out) as reader:
+            obs_ids = [read_id.split()[0] for read_id, _, _ in reader]
+        self.assertListEqual(obs_ids, [
+            'SRR123457:test_acc_single.1', 'SRR123457:test_acc_single.4',
+            'SRR123457:test_acc_single.5', 'SRR123456:test_acc_single.1',
+            'SRR123456:test_acc_single.4', 'SRR123456:test_acc_single.5'
+        ])
+
+    def test_concat_runs_existing_output(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        with open(file_out, 'w') as fout:
+            fout.write('existing')
+
+        with self.assertRaisesRegex(FileExistsError, 'already exists'):
+            _concat_runs([file_in], file_out)
+        with open(file_out) as fout:
+            self.assertEqual(fout.read(), 'existing')
+
+        obs = _concat_runs([file_in], file_out, overwrite=True)
+
+        self.assertEqual(obs.reads, 3)
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), fin.read())
+
+    def test_concat_runs_error(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with self.assertRaisesRegex(
+                FastqRewriteError, r'SRR000000.fastq could not be written'
+        ):
+            _concat_runs(
+                [self.get_data_path('SRR123456.fastq'),
+                 self.get_data_path('SRR000000.fastq')], file_out
+            )
+
+    @parameterized.expand([(1, [1, 1, 1]), (2, [2, 1]), (3, [3]), (5, [3])])
+    def test_split_fastq(self, reads_per_chunk, exp_reads):
+        file_in = self.get_data_path('SRR123456.fastq')
+        prefix = os.path.join(self.temp_dir.name, 'chunk')
+
+        obs = _split_fastq(file_in, prefix, reads_per_chunk)
+
+        self.assertListEqual(obs, [
+            f'{prefix}_{i:04d}.fastq.gz' for i in range(1, len(exp_reads) + 1)
+        ])
+        obs_records = []
+        for chunk, exp in zip(obs, exp_reads):
+            with FastqReader(chunk) as reader:
+                records = list(reader)
+            self.assertEqual(len(records), exp)
+            obs_records.extend(records)
+        with FastqReader(file_in) as reader:
+            self.assertListEqual(obs_records, list(reader))
+
+    def test_split_fastq_invalid(self):
+        with self.assertRaisesRegex(ValueError, 'at least 1'):
+            _split_fastq(
+                self.get_data_path('SRR123456.fastq'),
+                os.path.join(self.temp_dir.name, 'chunk'), 0
+            )
+
+    def test_split_fastq_truncated(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nA\n+\nI\n@r2\nA\n+\nI\n@r3\nA\n')
+
+        with self.assertRaises(TruncatedFastqError):
+            _split_fastq(file_in, os.path.join(self.temp_dir.name, 'ch'), 1)
+        self.assertListEqual(os.listdir(self.temp_dir.name), [
+            'SRR000000.fastq'
+        ])
+
+    @parameterized.expand([
+        (0, {'s1': ['r1'], 's2': ['r3']}, 4),
+        (1, {'s1': ['r1', 'r2'], 's2': ['r3']}, 3),
+    ])
+    def test_demultiplex(self, max_mismatches, exp_ids, exp_unassigned):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nAAAACGT\n+\n!!!!I#I\n@r2\nAATACGT\n+\n!!!!I#I\n'
+                      '@r3\nccccgt\n+\n!!!!I#\n@r4\nGGGGACGT\n+\nIIIIII#I\n'
+                      '@r5\nGCCCGT\n+\n!!!!I#\n@r6\nAA\n+\nII\n')
+
+        obs, obs_unassigned = _demultiplex(
+            file_in, {'AAAA': 's1', 'CCCC': 's2', 'ACCC': 's3'},
+            self.temp_dir.name, max_mismatches
+        )
+
+        self.assertEqual(obs_unassigned, exp_unassigned)
+        self.assertSetEqual(set(obs), {'s1', 's2', 's3'})
+        self.assertEqual(obs['s3'].reads, 0)
+        for sample, ids in exp_ids.items():
+            path = os.path.join(self.temp_dir.name, f'{sample}.fastq.gz')
+            with FastqReader(path) as reader:
+                records = list(reader)
+            self.assertListEqual([x[0] for x in records], ids)
+            self.assertTrue(all(x[2] in ('I#I', 'I#') for x in records))
+
+    def test_demultiplex_truncated(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nAAAACGT\n+\n!!!!I#I\n@r2\nCCCCGT\n')
+
+        with self.assertRaises(TruncatedFastqError):
+            _demultiplex(
+                file_in, {'AAAA': 's1', 'CCCC': 's2'}, self.temp_dir.name
+            )
+        self.assertListEqual(os.listdir(self.temp_dir.name), [
+            'SRR000000.fastq'
+        ])
+
+    def test_fastq_stats(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq.gz')
+        with FastqGzWriter(file_in) as writer:
+            writer.write_record('r1', 'ACGT', '!!!#')
+            writer.write_record('r2', 'ACGT', 'IIII')
+            writer.write_record('r3', 'AC', 'I!')
+
+        obs = _fastq_stats(file_in)
+
+        self.assertEqual((obs.reads, obs.bases), (3, 10))
+        self.assertDictEqual(dict(obs.lengths), {4: 2, 2: 1})
+        self.assertDictEqual(dict(obs.mean_qualities), {0: 1, 40: 1, 20: 1})
+        summary = obs.summary(histograms=True)
+        self.assertDictEqual(summary['length_histogram'], {'2': 1, '4': 2})
+        self.assertDictEqual(
+            summary['mean_quality_histogram'], {'0': 1, '20': 1, '40': 1}
+        )
+        self.assertNotIn('length_histogram', obs.summary())
+
+    def test_fastq_stats_gc_content(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nGGCC\n+\nIIII\n@r2\nAATT\n+\nIIII\n'
+                      '@r3\nAGC\n+\nIII\n@r4\nAcgT\n+\nIIII\n')
+
+        obs = _fastq_stats(file_in)
+
+        self.assertDictEqual(
+            dict(obs.gc_contents), {100: 1, 0: 1, 66: 1, 50: 1}
+        )
+        summary = obs.summary()
+        self.assertAlmostEqual(summary['gc_percent'], 100 * 8 / 15)
+        self.assertDictEqual(summary['gc_percent_quantiles'], {
+            '0': 0, '0.25': 0, '0.5': 50, '0.75': 66, '1': 100
+        })
+
+    @parameterized.expand([
+        ('@r1\nACGT\n+\nIIII\n@r2\nAC\n+\nII\n', 2),
+        ('@r1\nACGT\n+\nIIII\n@r2\nAC\n+\nII', 2),
+        ('', 0),
+    ])
+    def test_count_reads(self, content, exp_count):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write(content)
+
+        self.assertEqual(_count_reads(file_in, block_size=5), exp_count)
+
+    def test_count_reads_gzipped(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq.gz')
+        _rewrite_fastq(self.get_data_path('SRR123456.fastq'), file_in)
+
+        self.assertEqual(_count_reads(file_in), 3)
+
+    def test_check_spot_counts(self):
+        files = []
+        for acc, reads in (('SRR1_1', 3), ('SRR1_2', 2), ('SRR2', 3),
+                           ('SRR4', 1)):
+            file_out = os.path.join(self.temp_dir.name, f'{acc}.fastq.gz')
+            with FastqGzWriter(file_out) as writer:
+                for i in range(reads):
+                    writer.write_record(f'r{i}', 'ACGT', 'I#II')
+            files.append(file_out)
+
+        obs = _check_spot_counts({'SRR1': 3, 'SRR2': 3, 'SRR3': 5}, files)
+
+        self.assertDictEqual(obs, {'SRR1': (3, [3, 2]), 'SRR3': (5, [])})
+
+
+class TestFastqGzWriter(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def test_write_records(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with FastqGzWriter(file_out) as writer:
+            writer.write_record('r1 desc', 'ACGT', 'IIII')
+            writer.flush()
+            writer.write_record('r2', 'AC', '#I')
+
+        self.assertTrue(writer.closed)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(), '@r1 desc\nACGT\n+\nIIII\n@r2\nAC\n+\n#I\n'
+            )
+        self.assertEqual(writer.stats, FastqStats(
+            reads=2, bases=6, min_length=2, max_length=4,
+            compressed_bytes=os.path.getsize(file_out),
+            uncompressed_bytes=33
+        ))
+
+    def test_write_records_buffered(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        records = [(f'r{i}', 'ACGT' * i, 'I' * 4 * i) for i in range(1, 6)]
+
+        with FastqGzWriter(file_out, write_buffer_size=30) as writer:
+            for record in records:
+                writer.write_record(*record)
+            writer.flush()
+            # all the records are compressed once flushed - into the
+            # temporary file, which replaces the output once closed
+            self.assertFalse(os.path.exists(file_out))
+            with open(f'{file_out}.tmp', 'rb') as fin:
+                obs = zlib.decompressobj(31).decompress(fin.read())
+            self.assertEqual(
+                obs, b''.join(_format_record(*x) for x in records)
+            )
+
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), records)
+
+    @parameterized.expand([
+        ('uniform', ['????', '??'], True),
+        ('varying', ['????', '?#'], False),
+        ('empty', [], False),
+    ])
+    def test_write_records_uniform_quality(self, name, quals, exp_uniform):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with FastqGzWriter(file_out) as writer:
+            for i, qual in enumerate(quals):
+                writer.write_record(f'r{i}', 'A' * len(qual), qual)
+
+        self.assertEqual(writer.stats.uniform_quality, exp_uniform)
+
+    def test_write_records_error(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'differ in length'):
+            with FastqGzWriter(file_out) as writer:
+                writer.write_record('r1', 'ACGT', 'IIII')
+                writer.write_record('r2', 'ACGT', 'II')
+
+        # neither the output nor the temporary file are left behind
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    def test_write_records_discard_append(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        with FastqGzWriter(file_out) as writer:
+            writer.write_record('r1', 'ACGT', 'IIII')
+
+        writer = FastqGzWriter(file_out, append=True)
+        writer.write_record('r2', 'AC', 'II')
+        writer.discard()
+
+        self.assertListEqual(os.listdir(self.temp_dir.name), ['out.fastq.gz'])
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), [('r1', 'ACGT', 'IIII')])
+
+    def test_write_records_reproducible(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with FastqGzWriter(file_out) as writer:
+            writer.write_record('r1', 'ACGT', 'IIII')
+
+        with open(file_out, 'rb') as fout:
+            obs = fout.read()
+        # neither a modification time nor a file name is stored
+        self.assertEqual(obs[3:8], b'\x00' * 5)
+
+    def test_write_records_append(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with FastqGzWriter(file_out) as writer:
+            writer.write_record('r1', 'ACGT', 'IIII')
+        with FastqGzWriter(file_out, append=True) as writer:
+            writer.write_record('r2', 'AC', '#I')
+
+        self.assertEqual(writer.stats.reads, 1)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(), '@r1\nACGT\n+\nIIII\n@r2\nAC\n+\n#I\n'
+            )
+
+    def test_write_record_length_mismatch(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with FastqGzWriter(file_out) as writer:
+            with self.assertRaisesRegex(
+                    ValueError, r'read r1 differ in length \(4 vs. 3\)'
+            ):
+                writer.write_record('r1', 'ACGT', 'III')
+        self.assertEqual(writer.stats.reads, 0)
+
+    def test_close_twice(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        writer = FastqGzWriter(file_out)
+        writer.close()
+        writer.close()
+
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(fout.read(), '')
+
+
+class TestFastqReader(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def test_read_records(self):
+        with FastqReader(self.get_data_path('SRR123456.fastq')) as reader:
+            obs = list(reader)
+
+        self.assertEqual(reader.records, 3)
+        self.assertListEqual(
+            [x[0] for x in obs],
+            ['test_acc_single.1 test_1_seq length=278',
+             'test_acc_single.4 test_2_seq length=274',
+             'test_acc_single.5 test_3_seq length=267']
+        )
+        self.assertTrue(all(len(seq) == len(qual) for _, seq, qual in obs))
+
+    def test_read_records_gzipped(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq.gz')
+        with FastqGzWriter(file_in) as writer:
+            writer.write_record('r1', 'ACGT', 'IIII')
+            writer.write_record('r2', 'AC', '#I')
+
+        with FastqReader(file_in) as reader:
+            obs = list(reader)
+
+        self.assertListEqual(obs, [('r1', 'ACGT', 'IIII'), ('r2', 'AC', '#I')])
+
+    def test_read_records_incomplete(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nAC\n')
+
+        with FastqReader(file_in) as reader:
+            self.assertEqual(next(reader), ('r1', 'ACGT', 'IIII'))
+            with self.assertRaisesRegex(
+                    TruncatedFastqError,
+                    r'Record 2 \(read r2\) in .*in.fastq is invalid: '
+                    r'record is truncated \(starting at byte 16\)'
+            ) as cm:
+                next(reader)
+        self.assertEqual(cm.exception.offset, 16)
+
+    def test_read_records_blank_lines(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n\n@r2\nAC\n+\n#I\n\r\n\n')
+
+        with FastqReader(file_in) as reader:
+            obs = list(reader)
+
+        self.assertListEqual(obs, [('r1', 'ACGT', 'IIII'), ('r2', 'AC', '#I')])
+
+    def test_read_records_blank_lines_validated(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n\n')
+
+        with FastqReader(file_in, validate=True) as reader, \
+                self.assertRaisesRegex(
+                    FastqValidationError, 'header does not start with "@"'
+                ):
+            list(reader)
+
+    @parameterized.expand([
+        ('@r2\nAC\n+\nI', 16),
+        ('@r2\nAC', 16),
+        ('@r2\nAC\n+\nII\n@r3\nACGT\n+\nIII', 28),
+        ('@r2\nAC\n+\nII\n@', 28),
+    ])
+    def test_read_records_truncated_stop(self, content, exp_offset):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n' + content)
+
+        with FastqReader(file_in, on_truncation='stop') as reader:
+            obs = list(reader)
+
+        self.assertEqual(obs[0], ('r1', 'ACGT', 'IIII'))
+        self.assertEqual(reader.truncated_at, exp_offset)
+        self.assertEqual(len(obs), 1 if exp_offset == 16 else 2)
+
+    def test_read_records_multiline(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\nAC\n+r1\n@III\nI+\n@r2\nA\n+\n@\n'
+                      '@r3\n\n+\n\n@r4\nACG\nT\n+\nIIII')
+
+        with FastqReader(file_in, multiline=True) as reader:
+            obs = list(reader)
+
+        self.assertListEqual(obs, [
+            ('r1', 'ACGTAC', '@IIII+'), ('r2', 'A', '@'), ('r3', '', ''),
+            ('r4', 'ACGT', 'IIII')
+        ])
+
+    @parameterized.expand([
+        ('@r2\nACGT\nAC\n+\nIII\nI', 16),
+        ('@r2\nACGT\nAC\n+\nIII\nI\n', 16),
+        ('@r2\nACGT\nAC\n', 16),
+        ('@r2\n', 16),
+    ])
+    def test_read_records_multiline_truncated(self, content, exp_offset):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n' + content)
+
+        with FastqReader(
+                file_in, multiline=True, on_truncation='stop'
+        ) as reader:
+            self.assertEqual(list(reader), [('r1', 'ACGT', 'IIII')])
+        self.assertEqual(reader.truncated_at, exp_offset)
+
+    def test_read_records_fill_quality_multiline(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\nAC\n@r2\nAC\n+\n@I\n@r3\nA\nC')
+
+        with FastqReader(
+                file_in, multiline=True, fill_quality=40
+        ) as reader:
+            obs = list(reader)
+
+        self.assertListEqual(obs, [
+            ('r1', 'ACGTAC', 'IIIIII'), ('r2', 'AC', '@I'), ('r3', 'AC', 'II')
+        ])
+        self.assertEqual(reader.filled, 2)
+
+    def test_read_records_no_final_newline(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nAC\n+\nII')
+
+        with FastqReader(file_in) as reader:
+            self.assertEqual(len(list(reader)), 2)
+        self.assertIsNone(reader.truncated_at)
+
+    def test_read_records_no_header(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('>r1\nACGT\n+\nIIII\n')
+
+        with FastqReader(file_in) as reader:
+            with self.assertRaisesRegex(
+                    FastqValidationError,
+                    r'Record 1 in .* header does not start with "@"'
+            ):
+                next(reader)
+
+    @parameterized.expand([
+        ('@r1\nACGT\n-\nIIII\n', 'separator line does not start with'),
+        ('@r1\n\n+\n\n', 'sequence is empty'),
+        ('@r1\nACGT\n+\nIII\n', r'lengths differ \(4 vs. 3\)'),
+        ('@r1\nACGT\n+\nII I\n', 'outside of the Phred\\+33 range'),
+    ])
+    def test_read_records_validate(self, content, exp_reason):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r0\nA\n+\nI\n' + content)
+
+        with FastqReader(file_in, validate=True) as reader:
+            self.assertEqual(next(reader), ('r0', 'A', 'I'))
+            with self.assertRaisesRegex(
+                    FastqValidationError, exp_reason
+            ) as cm:
+                next(reader)
+        self.assertEqual(cm.exception.record, 2)
+        self.assertEqual(cm.exception.read_id, 'r1')
+
+    def test_read_records_no_validation(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n-\nIII\n')
+
+        with FastqReader(file_in) as reader:
+            self.assertListEqual(list(reader), [('r1', 'ACGT', 'III')])
+
+
+class TestPairedReads(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def _write(self, filename, content):
+        path = os.path.join(self.temp_dir.name, filename)
+        with open(path, 'w') as fin:
+            fin.write(content)
+        return path
+
+    def test_check_pairing_in_sync(self):
+        obs = _check_pairing(
+            self.get_data_path('SRR123457_1.fastq'),
+            self.get_data_path('SRR123457_2.fastq')
+        )
+        self.assertIsNone(obs)
+
+    def test_check_pairing_suffixes(self):
+        r1 = self._write('r1.fastq', '@r1/1 x\nA\n+\nI\n@r2/1\nA\n+\nI\n')
+        r2 = self._write('r2.fastq', '@r1/2 y\nA\n+\nI\n@r2/2\nA\n+\nI\n')
+
+        self.assertIsNone(_check_pairing(r1, r2))
+
+    def test_check_pairing_trailing_blank_line(self):
+        r1 = self._write('r1.fastq', '@r1/1\nA\n+\nI\n\n')
+        r2 = self._write('r2.fastq', '@r1/2\nA\n+\nI\n')
+
+        self.assertIsNone(_check_pairing(r1, r2))
+
+    @parameterized.expand([
+        ('@r1/2\nA\n+\nI\n@r3/2\nA\n+\nI\n', (2, 'r2/1', 'r3/2')),
+        ('@r1/2\nA\n+\nI\n', (2, 'r2/1', None)),
+        ('@r1/2\nA\n+\nI\n@r2/2\nA\n+\nI\n@r3/2\nA\n+\nI\n',
+         (3, None, 'r3/2')),
+        ('@r1/2\nA\n+\nI\n@r2/2\nAC\n+\n', (2, 'r2/1', None)),
+    ])
+    def test_check_pairing_divergence(self, content, exp_divergence):
+        r1 = self._write('r1.fastq', '@r1/1\nA\n+\nI\n@r2/1\nA\n+\nI\n')
+        r2 = self._write('r2.fastq', content)
+
+        self.assertTupleEqual(_check_pairing(r1, r2), exp_divergence)
+
+    def test_repair_pairs(self):
+        r1 = self._write(
+            'r1.fastq', '@r1/1\nA\n+\nI\n@r2/1\nC\n+\nI\n@r4/1\nG\n+\nI\n'
+        )
+        r2 = self._write(
+            'r2.fastq', '@r1/2\nT\n+\nI\n@r3/2\nA\n+\nI\n@r4/2\nC\n+\nI\n'
+        )
+        r1_out, r2_out, orphans_out = (
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1_out', 'r2_out', 'orphans')
+        )
+
+        obs = _repair_pairs(r1, r2, r1_out, r2_out, orphans_out)
+
+        self.assertEqual(obs, PairRepairStats(2, 1, 1))
+        self.assertIsNone(_check_pairing(r1_out, r2_out))
+        with FastqReader(r1_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r1/1', 'A', 'I'), ('r4/1', 'G', 'I')]
+            )
+        with FastqReader(orphans_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r2/1', 'C', 'I'), ('r3/2', 'A', 'I')]
+            )
+
+    def test_repair_pairs_truncated(self):
+        r1 = self._write('r1.fastq', '@r1/1\nA\n+\nI\n@r2/1\nC\n+\nI\n')
+        r2 = self._write('r2.fastq', '@r1/2\nT\n+\nI\n@r2/2\nG\n')
+        outputs = [
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1_out', 'r2_out', 'orphans')
+        ]
+
+        with self.assertRaises(TruncatedFastqError):
+            _repair_pairs(r1, r2, *outputs)
+        self.assertListEqual(
+            sorted(os.listdir(self.temp_dir.name)), ['r1.fastq', 'r2.fastq']
+        )
+
+    def test_interleave(self):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        obs = _interleave(
+            self.get_data_path('SRR123457_1.fastq'),
+            self.get_data_path('SRR123457_2.fastq'), file_out
+        )
+
+        self.assertEqual(obs.reads, 6)
+        with FastqReader(file_out) as reader:
+            obs_ids = [read_id.split()[0] for read_id, _, _ in reader]
+        self.assertListEqual(obs_ids, [
+            'test_acc_single.1', 'test_acc_single.1', 'test_acc_single.4',
+            'test_acc_single.4', 'test_acc_single.5', 'test_acc_single.5'
+        ])
+
+    @parameterized.expand([
+        ('@r1/2\nA\n+\nI\n@r3/2\nA\n+\nI\n',
+         r'Record 2 \(read r3/2\) in .*r2.fastq .* not the mate of read r2/1'),
+        ('@r1/2\nA\n+\nI\n',
+         r'Record 2 in .*r2.fastq .* ends before the mate of read r2/1'),
+    ])
+    def test_interleave_out_of_sync(self, content, exp_error):
+        r1 = self._write('r1.fastq', '@r1/1\nA\n+\nI\n@r2/1\nA\n+\nI\n')
+        r2 = self._write('r2.fastq', content)
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with self.assertRaisesRegex(PairingError, exp_error):
+            _interleave(r1, r2, file_out)
+        self.assertListEqual(
+            [x for x in os.listdir(self.temp_dir.name) if 'out' in x], []
+        )
+
+    def test_deinterleave(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq.gz')
+        _interleave(
+            self.get_data_path('SRR123457_1.fastq'),
+            self.get_data_path('SRR123457_2.fastq'), file_in
+        )
+        r1_out, r2_out = (
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1_out', 'r2_out')
+        )
+
+        obs = _deinterleave(file_in, r1_out, r2_out)
+
+        self.assertEqual(obs, PairRepairStats(3, 0, 0))
+        for file_out, exp in zip(
+                (r1_out, r2_out), ('SRR123457_1.fastq', 'SRR123457_2.fastq')
+        ):
+            with FastqReader(file_out) as obs_reader, \
+                    FastqReader(self.get_data_path(exp)) as exp_reader:
+                self.assertListEqual(list(obs_reader), list(exp_reader))
+
+    def test_deinterleave_truncated(self):
+        file_in = self._write(
+            'in.fastq', '@r1/1\nA\n+\nI\n@r1/2\nC\n+\nI\n@r2/1\nG\n'
+        )
+        outputs = [
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1_out', 'r2_out', 'orphans')
+        ]
+        file_stats = {}
+
+        with self.assertRaises(TruncatedFastqError):
+            _deinterleave(file_in, *outputs, file_stats=file_stats)
+        # none of the outputs (nor their temporary files) are kept
+        self.assertListEqual(os.listdir(self.temp_dir.name), ['in.fastq'])
+        self.assertDictEqual(file_stats, {})
+
+    def test_deinterleave_orphans(self):
+        file_in = self._write(
+            'in.fastq', '@r1/1\nA\n+\nI\n@r2/2\nC\n+\nI\n@r3/1\nG\n+\nI\n'
+                        '@r3/2\nT\n+\nI\n@r4/1\nA\n+\nI\n'
+        )
+        r1_out, r2_out, orphans_out = (
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('r1_out', 'r2_out', 'orphans')
+        )
+
+        obs = _deinterleave(file_in, r1_out, r2_out, orphans_out)
+
+        self.assertEqual(obs, PairRepairStats(1, 2, 1))
+        with FastqReader(r2_out) as reader:
+            self.assertListEqual(list(reader), [('r3/2', 'T', 'I')])
+        with FastqReader(orphans_out) as reader:
+            self.assertListEqual(
+                [x[0] for x in reader], ['r1/1', 'r2/2', 'r4/1']
+            )
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,1894 @@
+# ----------------------------------------------------------------------------
+# Copyright (c) 2022, Bokulich Laboratories.
+#
+# Distributed under the terms of the Modified BSD License.
+#
+# The full license is in the file LICENSE, distributed with this software.
+# ----------------------------------------------------------------------------
+import bz2
+import gzip
+import hashlib
+import io
+import json
+import lzma
+import os
+import re
+import shutil
+import struct
+import time
+import unittest
+import zlib
+from pathlib import Path
+from threading import Thread
+from unittest.mock import MagicMock, patch
+
+from parameterized import parameterized
+from qiime2.plugin.testing import TestPluginBase
+
+from q2_fondue.compression import _AdaptiveLevel, verify_gzip
+from q2_fondue.fastq import FastqReader, _count_reads
+from q2_fondue.rewrite import (
+    RewriteOptions, _rewrite_fastq, _rewrite_fastq_batch, _rewrite_to_casava
+)
+from q2_fondue.utils import (
+    CancellationToken, CompressionError, FastqParseError, FastqRewriteError,
+    FastqStats, FastqValidationError, InsufficientSpaceError,
+    RewriteCancelledError, TEMP_FILES, TruncatedFastqError, _set_log_level
+)
+
+
+class TestRewriteFastq(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def test_rewrite_fastq(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = self.get_data_path('SRR123456.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out)
+
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            for lin, lout in zip(fin.readlines(), fout.readlines()):
+                self.assertEqual(lin, lout)
+
+        # clean up
+        os.remove(file_out)
+
+    def test_rewrite_fastq_options(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        options = RewriteOptions(min_length=270)
+
+        obs = _rewrite_fastq(file_in, file_out, options, checksums=['md5'])
+
+        self.assertEqual(obs.reads, 2)
+        self.assertEqual(obs.dropped, {'length': 1})
+        self.assertIn('md5', obs.checksums)
+        self.assertTupleEqual(options.checksums, ())
+
+    def test_rewrite_options_invalid(self):
+        with self.assertRaisesRegex(ValueError, r'at least 1 \(got 0\)'):
+            RewriteOptions(threads=0)
+
+    def test_rewrite_fastq_stats(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        obs = _rewrite_fastq(file_in, file_out)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(obs.bases, 278 + 274 + 267)
+        self.assertEqual(obs.min_length, 267)
+        self.assertEqual(obs.max_length, 278)
+        self.assertAlmostEqual(obs.mean_length, 273.0)
+        self.assertEqual(obs.compressed_bytes, os.path.getsize(file_out))
+
+    def test_rewrite_fastq_stats_multithreaded_no_final_newline(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTACGTAC\n+\nIIIII#IIII\n@r2\nACG\n+\nIII')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, threads=2, compression_block_size=100
+        )
+
+        self.assertEqual(obs, FastqStats(
+            reads=2, bases=13, min_length=3, max_length=10,
+            compressed_bytes=os.path.getsize(file_out),
+            uncompressed_bytes=os.path.getsize(file_in)
+        ))
+
+    def test_rewrite_fastq_throughput(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with patch('time.perf_counter', side_effect=[10.0, 12.0]), \
+                patch('time.thread_time', side_effect=[1.0, 2.5]):
+            obs = _rewrite_fastq(file_in, file_out)
+
+        self.assertEqual(obs.uncompressed_bytes, os.path.getsize(file_in))
+        self.assertEqual(obs.wall_time, 2.0)
+        self.assertEqual(obs.cpu_time, 1.5)
+        self.assertEqual(obs.reads_per_second, obs.reads / 2)
+        self.assertAlmostEqual(
+            obs.mb_per_second_in, os.path.getsize(file_in) / 2e6
+        )
+        self.assertAlmostEqual(
+            obs.mb_per_second_out, os.path.getsize(file_out) / 2e6
+        )
+
+    def test_rewrite_fastq_buffer_sizes(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with patch('shutil.copyfileobj', wraps=shutil.copyfileobj) as mock:
+            _rewrite_fastq(
+                file_in, file_out, read_buffer_size=7, write_buffer_size=5
+            )
+
+        self.assertEqual(mock.call_args.args[2], 7)
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'rb') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    def test_rewrite_fastq_invalid_buffer_size(self):
+        with self.assertRaisesRegex(ValueError, 'Buffer sizes must be'):
+            _rewrite_fastq(
+                self.get_data_path('SRR123456.fastq'), 'out.fastq.gz',
+                write_buffer_size=0
+            )
+
+    @parameterized.expand([
+        ('copy', {}),
+        ('threads', {'threads': 2}),
+        ('bgzf', {'output_format': 'bgzf'}),
+        ('parsed', {'min_length': 1, 'validate': True}),
+    ])
+    def test_rewrite_fastq_pipeline(self, name, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_exp = os.path.join(self.temp_dir.name, 'exp.fastq.gz')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        exp = _rewrite_fastq(file_in, file_exp, **kwargs)
+        obs = _rewrite_fastq(
+            file_in, file_out, pipeline=True, read_buffer_size=10, **kwargs
+        )
+
+        self.assertEqual(obs, exp)
+        with open(file_exp, 'rb') as fexp, open(file_out, 'rb') as fout:
+            self.assertEqual(fexp.read(), fout.read())
+
+    def test_rewrite_fastq_pipeline_read_error(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n')
+
+        with self.assertRaisesRegex(FastqValidationError, 'read r2'):
+            _rewrite_fastq(file_in, file_out, validate=True, pipeline=True)
+        self.assertListEqual(os.listdir(self.temp_dir.name), [
+            'SRR000000.fastq'
+        ])
+
+    def test_rewrite_fastq_pipeline_write_error(self):
+        class FailingWriter(io.RawIOBase):
+            def writable(self):
+                return True
+
+            def write(self, data):
+                raise OSError('Disk quota exceeded')
+
+        with self.assertRaisesRegex(FastqRewriteError, 'quota exceeded'):
+            _rewrite_fastq(
+                self.get_data_path('SRR123456.fastq'), FailingWriter(),
+                pipeline=True
+            )
+
+    def test_rewrite_fastq_short_writes(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        f_out = io.BytesIO()
+
+        class ShortWriter(io.RawIOBase):
+            def writable(self):
+                return True
+
+            def write(self, data):
+                # accept at most 7 bytes at a time
+                return f_out.write(bytes(data[:7]))
+
+        _rewrite_fastq(file_in, ShortWriter())
+
+        with open(file_in, 'rb') as fin:
+            self.assertEqual(gzip.decompress(f_out.getvalue()), fin.read())
+
+    def test_rewrite_fastq_stats_empty(self):
+        file_in = os.path.join(self.temp_dir.name, 'empty.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'empty.fastq.gz')
+        open(file_in, 'w').close()
+
+        obs = _rewrite_fastq(file_in, file_out)
+
+        self.assertEqual(obs.reads, 0)
+        self.assertIsNone(obs.min_length)
+        self.assertEqual(obs.mean_length, 0.0)
+
+    def test_rewrite_fastq_validate(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        obs = _rewrite_fastq(file_in, file_out, validate=True)
+
+        self.assertEqual(obs.reads, 3)
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            lines_in, lines_out = fin.readlines(), fout.readlines()
+        self.assertEqual(len(lines_in), len(lines_out))
+        for i, (lin, lout) in enumerate(zip(lines_in, lines_out)):
+            self.assertEqual(b'+\n' if i % 4 == 2 else lin, lout)
+
+    @parameterized.expand([
+        ('copy', 'copy', b'+test_acc_single.1 test_1_seq length=278\n'),
+        ('parse', 'parse', b'+\n'),
+        ('auto', 'auto', b'+test_acc_single.1 test_1_seq length=278\n'),
+    ])
+    def test_rewrite_fastq_mode(self, name, mode, exp_separator):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with patch('q2_fondue.rewrite.FastqReader', wraps=FastqReader) as mock:
+            obs = _rewrite_fastq(file_in, file_out, mode=mode)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(mock.called, mode == 'parse')
+        with gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fout.readlines()[2], exp_separator)
+
+    def test_rewrite_fastq_mode_copy_parsing_options(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'use mode="parse"'):
+            _rewrite_fastq(file_in, file_out, mode='copy', min_length=10)
+        self.assertFalse(os.path.exists(file_out))
+
+    def test_rewrite_fastq_mode_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, r'\(got "lines"\)'):
+            _rewrite_fastq(file_in, file_out, mode='lines')
+
+    @parameterized.expand([('lf', '\n'), ('crlf', '\r\n')])
+    def test_rewrite_fastq_long_reads(self, name, newline):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        lengths = [3 * 1024 ** 2 + 1, 10, 2 * 1024 ** 2]
+        with open(file_in, 'w', newline='') as fin:
+            for i, length in enumerate(lengths):
+                fin.write(newline.join([
+                    f'@r{i}', 'A' * length, f'+r{i}', '5' * length, ''
+                ]))
+
+        obs = _rewrite_fastq(file_in, file_out, read_buffer_size=65535)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(obs.bases, sum(lengths))
+        self.assertEqual(obs.min_length, 10)
+        self.assertEqual(obs.max_length, lengths[0])
+        self.assertTrue(obs.uniform_quality)
+
+    @parameterized.expand([(1,), (5,), (1024 ** 2,)])
+    def test_rewrite_fastq_bare_separator(self, read_buffer_size):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_exp = os.path.join(self.temp_dir.name, 'exp.fastq.gz')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        _rewrite_fastq(file_in, file_exp, mode='parse')
+        obs = _rewrite_fastq(
+            file_in, file_out, mode='copy', bare_separator=True,
+            read_buffer_size=read_buffer_size
+        )
+
+        self.assertEqual(obs.reads, 3)
+        with gzip.open(file_exp, 'r') as fexp, gzip.open(file_out) as fout:
+            exp = fexp.read()
+            self.assertEqual(fout.read(), exp)
+        self.assertEqual(obs.uncompressed_bytes, len(exp))
+
+    def test_rewrite_fastq_bare_separator_crlf(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'wb') as fin:
+            fin.write(b'@r1\r\nACGT\r\n+r1\r\nIIII\r\n@r2\nAC\n+\nII')
+
+        _rewrite_fastq(
+            file_in, file_out, bare_separator=True, read_buffer_size=3
+        )
+
+        with gzip.open(file_out, 'r') as fout:
+            self.assertEqual(
+                fout.read(), b'@r1\r\nACGT\r\n+\r\nIIII\r\n@r2\nAC\n+\nII'
+            )
+
+    def test_rewrite_fastq_validate_error(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n')
+
+        with self.assertRaisesRegex(
+                FastqValidationError, r'Record 2 \(read r2\) in '
+                                      r'.*SRR000000.fastq is invalid'
+        ):
+            _rewrite_fastq(file_in, file_out, validate=True)
+
+    @parameterized.expand([
+        ('invalid', {'validate': True}, FastqValidationError),
+        ('interrupted', {'output_format': 'bgzf'}, KeyboardInterrupt),
+    ])
+    def test_rewrite_fastq_atomic(self, name, kwargs, error):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n')
+        # a complete file from an earlier run must stay untouched
+        with open(file_out, 'wb') as fout:
+            fout.write(b'previous')
+
+        with patch('q2_fondue.compression._write_bgzf_index',
+                   side_effect=KeyboardInterrupt), \
+                self.assertRaises(error):
+            _rewrite_fastq(file_in, file_out, overwrite=True, **kwargs)
+
+        self.assertListEqual(
+            sorted(os.listdir(self.temp_dir.name)),
+            ['SRR000000.fastq', 'SRR000000.fastq.gz']
+        )
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), b'previous')
+
+    def test_rewrite_fastq_atomic_keep_temp(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII\n')
+
+        with patch.object(TEMP_FILES, 'keep_temp', True), \
+                self.assertRaises(FastqValidationError):
+            _rewrite_fastq(file_in, file_out, validate=True)
+
+        self.assertTrue(os.path.isfile(f'{file_out}.tmp'))
+        self.assertNotIn(f'{file_out}.tmp', TEMP_FILES.paths)
+
+    def test_rewrite_fastq_exists(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        with open(file_out, 'wb') as fout:
+            fout.write(b'previous')
+
+        with self.assertRaisesRegex(
+                FileExistsError, 'already exists - use overwrite=True'
+        ):
+            _rewrite_fastq(file_in, file_out)
+        with open(file_out, 'rb') as fout:
+            self.assertEqual(fout.read(), b'previous')
+
+        obs = _rewrite_fastq(file_in, file_out, overwrite=True)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(_count_reads(file_out), 3)
+
+    @parameterized.expand([
+        ('gzip', {}), ('bgzf', {'output_format': 'bgzf', 'threads': 2})
+    ])
+    def test_rewrite_fastq_fd(self, name, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        fd = os.open(file_out, os.O_WRONLY | os.O_CREAT)
+        try:
+            obs = _rewrite_fastq(file_in, fd, checksums=['md5'], **kwargs)
+            # the descriptor is left open for further writes
+            os.write(fd, gzip.compress(b'@r4\nA\n+\nI\n'))
+        finally:
+            os.close(fd)
+
+        self.assertEqual(obs.reads, 3)
+        with open(file_out, 'rb') as fout:
+            content = fout.read()
+        self.assertEqual(
+            obs.checksums['md5'],
+            hashlib.md5(content[:obs.compressed_bytes]).hexdigest()
+        )
+        self.assertEqual(_count_reads(file_out), 4)
+        self.assertListEqual(os.listdir(self.temp_dir.name), ['out.fastq.gz'])
+
+    def test_rewrite_fastq_stdout(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+
+        with open(file_out, 'wb') as fout, \
+                patch('sys.stdout', MagicMock(fileno=fout.fileno)):
+            obs = _rewrite_fastq(file_in, '-')
+
+        self.assertEqual(obs.compressed_bytes, os.path.getsize(file_out))
+        with open(file_in, 'rb') as fin, gzip.open(file_out) as fout:
+            self.assertEqual(fout.read(), fin.read())
+
+    @parameterized.expand([
+        ('verify', {'verify': True}), ('qc_report', {'qc_report': True}),
+        ('append', {'append': True})
+    ])
+    def test_rewrite_fastq_stdout_invalid(self, name, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+
+        with self.assertRaisesRegex(ValueError, 'standard output'):
+            _rewrite_fastq(file_in, '-', **kwargs)
+
+    @parameterized.expand([
+        ('pathlib', Path), ('bytes', os.fsencode),
+    ])
+    def test_rewrite_fastq_path_like(self, name, to_path):
+        file_in = to_path(self.get_data_path('SRR123456.fastq'))
+        # names which are not valid UTF-8 are kept as they are
+        file_out = to_path(
+            os.path.join(self.temp_dir.name, 'SRR123456_\udcff.fastq.gz')
+            if name == 'bytes' else
+            os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        )
+
+        obs = _rewrite_fastq(file_in, file_out, renumber=True)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(_count_reads(file_out), 3)
+        self.assertTrue(verify_gzip(file_out))
+        with FastqReader(file_out) as reader:
+            self.assertEqual(next(reader)[0], 'SRR123456.1')
+        self.assertEqual(len(os.listdir(self.temp_dir.name)), 1)
+
+    @parameterized.expand([
+        ('plain', lambda x: x), ('gzip', gzip.compress), ('bz2', bz2.compress)
+    ])
+    def test_rewrite_fastq_file_objects(self, name, compress):
+        with open(self.get_data_path('SRR123456.fastq'), 'rb') as fin:
+            content = fin.read()
+        f_in = io.BytesIO(compress(content))
+        f_out = io.BytesIO()
+
+        obs = _rewrite_fastq(f_in, f_out, validate=True)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertFalse(f_out.closed)
+        self.assertEqual(obs.compressed_bytes, len(f_out.getvalue()))
+        with FastqReader(io.BytesIO(f_out.getvalue())) as obs_reader, \
+                FastqReader(memoryview(content)) as exp_reader:
+            self.assertListEqual(list(obs_reader), list(exp_reader))
+
+    def test_rewrite_fastq_pipe(self):
+        with open(self.get_data_path('SRR123456.fastq'), 'rb') as fin:
+            content = fin.read()
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        fd_read, fd_write = os.pipe()
+
+        def _produce():
+            with os.fdopen(fd_write, 'wb') as f_pipe:
+                f_pipe.write(gzip.compress(content))
+
+        producer = Thread(target=_produce)
+        producer.start()
+        try:
+            obs = _rewrite_fastq(fd_read, file_out, threads=2)
+        finally:
+            producer.join()
+            os.close(fd_read)
+
+        self.assertEqual(obs.reads, 3)
+        with gzip.open(file_out) as fout:
+            self.assertEqual(fout.read(), content)
+
+    def test_rewrite_fastq_stdin(self):
+        file_in = os.path.join(self.temp_dir.name, 'in.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n')
+
+        with open(file_in, 'rb') as fin, \
+                patch('sys.stdin', MagicMock(fileno=fin.fileno)):
+            obs = _rewrite_fastq('-', file_out, validate=True)
+
+        self.assertEqual(obs.reads, 1)
+        self.assertEqual(_count_reads(file_out), 1)
+
+    def test_rewrite_fastq_bytearray(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        buffer = bytearray()
+
+        obs = _rewrite_fastq(file_in, buffer, threads=2)
+
+        self.assertEqual(obs.compressed_bytes, len(buffer))
+        with open(file_in, 'rb') as fin:
+            self.assertEqual(gzip.decompress(buffer), fin.read())
+
+    @parameterized.expand([('gzip', 1), ('threads', 2)])
+    def test_rewrite_fastq_append(self, name, threads):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        _rewrite_fastq(file_in, file_out)
+        size = os.path.getsize(file_out)
+
+        obs = _rewrite_fastq(
+            file_in, file_out, threads=threads, renumber=True, append=True
+        )
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(
+            obs.compressed_bytes, os.path.getsize(file_out) - size
+        )
+        self.assertFalse(os.path.exists(f'{file_out}.tmp'))
+        with FastqReader(file_out) as reader:
+            obs_ids = [x[0] for x in reader]
+        self.assertEqual(len(obs_ids), 6)
+        self.assertListEqual(
+            obs_ids[3:], ['SRR123456.1', 'SRR123456.2', 'SRR123456.3']
+        )
+
+    def test_rewrite_fastq_append_new(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out, append=True)
+
+        self.assertEqual(_count_reads(file_out), 3)
+
+    def test_rewrite_fastq_append_bgzf(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'BGZF files cannot be'):
+            _rewrite_fastq(
+                file_in, file_out, output_format='bgzf', append=True
+            )
+
+    @parameterized.expand([
+        ('report', ['r1', 'r2', 'r3'], 10), ('drop', ['r1', 'r3'], 7)
+    ])
+    def test_rewrite_fastq_length_mismatches(
+            self, mode, exp_ids, exp_bases
+    ):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACG\n+\nII\n'
+                      '@r3\nACG\n+\nIII\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, validate=True, on_length_mismatch=mode
+        )
+
+        self.assertListEqual(obs.length_mismatches, [(file_in, 2, 'r2')])
+        self.assertEqual(obs.bases, exp_bases)
+        with FastqReader(file_out, on_length_mismatch='report') as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    def test_rewrite_fastq_length_mismatches_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, r'\(got "ignore"\)'):
+            _rewrite_fastq(file_in, file_out, on_length_mismatch='ignore')
+
+    def test_rewrite_fastq_truncated_stop(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nII#I\n@r2\nACGT\n+\nII')
+
+        with self.assertLogs('q2_fondue.rewrite', level='WARNING') as cm:
+            obs = _rewrite_fastq(file_in, file_out, on_truncation='stop')
+
+        self.assertIn('only the first 1 complete records', cm.output[0])
+        self.assertEqual(obs.reads, 1)
+        self.assertEqual(obs.truncated_at, 16)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(fout.read(), '@r1\nACGT\n+\nII#I\n')
+
+    def test_rewrite_fastq_truncated_error(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII')
+
+        with self.assertRaises(TruncatedFastqError):
+            _rewrite_fastq(file_in, file_out, validate=True)
+
+    def test_rewrite_fastq_normalize(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'wb') as fin:
+            fin.write(b'@r1 desc \r\nACGT\r\n+r1 \r\nIIII \t\r\n'
+                      b'@r2\nAC  \n+\nII\n')
+
+        obs = _rewrite_fastq(file_in, file_out, normalize=True)
+
+        self.assertEqual(obs.bases, 6)
+        with gzip.open(file_out, 'rb') as fout:
+            self.assertEqual(
+                fout.read(), b'@r1 desc\nACGT\n+\nIIII\n@r2\nAC\n+\nII\n'
+            )
+
+    def test_rewrite_fastq_multiline(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\nAC\n+\nIII\nIII\n@r2\nAC\n+\nII\n')
+
+        obs = _rewrite_fastq(file_in, file_out, multiline=True)
+
+        self.assertEqual(obs.reads, 2)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(), '@r1\nACGTAC\n+\nIIIIII\n@r2\nAC\n+\nII\n'
+            )
+
+    def test_rewrite_fastq_phred64_to_phred33(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\n@Jh~\n')
+
+        _rewrite_fastq(file_in, file_out, quality_offset_in=64)
+
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), [('r1', 'ACGT', '!+I_')])
+
+    def test_rewrite_fastq_phred33_to_phred64(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\n!+I_\n')
+
+        _rewrite_fastq(file_in, file_out, quality_offset_out=64)
+
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), [('r1', 'ACGT', '@Jh~')])
+
+    @parameterized.expand([
+        (64, 33, '@r1\nACGT\n+\n5III\n', 'below the expected offset of 64'),
+        (33, 64, '@r1\nACGT\n+\nIII`\n', 'with the offset of 64'),
+    ])
+    def test_rewrite_fastq_quality_offset_error(
+            self, offset_in, offset_out, content, exp_reason
+    ):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write(content)
+
+        with self.assertRaisesRegex(
+                FastqValidationError, r'Record 1 \(read r1\) .* ' + exp_reason
+        ):
+            _rewrite_fastq(
+                file_in, file_out, quality_offset_in=offset_in,
+                quality_offset_out=offset_out
+            )
+
+    @parameterized.expand([(33, 33), (64, 33), (33, 64)])
+    def test_rewrite_fastq_bin_qualities(self, offset_in, offset_out):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        scores = [0, 1, 2, 9, 10, 19, 20, 24, 25, 29, 30, 34, 35, 39, 40, 41]
+        binned = [0, 1, 6, 6, 15, 15, 22, 22, 27, 27, 33, 33, 37, 37, 40, 40]
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\n' + 'A' * len(scores) + '\n+\n' + ''.join(
+                chr(x + offset_in) for x in scores
+            ) + '\n')
+
+        _rewrite_fastq(
+            file_in, file_out, bin_qualities=True,
+            quality_offset_in=offset_in, quality_offset_out=offset_out
+        )
+
+        with FastqReader(file_out) as reader:
+            (_, _, qual), = reader
+        self.assertEqual(qual, ''.join(chr(x + offset_out) for x in binned))
+
+    def test_rewrite_fastq_bin_qualities_after_filters(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            # scores of 2 would pass the filter only once binned to 6
+            fin.write('@r1\nAC\n+\n##\n@r2\nAC\n+\n++\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, bin_qualities=True, min_mean_quality=5
+        )
+
+        self.assertEqual(obs.dropped, {'mean_quality': 1})
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(fout.read(), '@r2\nAC\n+\n00\n')
+
+    def test_rewrite_fastq_quality_offset_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'either 33 or 64'):
+            _rewrite_fastq(file_in, file_out, quality_offset_in=59)
+
+    def test_rewrite_fastq_fill_quality(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n@r2\nAC\n+\n#I\n@r3\nACG\n')
+
+        obs = _rewrite_fastq(file_in, file_out, fill_quality=30)
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual(obs.filled_qualities, 2)
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(),
+                '@r1\nACGT\n+\n????\n@r2\nAC\n+\n#I\n@r3\nACG\n+\n???\n'
+            )
+
+    def test_rewrite_fastq_fill_quality_phred64(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n@r2\nAC\n+\nBh\n')
+
+        _rewrite_fastq(
+            file_in, file_out, quality_offset_in=64, fill_quality=30
+        )
+
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r1', 'ACGT', '????'), ('r2', 'AC', '#I')]
+            )
+
+    def test_rewrite_fastq_fasta(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fasta.gz')
+
+        obs = _rewrite_fastq(file_in, file_out, record_format='fasta')
+
+        self.assertEqual(obs.reads, 3)
+        self.assertEqual((obs.min_length, obs.max_length), (267, 278))
+        self.assertFalse(obs.uniform_quality)
+        with gzip.open(file_out, 'rt') as fout:
+            lines = fout.read().splitlines()
+        self.assertEqual(len(lines), 6)
+        self.assertEqual(
+            lines[0], '>test_acc_single.1 test_1_seq length=278'
+        )
+        self.assertEqual(len(lines[1]), 278)
+
+    def test_rewrite_fastq_record_format_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'either "fastq" or "fasta"'):
+            _rewrite_fastq(file_in, file_out, record_format='sam')
+
+    def test_rewrite_fastq_fasta_input(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fasta')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('>r1 desc\nACGT\nAC\n>r2\nA\n')
+
+        obs = _rewrite_fastq(file_in, file_out, fill_quality=40)
+
+        self.assertEqual((obs.reads, obs.filled_qualities), (2, 2))
+        with gzip.open(file_out, 'rt') as fout:
+            self.assertEqual(
+                fout.read(), '@r1 desc\nACGTAC\n+\nIIIIII\n@r2\nA\n+\nI\n'
+            )
+
+    def test_rewrite_fastq_fasta_input_no_fill(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fasta')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('>r1\nACGT\n')
+
+        with self.assertRaisesRegex(
+                FastqValidationError, 'header does not start with "@"'
+        ):
+            _rewrite_fastq(file_in, file_out, validate=True)
+
+    def test_rewrite_fastq_fill_quality_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'between 0 and 93'):
+            _rewrite_fastq(file_in, file_out, fill_quality=94)
+
+    def test_rewrite_fastq_uniform_quality(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\n????\n@r2\nACGTAC\n+\n??????\n')
+
+        with self.assertLogs('q2_fondue.rewrite', level='WARNING') as cm:
+            obs = _rewrite_fastq(
+                file_in, file_out, threads=2, compression_block_size=5
+            )
+
+        self.assertTrue(obs.uniform_quality)
+        self.assertEqual(obs.bases, 10)
+        self.assertIn('quality scores of sequences from', cm.output[0])
+        self.assertIn('are the same (as in SRA Lite runs)', cm.output[0])
+
+    def test_rewrite_fastq_non_uniform_quality(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\n????\n@r2\nACGT\n+\n???I\n')
+
+        obs = _rewrite_fastq(file_in, file_out)
+
+        self.assertFalse(obs.uniform_quality)
+
+    def test_rewrite_fastq_compression_level(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out_fast = os.path.join(self.temp_dir.name, 'fast.fastq.gz')
+        file_out_best = os.path.join(self.temp_dir.name, 'best.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out_fast, compression_level=1)
+        _rewrite_fastq(file_in, file_out_best, compression_level=9)
+
+        for file_out in (file_out_fast, file_out_best):
+            with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+                self.assertEqual(fin.read(), fout.read())
+        self.assertLessEqual(
+            os.path.getsize(file_out_best), os.path.getsize(file_out_fast)
+        )
+
+    @parameterized.expand([
+        ('gzip', 'gzip', 1), ('threads', 'gzip', 2), ('bgzf', 'bgzf', 2)
+    ])
+    @patch('q2_fondue.compression.BGZF_BLOCK_SIZE', 100)
+    def test_rewrite_fastq_compression_level_auto(self, name, fmt, threads):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with patch.object(
+                _AdaptiveLevel, 'update', autospec=True,
+                side_effect=_AdaptiveLevel.update
+        ) as mock_update:
+            _rewrite_fastq(
+                file_in, file_out, compression_level='auto',
+                threads=threads, output_format=fmt,
+                compression_block_size=100
+            )
+
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fin.read(), fout.read())
+        # the level is adjusted after every written block
+        self.assertGreater(mock_update.call_count, 1)
+
+    def test_rewrite_fastq_compression_level_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(
+                ValueError, r'between 0 and 9 or "auto" \(got 10\)'
+        ):
+            _rewrite_fastq(file_in, file_out, compression_level=10)
+        self.assertFalse(os.path.exists(file_out))
+
+    def test_rewrite_fastq_multithreaded(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        _rewrite_fastq(
+            file_in, file_out, threads=3, compression_block_size=1000
+        )
+
+        with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    def test_rewrite_fastq_multithreaded_empty(self):
+        file_in = os.path.join(self.temp_dir.name, 'empty.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'empty.fastq.gz')
+        open(file_in, 'w').close()
+
+        _rewrite_fastq(file_in, file_out, threads=2)
+
+        with gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fout.read(), b'')
+
+    @parameterized.expand([
+        ('gzip', 1, 'gzip'), ('threads', 2, 'gzip'), ('bgzf', 2, 'bgzf'),
+        ('parsed', 1, 'gzip', {'validate': True})
+    ])
+    def test_rewrite_fastq_checksums(
+            self, name, threads, output_format, kwargs={}
+    ):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        stats = _rewrite_fastq(
+            file_in, file_out, threads=threads, output_format=output_format,
+            checksums=['md5', 'sha256'], **kwargs
+        )
+
+        with open(file_out, 'rb') as fout:
+            content = fout.read()
+        self.assertDictEqual(
+            stats.checksums,
+            {'md5': hashlib.md5(content).hexdigest(),
+             'sha256': hashlib.sha256(content).hexdigest()}
+        )
+
+    def test_rewrite_fastq_checksums_none(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        stats = _rewrite_fastq(file_in, file_out)
+
+        self.assertDictEqual(stats.checksums, {})
+
+    def test_rewrite_fastq_checksums_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'algorithms: crc99'):
+            _rewrite_fastq(file_in, file_out, checksums=['md5', 'crc99'])
+
+    @parameterized.expand([
+        ('gzip', {}), ('parsed', {'validate': True, 'threads': 2}),
+        ('fasta', {'record_format': 'fasta'})
+    ])
+    def test_rewrite_fastq_verify(self, name, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        stats = _rewrite_fastq(file_in, file_out, verify=True, **kwargs)
+
+        self.assertEqual(stats.reads, 3)
+
+    @parameterized.expand([
+        ('records', b'@r1\nA\n+\nI\n', 'contains 1 records instead of 3'),
+        ('content', None, 'differs from the written data'),
+        ('corrupt', b'', 'could not be written'),
+    ])
+    def test_rewrite_fastq_verify_mismatch(self, name, content, msg):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        if content is None:
+            with open(file_in, 'rb') as fin:
+                content = fin.read().replace(b'AAAC', b'CCCA', 1)
+
+        def compress(f_in, file_out, *args):
+            while f_in.read(1024):
+                pass
+            with open(file_out, 'wb') as f_out:
+                # a corrupt file is cut off before the end of the stream
+                data = gzip.compress(content or b'@r1\nA\n+\nI\n')
+                f_out.write(data if content else data[:-4])
+            return len(data), {}
+
+        with patch('q2_fondue.rewrite._compress_stream', compress):
+            with self.assertRaisesRegex(FastqRewriteError, msg):
+                _rewrite_fastq(file_in, file_out, verify=True)
+
+    @parameterized.expand([
+        ('gzip', {}), ('threads', {'threads': 2}),
+        ('bgzf', {'output_format': 'bgzf'})
+    ])
+    def test_rewrite_fastq_reproducible(self, name, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out1, file_out2 = (
+            os.path.join(self.temp_dir.name, f'{x}.fastq.gz')
+            for x in ('out1', 'out2')
+        )
+
+        with patch('time.time', return_value=1000000000):
+            _rewrite_fastq(file_in, file_out1, **kwargs)
+        _rewrite_fastq(file_in, file_out2, **kwargs)
+
+        with open(file_out1, 'rb') as f1, open(file_out2, 'rb') as f2:
+            obs1, obs2 = f1.read(), f2.read()
+        self.assertEqual(obs1, obs2)
+        # neither a modification time nor a file name is stored
+        self.assertFalse(obs1[3] & 8)
+        self.assertEqual(obs1[4:8], b'\x00' * 4)
+
+    def test_rewrite_fastq_threads_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, r'at least 1 \(got 0\)'):
+            _rewrite_fastq(file_in, file_out, threads=0)
+
+    @patch('q2_fondue.rewrite._available_cores', return_value=3)
+    def test_rewrite_fastq_all_cores(self, mock_cores):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with patch('q2_fondue.compression._compress_blocks') as mock_compress:
+            _rewrite_fastq(file_in, file_out, threads=None)
+
+        self.assertEqual(mock_compress.call_args.args[2], 3)
+
+    def test_rewrite_fastq_compression_block_size(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        _rewrite_fastq(
+            file_in, file_out, threads=2, compression_block_size=100
+        )
+
+        with open(file_in, 'rb') as fin:
+            exp = fin.read()
+        with open(file_out, 'rb') as fout:
+            data = fout.read()
+        members = 0
+        while data:
+            decompressor = zlib.decompressobj(31)
+            self.assertEqual(
+                decompressor.decompress(data), exp[100 * members:][:100]
+            )
+            data = decompressor.unused_data
+            members += 1
+        self.assertEqual(members, -(-len(exp) // 100))
+
+    def test_rewrite_fastq_compression_block_size_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, r'at least 1 byte \(got 0'):
+            _rewrite_fastq(file_in, file_out, compression_block_size=0)
+
+    @patch('q2_fondue.compression.BGZF_BLOCK_SIZE', 1000)
+    def test_rewrite_fastq_bgzf(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out, threads=2, output_format='bgzf')
+
+        with open(file_in, 'rb') as fin:
+            exp_content = fin.read()
+        with open(file_out, 'rb') as fout:
+            obs_content = fout.read()
+        self.assertEqual(gzip.decompress(obs_content), exp_content)
+        # every block carries the BGZF extra field
+        self.assertEqual(obs_content[12:16], b'BC\x02\x00')
+        self.assertTrue(obs_content.endswith(
+            bytes.fromhex('1f8b08040000000000ff0600424302001b0003'
+                          '000000000000000000')
+        ))
+
+        # each indexed block can be decompressed on its own
+        self.assertFalse(os.path.exists(f'{file_out}.tmp.gzi'))
+        with open(f'{file_out}.gzi', 'rb') as findex:
+            n_entries, = struct.unpack('<Q', findex.read(8))
+            offsets = [
+                struct.unpack('<QQ', findex.read(16))
+                for _ in range(n_entries)
+            ]
+        self.assertEqual(n_entries, len(exp_content) // 1000)
+        for offset_out, offset_in in offsets:
+            self.assertEqual(offset_in % 1000, 0)
+            self.assertEqual(
+                gzip.decompress(obs_content[offset_out:]),
+                exp_content[offset_in:]
+            )
+
+    def test_rewrite_fastq_format_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, r'\(got "bzip2"\)'):
+            _rewrite_fastq(file_in, file_out, output_format='bzip2')
+
+    def test_rewrite_fastq_gzipped_input(self):
+        file_orig = self.get_data_path('SRR123456.fastq')
+        file_in = os.path.join(self.temp_dir.name, 'SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        # the extension should not matter
+        with open(file_orig, 'rb') as fin, gzip.open(file_in, 'wb') as fout:
+            fout.write(fin.read())
+
+        _rewrite_fastq(file_in, file_out)
+
+        with open(file_orig, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    @parameterized.expand([('bz2', bz2.open), ('xz', lzma.open)])
+    def test_rewrite_fastq_other_compressed_input(self, ext, opener):
+        file_orig = self.get_data_path('SRR123456.fastq')
+        file_in = os.path.join(self.temp_dir.name, f'SRR123456.fastq.{ext}')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        with open(file_orig, 'rb') as fin, opener(file_in, 'wb') as fout:
+            fout.write(fin.read())
+
+        _rewrite_fastq(file_in, file_out)
+
+        with open(file_orig, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    def test_rewrite_fastq_bgzf_input(self):
+        file_orig = self.get_data_path('SRR123456.fastq')
+        file_in = os.path.join(self.temp_dir.name, 'SRR123456.bgzf.gz')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        _rewrite_fastq(file_orig, file_in, output_format='bgzf')
+
+        _rewrite_fastq(file_in, file_out)
+
+        with open(file_orig, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+            self.assertEqual(fin.read(), fout.read())
+
+    def test_rewrite_fastq_truncated_gzipped_input(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456_out.fastq.gz')
+        _rewrite_fastq(self.get_data_path('SRR123456.fastq'), file_in)
+        with open(file_in, 'r+b') as fin:
+            fin.truncate(os.path.getsize(file_in) // 2)
+
+        with self.assertRaisesRegex(CompressionError, 'SRR123456.fastq.gz'):
+            _rewrite_fastq(file_in, file_out)
+
+    @parameterized.expand([('corrupt', 100), ('truncated', None)])
+    def test_rewrite_fastq_corrupt_bz2_input(self, name, corrupt_at):
+        file_in = os.path.join(self.temp_dir.name, 'SRR123456.fastq.bz2')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        with open(self.get_data_path('SRR123456.fastq'), 'rb') as fin:
+            data = bz2.compress(fin.read())
+        if corrupt_at is None:
+            data = data[:len(data) // 2]
+        else:
+            data = data[:corrupt_at] + bytes(16) + data[corrupt_at + 16:]
+        with open(file_in, 'wb') as fin:
+            fin.write(data)
+
+        with self.assertRaisesRegex(CompressionError, 'SRR123456.fastq.bz2'):
+            _rewrite_fastq(file_in, file_out)
+        self.assertFalse(os.path.exists(file_out))
+
+    def test_rewrite_fastq_zstd_input(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR123456.fastq.zst')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        with open(file_in, 'wb') as fin:
+            fin.write(b'\x28\xb5\x2f\xfd' + b'\x00' * 10)
+
+        with self.assertRaisesRegex(
+                FastqRewriteError, 'zstd-compressed, which is not supported'
+        ):
+            _rewrite_fastq(file_in, file_out)
+
+    def test_rewrite_fastq_missing_input(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+
+        with self.assertRaisesRegex(
+                FastqRewriteError, r'Sequences from .*SRR000000\.fastq could '
+                                   r'not be written to .*SRR000000\.fastq\.gz'
+        ):
+            _rewrite_fastq(file_in, file_out)
+
+    def test_rewrite_fastq_batch(self):
+        files_in = [
+            self.get_data_path(f'SRR123457_{i}.fastq') for i in (1, 2)
+        ]
+        files_out = [
+            os.path.join(self.temp_dir.name, f'SRR123457_{i}.fastq.gz')
+            for i in (1, 2)
+        ]
+
+        obs = _rewrite_fastq_batch(
+            list(zip(files_in, files_out)), n_threads=2
+        )
+
+        self.assertListEqual([x.reads for x in obs], [3, 3])
+        for file_in, file_out in zip(files_in, files_out):
+            with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
+                self.assertEqual(fin.read(), fout.read())
+
+    def test_rewrite_fastq_batch_with_error(self):
+        files_in = [
+            os.path.join(self.temp_dir.name, 'SRR000000.fastq'),
+            self.get_data_path('SRR123456.fastq')
+        ]
+        files_out = [
+            os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz'),
+            os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        ]
+
+        with self.assertRaisesRegex(FastqRewriteError, 'SRR000000'):
+            _rewrite_fastq_batch(list(zip(files_in, files_out)), n_threads=2)
+        # the remaining files should still be processed
+        self.assertTrue(os.path.isfile(files_out[1]))
+
+    @patch('shutil.disk_usage')
+    def test_rewrite_fastq_low_space(self, mock_usage):
+        mock_usage.return_value = MagicMock(free=100)
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(
+                InsufficientSpaceError, 'only 100 bytes are free'
+        ):
+            _rewrite_fastq(
+                self.get_data_path('SRR123456.fastq'), file_out,
+                min_free_space=500
+            )
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    @patch('shutil.disk_usage')
+    def test_rewrite_fastq_batch_low_space(self, mock_usage):
+        mock_usage.side_effect = [
+            MagicMock(free=1000),
+            MagicMock(free=100),
+        ]
+        pairs = [
+            (self.get_data_path(f'SRR123457_{i}.fastq'),
+             os.path.join(self.temp_dir.name, f'SRR123457_{i}.fastq.gz'))
+            for i in (1, 2)
+        ]
+
+        with self.assertRaises(InsufficientSpaceError) as cm:
+            _rewrite_fastq_batch(pairs, min_free_space=500)
+        self.assertListEqual(cm.exception.completed, [pairs[0][1]])
+        self.assertListEqual(
+            os.listdir(self.temp_dir.name), ['SRR123457_1.fastq.gz']
+        )
+
+    def test_rewrite_fastq_progress(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+        calls = []
+
+        obs = _rewrite_fastq(
+            file_in, file_out, progress=lambda *x: calls.append(x),
+            progress_interval=10
+        )
+
+        self.assertGreaterEqual(len(calls), 2)
+        self.assertListEqual(calls, sorted(calls))
+        self.assertTupleEqual(
+            calls[-1],
+            (obs.reads, os.path.getsize(file_in), obs.compressed_bytes)
+        )
+
+    def test_rewrite_fastq_debug_logging(self):
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertLogs('q2_fondue.rewrite', 'DEBUG') as cm:
+            _set_log_level('debug')
+            try:
+                _rewrite_fastq(self.get_data_path('SRR123456.fastq'), file_out)
+            finally:
+                _set_log_level('info')
+
+        self.assertRegex(cm.output[0], 'Rewriting .*SRR123456.fastq to')
+        self.assertRegex(cm.output[-1], r'Wrote \d+ reads')
+
+    def test_rewrite_fastq_cancelled(self):
+        cancel = CancellationToken()
+        cancel.cancel()
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(RewriteCancelledError, 'cancelled'):
+            _rewrite_fastq(
+                self.get_data_path('SRR123456.fastq'), file_out,
+                cancel=cancel
+            )
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    def test_rewrite_fastq_batch_interrupted(self):
+        pairs = [('a.fastq', 'a.fastq.gz'), ('b.fastq', 'b.fastq.gz')]
+        started = []
+
+        def _rewrite(file_in, file_out, options, cancel):
+            started.append(file_in)
+            while not cancel.cancelled:
+                time.sleep(0.01)
+            cancel.check()
+
+        def _interrupt(futures):
+            while not started:
+                time.sleep(0.01)
+            raise KeyboardInterrupt
+
+        with patch('q2_fondue.rewrite._rewrite_fastq', _rewrite), \
+                patch('q2_fondue.rewrite.wait', _interrupt):
+            with self.assertRaises(KeyboardInterrupt):
+                _rewrite_fastq_batch(pairs)
+
+        # the running rewrite is cancelled and the pending one never starts
+        self.assertListEqual(started, ['a.fastq'])
+
+    def test_rewrite_fastq_batch_multiqc_summary(self):
+        files_out = [
+            os.path.join(self.temp_dir.name, f'SRR123457_{i}.fastq.gz')
+            for i in (1, 2)
+        ]
+        pairs = [
+            (self.get_data_path(f'SRR123457_{i}.fastq'), files_out[i - 1])
+            for i in (1, 2)
+        ]
+        summary = os.path.join(self.temp_dir.name, 'fondue_mqc.json')
+
+        _rewrite_fastq_batch(
+            pairs, n_threads=2, multiqc_summary=summary, min_length=10
+        )
+
+        with open(summary) as fin:
+            obs = json.load(fin)
+        self.assertEqual(obs['plot_type'], 'table')
+        self.assertSetEqual(set(obs['data']), {'SRR123457_1', 'SRR123457_2'})
+        self.assertDictEqual(obs['data']['SRR123457_2'], {
+            'reads': 1, 'bases': 59, 'mean_length': 59.0, 'min_length': 59,
+            'max_length': 59, 'dropped_reads': 2,
+            'compressed_bytes': os.path.getsize(files_out[1])
+        })
+
+    def _write_reads(self, n):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        with open(file_in, 'w') as fin:
+            for i in range(n):
+                fin.write(f'@r{i}\nACGT\n+\nII#I\n')
+        return file_in
+
+    def test_rewrite_fastq_subsample_fraction(self):
+        file_in = self._write_reads(1000)
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, subsample_fraction=0.1, seed=42
+        )
+        with FastqReader(file_out) as reader:
+            obs_ids = [int(x[0][1:]) for x in reader]
+        obs_repeated = _rewrite_fastq(
+            file_in, file_out, subsample_fraction=0.1, seed=42,
+            overwrite=True
+        )
+        with FastqReader(file_out) as reader:
+            obs_ids_repeated = [int(x[0][1:]) for x in reader]
+
+        self.assertTrue(50 < obs.reads < 150)
+        self.assertDictEqual(obs.dropped, {'subsample': 1000 - obs.reads})
+        self.assertEqual(obs, obs_repeated)
+        self.assertListEqual(obs_ids, obs_ids_repeated)
+        self.assertListEqual(obs_ids, sorted(obs_ids))
+
+    @parameterized.expand([(3, 3, 7), (10, 10, 0), (20, 10, 0), (0, 0, 10)])
+    def test_rewrite_fastq_subsample_n(self, n, exp_reads, exp_dropped):
+        file_in = self._write_reads(10)
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+
+        obs = _rewrite_fastq(file_in, file_out, subsample_n=n, seed=1)
+
+        self.assertEqual(obs.reads, exp_reads)
+        self.assertEqual(obs.dropped.get('subsample', 0), exp_dropped)
+        with FastqReader(file_out) as reader:
+            obs_ids = [int(x[0][1:]) for x in reader]
+        self.assertListEqual(obs_ids, sorted(set(obs_ids)))
+
+    @parameterized.expand([
+        ({'subsample_fraction': 0.5, 'subsample_n': 1}, 'Only one of'),
+        ({'subsample_fraction': 0}, 'greater than 0 and at most 1'),
+        ({'subsample_fraction': 1.5}, 'greater than 0 and at most 1'),
+        ({'subsample_n': -1}, 'cannot be negative'),
+    ])
+    def test_rewrite_fastq_subsample_invalid(self, kwargs, exp_error):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, exp_error):
+            _rewrite_fastq(file_in, file_out, **kwargs)
+
+    @parameterized.expand([(1, 1), (8, 2), (9, 3), (1000, 10)])
+    def test_rewrite_fastq_target_bases(self, target_bases, exp_reads):
+        file_in = self._write_reads(10)
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+
+        obs = _rewrite_fastq(file_in, file_out, target_bases=target_bases)
+
+        self.assertEqual(obs.reads, exp_reads)
+        self.assertEqual(obs.bases, exp_reads * 4)
+
+    def test_rewrite_fastq_target_bases_subsampled(self):
+        file_in = self._write_reads(100)
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, subsample_n=20, seed=7, target_bases=40
+        )
+
+        self.assertEqual(obs.reads, 10)
+        with FastqReader(file_out) as reader:
+            obs_ids = [int(x[0][1:]) for x in reader]
+        self.assertNotEqual(obs_ids, list(range(10)))
+
+    def test_rewrite_fastq_target_bases_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'at least 1'):
+            _rewrite_fastq(file_in, file_out, target_bases=0)
+
+    @parameterized.expand([
+        ('sequence', ['r1', 'r3']), ('record', ['r1', 'r2', 'r3'])
+    ])
+    def test_rewrite_fastq_deduplicate(self, mode, exp_ids):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nII#I\n@r2\nACGT\n+\nIIII\n'
+                      '@r3\nACGG\n+\nII#I\n@r4\nACGT\n+\nII#I\n')
+
+        obs = _rewrite_fastq(file_in, file_out, deduplicate=mode)
+
+        self.assertEqual(obs.reads, len(exp_ids))
+        self.assertDictEqual(obs.dropped, {'deduplicate': 4 - len(exp_ids)})
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    def test_rewrite_fastq_deduplicate_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'either "sequence" or'):
+            _rewrite_fastq(file_in, file_out, deduplicate='id')
+
+    @parameterized.expand([
+        (1, None, ['r2', 'r3', 'r4']),
+        (None, 3, ['r1', 'r2', 'r3']),
+        (2, 3, ['r2', 'r3']),
+    ])
+    def test_rewrite_fastq_length_filter(self, min_len, max_len, exp_ids):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\n\n+\n\n@r2\nAC\n+\nI#\n'
+                      '@r3\nACG\n+\nI#I\n@r4\nACGT\n+\nI#II\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, min_length=min_len, max_length=max_len
+        )
+
+        self.assertDictEqual(obs.dropped, {'length': 4 - len(exp_ids)})
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    @parameterized.expand([(-1, None), (None, -1), (5, 4)])
+    def test_rewrite_fastq_length_filter_invalid(self, min_len, max_len):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'Length limits'):
+            _rewrite_fastq(
+                file_in, file_out, min_length=min_len, max_length=max_len
+            )
+
+    @parameterized.expand([
+        (0, ['r1']), (0.25, ['r1', 'r2']), (0.5, ['r1', 'r2', 'r3']),
+    ])
+    def test_rewrite_fastq_n_filter(self, max_n_fraction, exp_ids):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nI#II\n@r2\nACNT\n+\nI#II\n'
+                      '@r3\nAnG.\n+\nI#II\n@r4\nNNNT\n+\nI#II\n')
+
+        obs = _rewrite_fastq(file_in, file_out, max_n_fraction=max_n_fraction)
+
+        self.assertDictEqual(obs.dropped, {'n_content': 4 - len(exp_ids)})
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    def test_rewrite_fastq_n_filter_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'between 0 and 1'):
+            _rewrite_fastq(file_in, file_out, max_n_fraction=1.1)
+
+    @parameterized.expand([
+        (33, '??5I', '++', '!I', 20, ['r1', 'r3']),
+        (33, '??5I', '++', '!I', 20.5, ['r1']),
+        (64, '^^Th', 'JJ', '@h', 20, ['r1', 'r3']),
+    ])
+    def test_rewrite_fastq_mean_quality_filter(
+            self, offset_in, qual1, qual2, qual3, min_quality, exp_ids
+    ):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        # mean scores of 30, 10 and 20
+        with open(file_in, 'w') as fin:
+            fin.write(f'@r1\nACGT\n+\n{qual1}\n@r2\nAC\n+\n{qual2}\n'
+                      f'@r3\nAC\n+\n{qual3}\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, quality_offset_in=offset_in,
+            min_mean_quality=min_quality
+        )
+
+        self.assertDictEqual(
+            obs.dropped, {'mean_quality': 3 - len(exp_ids)}
+        )
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    @parameterized.expand([
+        # scores of 40, 40, 30, 10, 10, 40
+        ((2, 20), 'ACG'), ((2, 35), 'AC'), ((1, 41), ''),
+        ((3, 20), 'ACG'), ((6, 20), 'ACGTAC'), ((7, 20), 'ACGTAC'),
+    ])
+    def test_rewrite_fastq_sliding_window(self, window, exp_seq):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTAC\n+\nII?++I\n')
+
+        obs = _rewrite_fastq(file_in, file_out, sliding_window=window)
+
+        self.assertDictEqual(
+            obs.trimmed,
+            {'sliding_window': 6 - len(exp_seq)} if len(exp_seq) < 6 else {}
+        )
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r1', exp_seq, 'II?++I'[:len(exp_seq)])]
+            )
+
+    def test_rewrite_fastq_sliding_window_before_filters(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTAC\n+\nII?++I\n@r2\nACGTAC\n+\nIIIIII\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, sliding_window=(2, 20), min_length=4
+        )
+
+        self.assertEqual(obs.reads, 1)
+        self.assertDictEqual(obs.dropped, {'length': 1})
+        self.assertDictEqual(obs.trimmed, {'sliding_window': 3})
+
+    @parameterized.expand([(0.2, ['r1']), (1, ['r1', 'r2']), (0, [])])
+    def test_rewrite_fastq_expected_errors(self, max_errors, exp_ids):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        # 0.1 and 0.52 expected errors
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\n+III\n@r2\nACGT\n+\n++&I\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, max_expected_errors=max_errors
+        )
+
+        self.assertEqual(obs.reads, len(exp_ids))
+        self.assertEqual(
+            obs.dropped.get('expected_errors', 0), 2 - len(exp_ids)
+        )
+
+    def test_rewrite_fastq_expected_errors_out_of_range(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGT\n+\nhhhh\n@r2\nACGT\n+\n!hhh\n')
+
+        with self.assertRaisesRegex(
+                FastqParseError, r'Record 2 \(read r2\) in .* is invalid: '
+                                 r'quality string contains scores which '
+                                 r'cannot be decoded with the offset of 64'
+        ):
+            _rewrite_fastq(
+                file_in, file_out, max_expected_errors=1,
+                quality_offset_in=64, quality_offset_out=64
+            )
+        self.assertFalse(os.path.exists(file_out))
+
+    @parameterized.expand([
+        (2, 0, 'GTAC', '+#I!'), (0, 2, 'ACGT', 'II+#'), (1, 1, 'CGTA', 'I+#I'),
+        (4, 4, '', ''), (10, 0, '', ''),
+    ])
+    def test_rewrite_fastq_fixed_trim(self, left, right, exp_seq, exp_qual):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTAC\n+\nII+#I!\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, trim_left=left, trim_right=right
+        )
+
+        self.assertDictEqual(obs.trimmed, {'fixed_trim': 6 - len(exp_seq)})
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), [('r1', exp_seq, exp_qual)])
+
+    def test_rewrite_fastq_fixed_trim_invalid(self):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'cannot be negative'):
+            _rewrite_fastq(file_in, file_out, trim_right=-1)
+
+    @parameterized.expand([
+        (('G', 3), 'ACGTA'), (('G', 5), 'ACGTAGGgg'), (('A', 1), 'ACGTAGGgg'),
+    ])
+    def test_rewrite_fastq_trim_homopolymer(self, polymer, exp_seq):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTAGGgg\n+\nII#IIIIII\n')
+
+        obs = _rewrite_fastq(file_in, file_out, trim_homopolymer=polymer)
+
+        self.assertEqual(obs.bases, len(exp_seq))
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r1', exp_seq, 'II#IIIIII'[:len(exp_seq)])]
+            )
+
+    @parameterized.expand([(('GA', 3),), (('G', 0),)])
+    def test_rewrite_fastq_trim_homopolymer_invalid(self, polymer):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'single base'):
+            _rewrite_fastq(file_in, file_out, trim_homopolymer=polymer)
+
+    @parameterized.expand([
+        ('ACGTACGTAGATCGGAAGAGCACAC', 'ACGTACGT'),
+        ('ACGTACGTAGATCGGTAGAGCACAC', 'ACGTACGT'),
+        ('ACGTACGTAGTTCCGTAGAGCACAC', 'ACGTACGTAGTTCCGTAGAGCACAC'),
+        ('ACGTACGTACGTAGATCG', 'ACGTACGTACGT'),
+        ('ACGTACGTACGTACGTAG', 'ACGTACGTACGTACGTAG'),
+        ('acgtagatcggaagagccc', 'acgt'),
+        ('AGATCGGAAGAGC', ''),
+    ])
+    def test_rewrite_fastq_adapters(self, seq, exp_seq):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        qual = ('I#' * len(seq))[:len(seq)]
+        with open(file_in, 'w') as fin:
+            fin.write(f'@r1\n{seq}\n+\n{qual}\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, adapters=['CTGTAGGCACC', 'AGATCGGAAGAGC']
+        )
+
+        self.assertEqual(
+            obs.trimmed.get('adapter', 0), len(seq) - len(exp_seq)
+        )
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(
+                list(reader), [('r1', exp_seq, qual[:len(exp_seq)])]
+            )
+
+    def test_rewrite_fastq_adapters_earliest(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGATTTTTTCCCCCC\n+\nIIIIIIIIIIIIIII#\n')
+
+        obs = _rewrite_fastq(
+            file_in, file_out, adapters=['CCCCCC', 'TTTTTT'],
+            adapter_error_rate=0
+        )
+
+        self.assertEqual(obs.bases, 4)
+
+    @parameterized.expand([
+        ({'adapters': ['']},), ({'adapters': ['A'], 'adapter_error_rate': 1},),
+        ({'adapters': ['A'], 'adapter_min_overlap': 0},),
+    ])
+    def test_rewrite_fastq_adapters_invalid(self, kwargs):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, 'Adapters cannot be empty'):
+            _rewrite_fastq(file_in, file_out, **kwargs)
+
+    @parameterized.expand([
+        (0.1, ['r2', 'r3']), (0.5, ['r3']), (0, ['r1', 'r2', 'r3'])
+    ])
+    def test_rewrite_fastq_complexity_filter(self, min_complexity, exp_ids):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        seqs = ['A' * 20, 'AC' * 10, 'ACGTTGCAAGCTTAGCCGAT']
+        with open(file_in, 'w') as fin:
+            for i, seq in enumerate(seqs, 1):
+                fin.write(f'@r{i}\n{seq}\n+\n{"I#" * 10}\n')
+
+        obs = _rewrite_fastq(file_in, file_out, min_complexity=min_complexity)
+
+        self.assertEqual(
+            obs.dropped.get('complexity', 0), 3 - len(exp_ids)
+        )
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], exp_ids)
+
+    @parameterized.expand([
+        ({'umi_length': 3}, [('r1_ACG desc', 'TACGT', 'I#III'),
+                             ('r2_GGG', 'A', 'I')]),
+        ({'umi_length': 3, 'umi_format': 'rx'},
+         [('r1 desc RX:Z:ACG', 'TACGT', 'I#III'),
+          ('r2 RX:Z:GGG', 'A', 'I')]),
+        ({'umi_pattern': r'(.{3})TA'}, [('r1_ACG desc', 'CGT', 'III')]),
+        ({'umi_pattern': r'(GG)?[AG]'}, [('r2_GG', 'A', 'I')]),
+    ])
+    def test_rewrite_fastq_umi(self, kwargs, exp_records):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1 desc\nACGTACGT\n+\nII#I#III\n'
+                      '@r2\nGGGA\n+\nI##I\n')
+
+        obs = _rewrite_fastq(file_in, file_out, **kwargs)
+
+        self.assertEqual(obs.reads, len(exp_records))
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), exp_records)
+
+    def test_rewrite_fastq_umi_empty_id(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGTACGT\n+\nII#I#III\n@\nGGGA\n+\nI##I\n')
+
+        with self.assertRaisesRegex(
+                FastqParseError, 'Record 2 in .*SRR000000.fastq is invalid: '
+                                 'read ID is empty'
+        ):
+            _rewrite_fastq(file_in, file_out, umi_length=3)
+
+    @parameterized.expand([
+        ({'umi_length': 3, 'umi_pattern': '(A)'}, 'Only one of'),
+        ({'umi_length': 0}, 'at least 1'),
+        ({'umi_pattern': 'AC'}, 'in a group'),
+        ({'umi_length': 3, 'umi_format': 'tag'}, 'either "suffix" or "rx"'),
+    ])
+    def test_rewrite_fastq_umi_invalid(self, kwargs, exp_error):
+        file_in = self.get_data_path('SRR123456.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123456.fastq.gz')
+
+        with self.assertRaisesRegex(ValueError, exp_error):
+            _rewrite_fastq(file_in, file_out, **kwargs)
+
+    @parameterized.expand([
+        ('SRR1.1 M00123:45:000000000-A1B2C:1:1101:15589:1333 length=151',
+         'M00123:45:000000000-A1B2C:1:1101:15589:1333'),
+        ('SRR1.2 HWUSI-EAS100R:6:73:941:1973#0/1 length=36',
+         'HWUSI-EAS100R:6:73:941:1973#0/1'),
+        ('SRR1.3 A00123:8:H2:2:1101:1000:2000 1:N:0:ACGT length=4',
+         'A00123:8:H2:2:1101:1000:2000 1:N:0:ACGT'),
+        ('SRR1.4 4 length=4', 'SRR1.4 4 length=4'),
+        ('SRR1.5', 'SRR1.5'),
+    ])
+    def test_rewrite_fastq_restore_illumina_ids(self, read_id, exp_id):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write(f'@{read_id}\nACGT\n+\nI#II\n')
+
+        _rewrite_fastq(file_in, file_out, restore_illumina_ids=True)
+
+        with FastqReader(file_out) as reader:
+            self.assertListEqual([x[0] for x in reader], [exp_id])
+
+    def test_rewrite_fastq_stamp_accession(self):
+        file_in = self.get_data_path('SRR123457_1.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR123457_1.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out, stamp_accession=True)
+
+        with FastqReader(file_out) as reader:
+            obs_ids = [x[0] for x in reader]
+        self.assertEqual(
+            obs_ids[0], 'test_acc_single.1 test_1_seq length=278 run=SRR123457'
+        )
+        self.assertTrue(all(x.endswith(' run=SRR123457') for x in obs_ids))
+
+    @parameterized.expand([('stamp_accession',), ('renumber',)])
+    def test_rewrite_fastq_accession_from_stream(self, option):
+        file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+        fd = os.open(self.get_data_path('SRR123457_1.fastq'), os.O_RDONLY)
+        self.addCleanup(os.close, fd)
+
+        for file_in in ('-', fd):
+            with self.subTest(file_in=file_in), \
+                    patch('sys.stdin', io.TextIOWrapper(io.BytesIO())), \
+                    self.assertRaisesRegex(
+                        ValueError, 'No run accession can be derived'):
+                _rewrite_fastq(file_in, file_out, **{option: True})
+        self.assertFalse(os.path.exists(file_out))
+
+    def test_rewrite_fastq_renumber(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000_1.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000_1.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r 1\nACGT\n+\nI#II\n@r 1\nA\n+\nI\n'
+                      '@x\nACG\n+\nI#I\n')
+
+        obs = _rewrite_fastq(file_in, file_out, renumber=True, min_length=2)
+
+        self.assertEqual(obs.reads, 2)
+        with FastqReader(file_out) as reader:
+            self.assertListEqual(list(reader), [
+                ('SRR000000.1', 'ACGT', 'I#II'), ('SRR000000.2', 'ACG', 'I#I')
+            ])
+
+    def test_rewrite_fastq_anonymize(self):
+        obs_ids = []
+        for i, salt in ((1, 'secret'), (2, 'secret'), (1, 'other')):
+            file_in = os.path.join(self.temp_dir.name, f'in{i}.fastq')
+            with open(file_in, 'w') as fin:
+                fin.write(f'@r1/{i} M001:1:FC:1:1:1:1\nACGT\n+\nI#II\n'
+                          f'@r2/{i}\nA\n+\nI\n')
+            file_out = os.path.join(self.temp_dir.name, 'out.fastq.gz')
+            _rewrite_fastq(
+                file_in, file_out, anonymize_salt=salt, overwrite=True
+            )
+            with FastqReader(file_out) as reader:
+                obs_ids.append([x[0] for x in reader])
+
+        fwd, rev, other = obs_ids
+        self.assertTrue(all(re.match(r'^[0-9a-f]{32}/1$', x) for x in fwd))
+        self.assertListEqual([x[:-2] for x in fwd], [x[:-2] for x in rev])
+        self.assertListEqual([x[-2:] for x in rev], ['/2', '/2'])
+        self.assertNotEqual(fwd[0], fwd[1])
+        self.assertNotEqual(fwd[0], other[0])
+
+    def test_rewrite_fastq_qc_report(self):
+        file_in = os.path.join(self.temp_dir.name, 'SRR000000.fastq')
+        file_out = os.path.join(self.temp_dir.name, 'SRR000000.fastq.gz')
+        with open(file_in, 'w') as fin:
+            fin.write('@r1\nACGN\n+\n!!!!\n@r2\nACGN\n+\nIIII\n'
+                      '@r3\nAC\n+\n++\n@r4\nACGTACGT\n+\n++++++++\n')
+
+        _rewrite_fastq(file_in, file_out, qc_report=True)
+
+        with open(f'{file_out}.qc.json') as fin:
+            obs = json.load(fin)
+        self.assertDictEqual(obs, {
+            'reads': 4,
+            'bases': 18,
+            'mean_quality': 260 / 18,
+            'length_quantiles': {
+                '0': 2, '0.25': 2, '0.5': 4, '0.75': 4, '1': 8
+            },
+            'n_percent': 100 * 2 / 18,
+            'gc_percent': 50.0,
+            'gc_percent_quantiles': {
+                '0': 50, '0.25': 50, '0.5': 50, '0.75': 50, '1': 50
+            },
+            'duplication_rate': 0.25,
+        })
+
+    def test_rewrite_fastq_qc_report_empty(self):
+        file_in = self.get_data_path('empty/xxx_00_L001_R1_001.fastq.gz')
+        file_out = os.path.join(self.temp_dir.name, 'empty.fastq.gz')
+
+        _rewrite_fastq(file_in, file_out, qc_report=True)
+
+        with open(f'{file_out}.qc.json') as fin:
+            obs = json.load(fin)
+        self.assertEqual(obs['reads'], 0)
+        self.assertIsNone(obs['mean_quality'])
+        self.assertIsNone(obs['length_quantiles'])
+
+    def test_rewrite_to_casava(self):
+        obs_path, obs_stats = _rewrite_to_casava(
+            self.get_data_path('SRR123457_2.fastq'), self.temp_dir.name,
+            'SRR123457', read=2, paired=True, compression_level=1
+        )
+
+        self.assertEqual(
+            obs_path, os.path.join(
+                self.temp_dir.name, 'SRR123457_00_L001_R2_001.fastq.gz'
+            )
+        )
+        self.assertEqual(obs_stats.reads, 3)
+        self.assertTrue(os.path.isfile(obs_path))
+
+    def test_rewrite_to_casava_invalid_read(self):
+        with self.assertRaisesRegex(ValueError, 'either 1 or 2'):
+            _rewrite_to_casava(
+                self.get_data_path('SRR123456.fastq'), self.temp_dir.name,
+                'SRR123456', read=3
+            )
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -10,7 +10,6 @@ from multiprocessing import Queue, Manager
 import gzip
 import itertools
 import logging
-import filecmp
 import os
 import pandas as pd
 import shutil
@@ -28,13 +27,41 @@ from q2_fondue.sequences import (
     get_sequences, _run_fasterq_dump_for_all, _process_downloaded_sequences,
     _write_empty_casava, combine_seqs, _write2casava_dir, _announce_completion
 )
-from q2_fondue.utils import DownloadError
+from q2_fondue.fastq import FastqGzWriter
+from q2_fondue.utils import DownloadError, FastqStats
+
+# statistics returned by _dump_and_compress for a run with reads
+DUMPED = {'testaccA.fastq.gz': FastqStats(reads=1)}
 
 
 class MockTempDir(tempfile.TemporaryDirectory):
     pass
 
 
+class SyncProcess:
+    """Runs its target in the current process once started."""
+    def __init__(self, target, args, daemon=False):
+        self.target, self.args = target, args
+
+    def start(self):
+        self.target(*self.args)
+
+    def join(self):
+        pass
+
+
+class SyncPool:
+    """Runs a single worker in the current process once closed."""
+    def __init__(self, processes, initializer, initargs):
+        self.initializer, self.initargs = initializer, initargs
+
+    def close(self):
+        self.initializer(*self.initargs)
+
+    def join(self):
+        pass
+
+
 class SequenceTests(TestPluginBase):
     # class is inspired by class SubsampleTest in
     # q2_demux.tests.test_subsample
@@ -48,11 +75,19 @@ class SequenceTests(TestPluginBase):
         super().setUp()
         self.fetched_q = Queue()
         self.manager = Manager()
+        self.addCleanup(self.manager.shutdown)
         self.renamed_q = self.manager.Queue()
         self.processed_q = self.manager.Queue()
 
-    def move_files_2_tmp_dir(self, ls_files):
+    def make_tmp_dir(self):
         test_temp_dir = MockTempDir()
+        # remove it once the test is done - left for garbage collection,
+        # it could be removed through the patched rmtree of a later test
+        self.addCleanup(test_temp_dir.cleanup)
+        return test_temp_dir
+
+    def move_files_2_tmp_dir(self, ls_files):
+        test_temp_dir = self.make_tmp_dir()
 
         for file in ls_files:
             path_seq_single = self.get_data_path(file)
@@ -64,6 +99,17 @@ class SequenceTests(TestPluginBase):
 
         return test_temp_dir
 
+    def compress_files_2_tmp_dir(self, ls_files):
+        test_temp_dir = self.make_tmp_dir()
+
+        for file in ls_files:
+            path_out = os.path.join(test_temp_dir.name, f'{file}.gz')
+            with open(self.get_data_path(file), 'rb') as fin, \
+                    gzip.open(path_out, 'wb') as fout:
+                shutil.copyfileobj(fin, fout)
+
+        return test_temp_dir
+
     def _validate_sequences_in_samples(self, read_output):
         nb_obs_samples = 0
         ls_seq_length = []
@@ -100,79 +146,72 @@ class SequenceTests(TestPluginBase):
 
 class TestUtils4SequenceFetching(SequenceTests):
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('os.remove')
-    @patch('subprocess.run', return_value=MagicMock(returncode=0))
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_cmd_fasterq_sra_file(
-            self, mock_space_check, mock_subprocess, mock_rm
+            self, mock_space_check, mock_dump, mock_rm, mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq',
                                                    'testaccA.sra'])
 
         ls_acc_ids = ['testaccA']
-        exp_prefetch = [
-            'prefetch', '-X', 'u', '-O', ls_acc_ids[0], ls_acc_ids[0]
-        ]
-        exp_fasterq = [
-            'fasterq-dump', '-e', str(6), '--size-check', 'on', '-x',
-            ls_acc_ids[0]
-        ]
 
         _run_fasterq_dump_for_all(
             ls_acc_ids, test_temp_dir.name, threads=6, key_file='',
             retries=0, fetched_queue=self.fetched_q,
             done_queue=self.processed_q
         )
-        mock_subprocess.assert_has_calls([
-            call(exp_prefetch, text=True,
-                 capture_output=True, cwd=test_temp_dir.name),
-            call(exp_fasterq, text=True,
-                 capture_output=True, cwd=test_temp_dir.name)
-        ])
+        mock_prefetch.assert_called_once_with(
+            ls_acc_ids[0], test_temp_dir.name
+        )
+        mock_dump.assert_called_once_with(
+            ls_acc_ids[0], os.path.join(test_temp_dir.name, 'testaccA.sra'),
+            test_temp_dir.name, 6, '', min_free_space=None
+        )
         mock_rm.assert_called_with(
             os.path.join(test_temp_dir.name, ls_acc_ids[0] + '.sra')
         )
         mock_space_check.assert_not_called()
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('shutil.rmtree')
-    @patch('subprocess.run', return_value=MagicMock(returncode=0))
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_cmd_fasterq_sra_directory(
-            self, mock_space_check, mock_subprocess, mock_rm
+            self, mock_space_check, mock_dump, mock_rm, mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq'])
         os.makedirs(f'{test_temp_dir.name}/testaccA')
 
         ls_acc_ids = ['testaccA']
-        exp_prefetch = [
-            'prefetch', '-X', 'u', '-O', ls_acc_ids[0], ls_acc_ids[0]
-        ]
-        exp_fasterq = [
-            'fasterq-dump', '-e', str(6), '--size-check', 'on', '-x',
-            ls_acc_ids[0]
-        ]
 
         _run_fasterq_dump_for_all(
             ls_acc_ids, test_temp_dir.name, threads=6, key_file='',
             retries=0, fetched_queue=self.fetched_q,
-            done_queue=self.processed_q
+            done_queue=self.processed_q, min_free_space=500
+        )
+        mock_prefetch.assert_called_once_with(
+            ls_acc_ids[0], test_temp_dir.name
+        )
+        mock_dump.assert_called_once_with(
+            ls_acc_ids[0], os.path.join(test_temp_dir.name, 'testaccA'),
+            test_temp_dir.name, 6, '', min_free_space=500
         )
-        mock_subprocess.assert_has_calls([
-            call(exp_prefetch, text=True,
-                 capture_output=True, cwd=test_temp_dir.name),
-            call(exp_fasterq, text=True,
-                 capture_output=True, cwd=test_temp_dir.name)
-        ])
         mock_rm.assert_called_with(
             os.path.join(test_temp_dir.name, ls_acc_ids[0])
         )
         mock_space_check.assert_not_called()
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('shutil.rmtree')
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('subprocess.run', return_value=MagicMock(returncode=0))
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_cmd_fasterq_with_restricted_key(
-            self, mock_space_check, mock_subprocess, mock_rm
+            self, mock_space_check, mock_subprocess, mock_dump, mock_rm,
+            mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq'])
         os.makedirs(f'{test_temp_dir.name}/testaccA')
@@ -183,43 +222,36 @@ class TestUtils4SequenceFetching(SequenceTests):
             'prefetch', '-X', 'u', '-O', ls_acc_ids[0], '--ngc', key,
             ls_acc_ids[0]
         ]
-        exp_fasterq = [
-            'fasterq-dump', '-e', str(6), '--size-check', 'on', '-x',
-            '--ngc', key, ls_acc_ids[0]
-        ]
 
         _run_fasterq_dump_for_all(
             ls_acc_ids, test_temp_dir.name, threads=6, key_file=key,
             retries=0, fetched_queue=self.fetched_q,
             done_queue=self.processed_q
         )
-        mock_subprocess.assert_has_calls([
-            call(exp_prefetch, text=True,
-                 capture_output=True, cwd=test_temp_dir.name),
-            call(exp_fasterq, text=True,
-                 capture_output=True, cwd=test_temp_dir.name)
-        ])
+        mock_subprocess.assert_called_once_with(
+            exp_prefetch, text=True, capture_output=True,
+            cwd=test_temp_dir.name
+        )
+        mock_dump.assert_called_once_with(
+            ls_acc_ids[0], os.path.join(test_temp_dir.name, 'testaccA'),
+            test_temp_dir.name, 6, key, min_free_space=None
+        )
         mock_rm.assert_called_with(
             os.path.join(test_temp_dir.name, ls_acc_ids[0])
         )
         mock_space_check.assert_not_called()
+        mock_prefetch.assert_not_called()
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('os.remove')
-    @patch('subprocess.run', return_value=MagicMock(returncode=0))
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_fasterq_dump_for_all(
-            self, mock_space_check, mock_subprocess, mock_rm
+            self, mock_space_check, mock_dump, mock_rm, mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq',
                                                    'testaccA.sra'])
         ls_acc_ids = ['testaccA']
-        exp_prefetch = [
-            'prefetch', '-X', 'u', '-O', ls_acc_ids[0], ls_acc_ids[0]
-        ]
-        exp_fasterq = [
-            'fasterq-dump', '-e', str(6), '--size-check', 'on', '-x',
-            ls_acc_ids[0]
-        ]
 
         with self.assertLogs('q2_fondue.sequences', level='INFO') as cm:
             _run_fasterq_dump_for_all(
@@ -227,12 +259,14 @@ class TestUtils4SequenceFetching(SequenceTests):
                 retries=0, fetched_queue=self.fetched_q,
                 done_queue=self.processed_q
             )
-            mock_subprocess.assert_has_calls([
-                call(exp_prefetch, text=True,
-                     capture_output=True, cwd=test_temp_dir.name),
-                call(exp_fasterq, text=True,
-                     capture_output=True, cwd=test_temp_dir.name)
-            ])
+            mock_prefetch.assert_called_once_with(
+                ls_acc_ids[0], test_temp_dir.name
+            )
+            mock_dump.assert_called_once_with(
+                ls_acc_ids[0],
+                os.path.join(test_temp_dir.name, 'testaccA.sra'),
+                test_temp_dir.name, 6, '', min_free_space=None
+            )
             mock_rm.assert_called_with(
                 os.path.join(test_temp_dir.name, ls_acc_ids[0] + '.sra')
             )
@@ -243,14 +277,120 @@ class TestUtils4SequenceFetching(SequenceTests):
             obs_failed = self.processed_q.get()
             self.assertDictEqual(obs_failed, {'failed_ids': {}})
 
+    @patch('q2_fondue.sequences._fetch_from_ena')
+    @patch('q2_fondue.sequences._prefetch')
+    @patch('q2_fondue.sequences._dump_and_compress')
+    def test_run_fasterq_dump_for_all_from_ena(
+            self, mock_dump, mock_prefetch, mock_ena
+    ):
+        test_temp_dir = self.make_tmp_dir()
+        ls_acc_ids = ['testaccA']
+        mock_ena.return_value = [os.path.join(
+            test_temp_dir.name, 'testaccA_01_L001_R1_001.fastq.gz'
+        )]
+
+        _run_fasterq_dump_for_all(
+            ls_acc_ids, test_temp_dir.name, threads=6, key_file='',
+            retries=0, fetched_queue=self.fetched_q,
+            done_queue=self.processed_q, prefer_ena=True
+        )
+
+        mock_ena.assert_called_once_with(ls_acc_ids[0], test_temp_dir.name)
+        mock_prefetch.assert_not_called()
+        mock_dump.assert_not_called()
+        self.assertEqual(self.fetched_q.get(), ls_acc_ids[0])
+        self.assertDictEqual(self.processed_q.get(), {'failed_ids': {}})
+
+    @parameterized.expand([
+        ('not_mirrored', []),
+        ('failed', DownloadError('Run testaccA could not be located'))
+    ])
+    @patch('q2_fondue.sequences._fetch_from_ena')
+    @patch('q2_fondue.sequences._prefetch')
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
+    def test_run_fasterq_dump_for_all_from_ena_fallback(
+            self, name, ena_result, mock_dump, mock_prefetch, mock_ena
+    ):
+        test_temp_dir = self.make_tmp_dir()
+        ls_acc_ids = ['testaccA']
+        if isinstance(ena_result, Exception):
+            mock_ena.side_effect = ena_result
+        else:
+            mock_ena.return_value = ena_result
+
+        _run_fasterq_dump_for_all(
+            ls_acc_ids, test_temp_dir.name, threads=6, key_file='',
+            retries=0, fetched_queue=self.fetched_q,
+            done_queue=self.processed_q, prefer_ena=True
+        )
+
+        mock_ena.assert_called_once_with(ls_acc_ids[0], test_temp_dir.name)
+        mock_prefetch.assert_called_once_with(
+            ls_acc_ids[0], test_temp_dir.name
+        )
+        mock_dump.assert_called_once()
+        self.assertEqual(self.fetched_q.get(), ls_acc_ids[0])
+
+    @patch('q2_fondue.sequences._fetch_from_ena')
+    @patch('subprocess.run', return_value=MagicMock(returncode=0))
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
+    def test_run_fasterq_dump_for_all_from_ena_restricted(
+            self, mock_dump, mock_subprocess, mock_ena
+    ):
+        test_temp_dir = self.make_tmp_dir()
+
+        _run_fasterq_dump_for_all(
+            ['testaccA'], test_temp_dir.name, threads=6,
+            key_file='mykey.ngc', retries=0, fetched_queue=self.fetched_q,
+            done_queue=self.processed_q, prefer_ena=True
+        )
+
+        # restricted runs are never mirrored on ENA
+        mock_ena.assert_not_called()
+        mock_dump.assert_called_once()
+
+    @parameterized.expand([
+        ('converted', None, {}),
+        ('failed', DownloadError('SRR123.bam does not contain any reads.'),
+         {'testaccA': 'SRR123.bam does not contain any reads.'})
+    ])
+    @patch('q2_fondue.sequences._fetch_from_ena')
+    @patch('q2_fondue.sequences._prefetch')
+    @patch('q2_fondue.sequences._dump_and_compress', return_value={})
+    def test_run_fasterq_dump_for_all_no_reads(
+            self, name, ena_error, exp_failed, mock_dump, mock_prefetch,
+            mock_ena
+    ):
+        test_temp_dir = self.make_tmp_dir()
+        mock_ena.side_effect = ena_error
+
+        with self.assertLogs('q2_fondue.sequences', level='WARNING') as cm:
+            _run_fasterq_dump_for_all(
+                ['testaccA'], test_temp_dir.name, threads=6, key_file='',
+                retries=0, fetched_queue=self.fetched_q,
+                done_queue=self.processed_q
+            )
+
+        # runs submitted as BAM files only are converted from ENA
+        mock_ena.assert_called_once_with('testaccA', test_temp_dir.name)
+        self.assertIn(
+            'WARNING:q2_fondue.sequences:fasterq-dump found no reads for '
+            'testaccA - fetching its submitted files from ENA instead.',
+            cm.output
+        )
+        self.assertDictEqual(
+            self.processed_q.get(), {'failed_ids': exp_failed}
+        )
+
+    @patch('q2_fondue.sequences._prefetch')
     @patch('time.sleep')
-    @patch('subprocess.run',
-           return_value=MagicMock(stderr='Some error', returncode=1))
+    @patch('q2_fondue.sequences._dump_and_compress',
+           side_effect=DownloadError('Some error'))
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_fasterq_dump_for_all_error(
-            self, mock_space_check, mock_subprocess, mock_sleep
+            self, mock_space_check, mock_dump, mock_sleep, mock_prefetch
     ):
-        test_temp_dir = MockTempDir()
+        test_temp_dir = self.make_tmp_dir()
         ls_acc_ids = ['test_accERROR']
 
         with self.assertLogs('q2_fondue.sequences', level='INFO') as cm:
@@ -260,7 +400,7 @@ class TestUtils4SequenceFetching(SequenceTests):
                 done_queue=self.processed_q
             )
             # check retry procedure:
-            self.assertEqual(mock_subprocess.call_count, 2)
+            self.assertEqual(mock_dump.call_count, 2)
             mock_space_check.assert_not_called()
             self.assertIn(
                 'INFO:q2_fondue.sequences:Download finished. 1 out of 1 '
@@ -273,20 +413,82 @@ class TestUtils4SequenceFetching(SequenceTests):
                 obs_failed, {'failed_ids': {'test_accERROR': 'Some error'}}
             )
 
+    @patch('q2_fondue.sequences._prefetch')
+    @patch('subprocess.run', return_value=MagicMock(returncode=0))
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
+    def test_run_fasterq_dump_for_all_prefetch_fallback(
+            self, mock_dump, mock_subprocess, mock_prefetch
+    ):
+        test_temp_dir = self.make_tmp_dir()
+        mock_prefetch.side_effect = DownloadError(
+            'Run testaccA is only available from locations which require '
+            'payment or a cloud environment.'
+        )
+
+        with self.assertLogs('q2_fondue.sequences', level='WARNING') as cm:
+            _run_fasterq_dump_for_all(
+                ['testaccA'], test_temp_dir.name, threads=6, key_file='',
+                retries=0, fetched_queue=self.fetched_q,
+                done_queue=self.processed_q
+            )
+
+        mock_subprocess.assert_called_once_with(
+            ['prefetch', '-X', 'u', '-O', 'testaccA', 'testaccA'],
+            text=True, capture_output=True, cwd=test_temp_dir.name
+        )
+        mock_dump.assert_called_once()
+        self.assertTrue(cm.output[0].startswith(
+            'WARNING:q2_fondue.sequences:Run testaccA could not be '
+            'downloaded'
+        ))
+        self.assertDictEqual(self.processed_q.get(), {'failed_ids': {}})
+
+    @patch('q2_fondue.sequences._prefetch')
+    @patch('subprocess.run', side_effect=FileNotFoundError('prefetch'))
+    @patch('time.sleep')
+    @patch('q2_fondue.sequences._dump_and_compress')
+    @patch('q2_fondue.sequences._has_enough_space', return_value=True)
+    def test_run_fasterq_dump_for_all_prefetch_error(
+            self, mock_space_check, mock_dump, mock_sleep, mock_subprocess,
+            mock_prefetch
+    ):
+        test_temp_dir = self.make_tmp_dir()
+        ls_acc_ids = ['test_accERROR']
+        mock_prefetch.side_effect = DownloadError(
+            'Run test_accERROR could not be located: HTTP Error 404'
+        )
+
+        _run_fasterq_dump_for_all(
+            ls_acc_ids, test_temp_dir.name, threads=6, key_file='',
+            retries=1, fetched_queue=self.fetched_q,
+            done_queue=self.processed_q
+        )
+
+        self.assertEqual(mock_prefetch.call_count, 2)
+        self.assertEqual(mock_subprocess.call_count, 2)
+        mock_dump.assert_not_called()
+        obs_failed = self.processed_q.get()
+        self.assertDictEqual(
+            obs_failed, {'failed_ids': {
+                'test_accERROR':
+                    'Run test_accERROR could not be located: HTTP Error 404'
+            }}
+        )
+
+    @patch('q2_fondue.sequences._prefetch')
     @patch('os.remove')
     @patch('time.sleep')
-    @patch('subprocess.run')
+    @patch('q2_fondue.sequences._dump_and_compress')
     @patch('q2_fondue.sequences._has_enough_space', return_value=True)
     def test_run_fasterq_dump_for_all_error_twoids(
-            self, mock_space_check, mock_subprocess, mock_sleep, mock_rm
+            self, mock_space_check, mock_dump, mock_sleep, mock_rm,
+            mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq',
                                                    'testaccA.sra'])
         ls_acc_ids = ['testaccA', 'testaccERROR']
-        mock_subprocess.side_effect = [
-            MagicMock(returncode=0), MagicMock(returncode=0),
-            MagicMock(returncode=1, stderr='Error 1'),
-            MagicMock(returncode=1, stderr='Error 2')
+        mock_dump.side_effect = [
+            DUMPED, DownloadError('Error 1'), DownloadError('Error 2')
         ]
 
         with self.assertLogs('q2_fondue.sequences', level='INFO') as cm:
@@ -296,7 +498,7 @@ class TestUtils4SequenceFetching(SequenceTests):
                 done_queue=self.processed_q
             )
             # check retry procedure:
-            self.assertEqual(mock_subprocess.call_count, 4)
+            self.assertEqual(mock_dump.call_count, 3)
             self.assertIn(
                 'INFO:q2_fondue.sequences:Download finished. 1 out of 2 runs '
                 'failed to fetch. Below are the error messages of the first '
@@ -312,15 +514,17 @@ class TestUtils4SequenceFetching(SequenceTests):
                 obs_failed, {'failed_ids': {'testaccERROR': 'Error 2'}}
             )
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('shutil.rmtree')
     @patch('shutil.disk_usage', side_effect=[(0, 0, 10), (0, 0, 2)])
-    @patch('subprocess.run', side_effect=[MagicMock(returncode=0)] * 2)
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('q2_fondue.sequences._has_enough_space', return_value=False)
     def test_run_fasterq_dump_for_all_space_error(
-            self, mock_space_check, mock_subprocess, mock_disk_usage, mock_rm
+            self, mock_space_check, mock_dump, mock_disk_usage, mock_rm,
+            mock_prefetch
     ):
         # test checking that space availability break procedure works
-        test_temp_dir = MockTempDir()
+        test_temp_dir = self.make_tmp_dir()
         os.makedirs(f'{test_temp_dir.name}/testaccA')
         ls_acc_ids = ['testaccA', 'testaccERROR']
 
@@ -330,7 +534,7 @@ class TestUtils4SequenceFetching(SequenceTests):
                 retries=2, fetched_queue=self.fetched_q,
                 done_queue=self.processed_q
             )
-            self.assertEqual(mock_subprocess.call_count, 2)
+            self.assertEqual(mock_dump.call_count, 1)
             self.assertEqual(mock_disk_usage.call_count, 2)
             self.assertIn(
                 'INFO:q2_fondue.sequences:Download finished. 1 out of 2 runs '
@@ -350,16 +554,18 @@ class TestUtils4SequenceFetching(SequenceTests):
                 {'failed_ids': {'testaccERROR': 'Storage exhausted.'}}
             )
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('shutil.rmtree')
     @patch('shutil.disk_usage', side_effect=[(0, 0, 10), (0, 0, 2)])
-    @patch('subprocess.run', side_effect=[MagicMock(returncode=0)] * 2)
+    @patch('q2_fondue.sequences._dump_and_compress', return_value=DUMPED)
     @patch('q2_fondue.sequences._has_enough_space', return_value=False)
     def test_run_fasterq_dump_for_all_no_last_space_error(
-            self, mock_space_check, mock_subprocess, mock_disk_usage, mock_rm
+            self, mock_space_check, mock_dump, mock_disk_usage, mock_rm,
+            mock_prefetch
     ):
         # test checking that space availability break procedure does not cause
         # issues when triggered after last run ID
-        test_temp_dir = MockTempDir()
+        test_temp_dir = self.make_tmp_dir()
         os.makedirs(f'{test_temp_dir.name}/testaccA')
         ls_acc_ids = ['testaccA']
 
@@ -369,7 +575,7 @@ class TestUtils4SequenceFetching(SequenceTests):
                 retries=2, fetched_queue=self.fetched_q,
                 done_queue=self.processed_q
             )
-            self.assertEqual(mock_subprocess.call_count, 2)
+            self.assertEqual(mock_dump.call_count, 1)
             self.assertEqual(mock_disk_usage.call_count, 2)
             self.assertIn(
                 'INFO:q2_fondue.sequences:Download finished.', cm.output
@@ -381,26 +587,23 @@ class TestUtils4SequenceFetching(SequenceTests):
             obs_failed = self.processed_q.get()
             self.assertDictEqual(obs_failed, {'failed_ids': {}})
 
+    @patch('q2_fondue.sequences._prefetch')
     @patch('shutil.rmtree')
     @patch('os.remove')
     @patch('shutil.disk_usage')
     @patch('time.sleep')
-    @patch('subprocess.run')
+    @patch('q2_fondue.sequences._dump_and_compress')
     @patch('q2_fondue.sequences._has_enough_space', return_value=False)
     def test_run_fasterq_dump_for_all_error_and_storage_exhausted(
-            self, mock_space_check, mock_subprocess, mock_sleep,
-            mock_disk_usage, mock_rm, mock_rmtree
+            self, mock_space_check, mock_dump, mock_sleep,
+            mock_disk_usage, mock_rm, mock_rmtree, mock_prefetch
     ):
         test_temp_dir = self.move_files_2_tmp_dir(['testaccA.fastq',
                                                    'testaccA.sra'])
         os.makedirs(f'{test_temp_dir.name}/testaccF')
 
         ls_acc_ids = ['testaccA', 'testaccERROR', 'testaccF', 'testaccNOSPACE']
-        mock_subprocess.side_effect = [
-            MagicMock(returncode=0), MagicMock(returncode=0),
-            MagicMock(returncode=1, stderr='Error 1'),
-            MagicMock(returncode=0), MagicMock(returncode=0)
-        ]
+        mock_dump.side_effect = [DUMPED, DownloadError('Error 1'), DUMPED]
         mock_disk_usage.side_effect = [
             (0, 0, 10), (0, 0, 10), (0, 0, 10), (0, 0, 2)
         ]
@@ -412,7 +615,7 @@ class TestUtils4SequenceFetching(SequenceTests):
                 done_queue=self.processed_q
             )
             # check retry procedure:
-            self.assertEqual(mock_subprocess.call_count, 5)
+            self.assertEqual(mock_dump.call_count, 3)
             self.assertIn(
                 'INFO:q2_fondue.sequences:Download finished. 2 out of 4 runs '
                 'failed to fetch. Below are the error messages of the first '
@@ -438,7 +641,9 @@ class TestUtils4SequenceFetching(SequenceTests):
 
     def test_process_downloaded_sequences(self):
         ids = ['testaccA', 'testacc_1', 'testacc_2']
-        test_temp_dir = self.move_files_2_tmp_dir([f'{x}.fastq' for x in ids])
+        test_temp_dir = self.compress_files_2_tmp_dir(
+            [f'{x}.fastq' for x in ids]
+        )
 
         [self.fetched_q.put(_id) for _id in ids]
         self.fetched_q.put(None)
@@ -453,12 +658,14 @@ class TestUtils4SequenceFetching(SequenceTests):
             ls_act_single.append(_id[0][0]) if not _id[0][1] else False
             ls_act_paired.append(_id[0][0]) if _id[0][1] else False
 
-        ls_exp_single = [
-            os.path.join(test_temp_dir.name, 'testaccA_01_L001_R1_001.fastq')
-        ]
+        ls_exp_single = [os.path.join(
+            test_temp_dir.name, 'testaccA_01_L001_R1_001.fastq.gz'
+        )]
         ls_exp_paired = [
-            os.path.join(test_temp_dir.name, 'testacc_00_L001_R1_001.fastq'),
-            os.path.join(test_temp_dir.name, 'testacc_00_L001_R2_001.fastq')
+            os.path.join(
+                test_temp_dir.name, 'testacc_00_L001_R1_001.fastq.gz'),
+            os.path.join(
+                test_temp_dir.name, 'testacc_00_L001_R2_001.fastq.gz')
         ]
 
         self.assertEqual(set(ls_act_single), set(ls_exp_single))
@@ -466,7 +673,9 @@ class TestUtils4SequenceFetching(SequenceTests):
 
     def test_process_downloaded_sequences_paired_n_single_content(self):
         ids = ['testaccHYB', 'testaccHYB_1', 'testaccHYB_2']
-        test_temp_dir = self.move_files_2_tmp_dir([f'{x}.fastq' for x in ids])
+        test_temp_dir = self.compress_files_2_tmp_dir(
+            [f'{x}.fastq' for x in ids]
+        )
 
         [self.fetched_q.put(_id) for _id in ids]
         self.fetched_q.put(None)
@@ -483,13 +692,38 @@ class TestUtils4SequenceFetching(SequenceTests):
                 ls_act_paired.append(_id[i][0]) if _id[i][1] else False
 
         # test that file contents are the same
-        self.assertTrue(
-            filecmp.cmp(
-                ls_act_single[0], self.get_data_path(f'{ids[0]}.fastq')))
-        for i in [0, 1]:
-            self.assertTrue(
-                filecmp.cmp(
-                    ls_act_paired[i], self.get_data_path(f'{ids[i+1]}.fastq')))
+        ls_act = [ls_act_single[0], *ls_act_paired]
+        for path_act, _id in zip(ls_act, ids):
+            with gzip.open(path_act, 'rb') as fact, \
+                    open(self.get_data_path(f'{_id}.fastq'), 'rb') as fexp:
+                self.assertEqual(fact.read(), fexp.read())
+
+    def test_process_downloaded_sequences_from_ena(self):
+        test_temp_dir = self.make_tmp_dir()
+        filenames = ['testaccE_00_L001_R1_001.fastq.gz',
+                     'testaccE_00_L001_R2_001.fastq.gz',
+                     'testaccS_01_L001_R1_001.fastq.gz']
+        for filename in filenames:
+            FastqGzWriter(os.path.join(test_temp_dir.name, filename)).close()
+
+        [self.fetched_q.put(_id) for _id in ['testaccE', 'testaccS']]
+        self.fetched_q.put(None)
+
+        _process_downloaded_sequences(
+            output_dir=test_temp_dir.name, fetched_queue=self.fetched_q,
+            renaming_queue=self.renamed_q, n_workers=1
+        )
+
+        paths = [os.path.join(test_temp_dir.name, x) for x in filenames]
+        self.assertListEqual(
+            self.renamed_q.get(), [(paths[0], True), (paths[1], True)]
+        )
+        self.assertListEqual(self.renamed_q.get(), [(paths[2], False)])
+        self.assertIsNone(self.renamed_q.get())
+        # files downloaded from ENA keep their names
+        self.assertListEqual(
+            sorted(os.listdir(test_temp_dir.name)), filenames
+        )
 
     def test_write_empty_casava_single(self):
         casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
@@ -523,10 +757,11 @@ class TestUtils4SequenceFetching(SequenceTests):
         casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
         casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
         ls_file_single = ['testaccA_01_L001_R1_001.fastq']
-        test_temp_dir = self.move_files_2_tmp_dir(ls_file_single)
+        test_temp_dir = self.compress_files_2_tmp_dir(ls_file_single)
 
         self.renamed_q.put(
-            [(os.path.join(test_temp_dir.name, ls_file_single[0]), False)]
+            [(os.path.join(test_temp_dir.name, f'{ls_file_single[0]}.gz'),
+              False)]
         )
         self.renamed_q.put(None)
 
@@ -550,11 +785,11 @@ class TestUtils4SequenceFetching(SequenceTests):
         casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
         ls_file_paired = ['testacc_00_L001_R1_001.fastq',
                           'testacc_00_L001_R2_001.fastq']
-        test_temp_dir = self.move_files_2_tmp_dir(ls_file_paired)
+        test_temp_dir = self.compress_files_2_tmp_dir(ls_file_paired)
 
         self.renamed_q.put([
-            (os.path.join(test_temp_dir.name, ls_file_paired[0]), True),
-            (os.path.join(test_temp_dir.name, ls_file_paired[1]), True)
+            (os.path.join(test_temp_dir.name, f'{x}.gz'), True)
+            for x in ls_file_paired
         ])
         self.renamed_q.put(None)
 
@@ -576,6 +811,247 @@ class TestUtils4SequenceFetching(SequenceTests):
             (2, [3, 3]), self._validate_sequences_in_samples(casava_out_paired)
         )
 
+    @patch('shutil.move')
+    def test_write2casava_dir_paired_move_error(self, patched_move):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        ls_file_paired = ['testacc_00_L001_R1_001.fastq',
+                          'testacc_00_L001_R2_001.fastq']
+        test_temp_dir = self.compress_files_2_tmp_dir(ls_file_paired)
+
+        def _move(path_in, path_out):
+            if path_in.endswith('R2_001.fastq.gz'):
+                raise OSError('some error')
+            with open(path_out, 'w'):
+                pass
+        patched_move.side_effect = _move
+
+        self.renamed_q.put([
+            (os.path.join(test_temp_dir.name, f'{x}.gz'), True)
+            for x in ls_file_paired
+        ])
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='ERROR', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q
+            )
+        self.assertIn(
+            'ERROR:q2_fondue.sequences:Sequences of run testacc could '
+            'not be written.', cm.output
+        )
+        # partially written sample should be removed
+        self.assertListEqual(os.listdir(str(casava_out_paired)), [])
+        self.assertEqual(1, self.processed_q.qsize())
+        self.assertDictEqual(
+            self.processed_q.get(), {'failed_ids': {'testacc': 'some error'}}
+        )
+
+    def test_write2casava_dir_too_many_files(self):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        ls_file_paired = ['testacc_00_L001_R1_001.fastq',
+                          'testacc_00_L001_R2_001.fastq',
+                          'testacc_00_L001_R3_001.fastq']
+        test_temp_dir = self.make_tmp_dir()
+
+        self.renamed_q.put([
+            (os.path.join(test_temp_dir.name, f'{x}.gz'), True)
+            for x in ls_file_paired
+        ])
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='ERROR', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q
+            )
+        self.assertIn(
+            'ERROR:q2_fondue.sequences:Sequences of run testacc could '
+            'not be written: found 3 files.', cm.output
+        )
+        self.assertListEqual(os.listdir(str(casava_out_paired)), [])
+        self.assertEqual(1, self.processed_q.qsize())
+        self.assertDictEqual(
+            self.processed_q.get(), {'failed_ids': {'testacc': (
+                'Expected one or two files of the same read type but found '
+                '3: testacc_00_L001_R1_001.fastq.gz, '
+                'testacc_00_L001_R2_001.fastq.gz, '
+                'testacc_00_L001_R3_001.fastq.gz.'
+            )}}
+        )
+
+    def test_write2casava_dir_quality_encoding_warning(self):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        test_temp_dir = self.make_tmp_dir()
+        filename = 'testaccP64_01_L001_R1_001.fastq.gz'
+        path_in = os.path.join(test_temp_dir.name, filename)
+        with gzip.open(path_in, 'wt') as fin:
+            fin.write('@r1\nACGT\n+\nhhJh\n')
+
+        self.renamed_q.put(
+            [(os.path.join(test_temp_dir.name, filename), False)]
+        )
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='WARNING', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q
+            )
+        self.assertIn(
+            'WARNING:q2_fondue.sequences:Quality scores in '
+            'testaccP64_01_L001_R1_001.fastq.gz do not seem to be '
+            'Phred+33-encoded (detected encoding: phred64). They may not '
+            'be interpreted correctly once imported.', cm.output
+        )
+        self.assertTrue(os.path.isfile(
+            os.path.join(str(casava_out_single), filename)
+        ))
+
+    def test_write2casava_dir_uniform_quality_warning(self):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        test_temp_dir = self.make_tmp_dir()
+        filename = 'testaccLITE_01_L001_R1_001.fastq.gz'
+        with FastqGzWriter(os.path.join(test_temp_dir.name, filename)) as w:
+            w.write_record('r1', 'ACGT', '????')
+            w.write_record('r2', 'AC', '??')
+
+        self.renamed_q.put(
+            [(os.path.join(test_temp_dir.name, filename), False)]
+        )
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='WARNING', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q
+            )
+        self.assertListEqual(cm.output, [
+            'WARNING:q2_fondue.sequences:All quality scores in '
+            'testaccLITE_01_L001_R1_001.fastq.gz are the same (as in SRA '
+            'Lite runs) - quality-based analyses of those sequences will '
+            'not be meaningful.'
+        ])
+        self.assertListEqual(self.processed_q.get(), [filename])
+
+    @parameterized.expand([
+        ('malformed', b'r1\nACGT\n+\nIIII\n'),
+        ('binary', b'@r1\nACGT\n+\n\xff\xfe\xfd\xfc\n'),
+    ])
+    def test_write2casava_dir_quality_encoding_undetected(self, _, content):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        test_temp_dir = self.make_tmp_dir()
+        filename = 'testaccBAD_01_L001_R1_001.fastq.gz'
+        path_in = os.path.join(test_temp_dir.name, filename)
+        with gzip.open(path_in, 'wb') as fin:
+            fin.write(content)
+
+        self.renamed_q.put(
+            [(os.path.join(test_temp_dir.name, filename), False)]
+        )
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='WARNING', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q
+            )
+        self.assertTrue(cm.output[0].startswith(
+            'WARNING:q2_fondue.sequences:Quality encoding of '
+            'testaccBAD_01_L001_R1_001.fastq.gz could not be detected:'
+        ))
+        self.assertListEqual(self.processed_q.get(), [filename])
+
+    def test_write2casava_dir_trailing_blank_line(self):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        test_temp_dir = self.make_tmp_dir()
+        filename = 'testaccBL_01_L001_R1_001.fastq.gz'
+        path_in = os.path.join(test_temp_dir.name, filename)
+        with gzip.open(path_in, 'wt') as fin:
+            fin.write('@r1\nACGT\n+\nIIII\n\n')
+
+        self.renamed_q.put(
+            [(os.path.join(test_temp_dir.name, filename), False)]
+        )
+        self.renamed_q.put(None)
+
+        _write2casava_dir(
+            test_temp_dir.name, str(casava_out_single.path),
+            str(casava_out_paired.path), self.renamed_q, self.processed_q
+        )
+        self.assertListEqual(self.processed_q.get(), [filename])
+
+    def test_write2casava_dir_paired_out_of_sync(self):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        test_temp_dir = self.make_tmp_dir()
+        filenames = ['testaccSYNC_00_L001_R1_001.fastq.gz',
+                     'testaccSYNC_00_L001_R2_001.fastq.gz']
+        contents = ['@r1/1\nACGT\n+\nII#I\n@r2/1\nAC\n+\nI#\n',
+                    '@r1/2\nACGT\n+\nII#I\n@r3/2\nAC\n+\nI#\n']
+        for filename, content in zip(filenames, contents):
+            path_in = os.path.join(test_temp_dir.name, filename)
+            with gzip.open(path_in, 'wt') as f:
+                f.write(content)
+
+        self.renamed_q.put([
+            (os.path.join(test_temp_dir.name, filenames[0]), True),
+            (os.path.join(test_temp_dir.name, filenames[1]), True)
+        ])
+        self.renamed_q.put(None)
+
+        with self.assertLogs(
+                level='WARNING', logger='q2_fondue.sequences') as cm:
+            _write2casava_dir(
+                test_temp_dir.name, str(casava_out_single.path),
+                str(casava_out_paired.path), self.renamed_q,
+                self.processed_q, check_pairing=True
+            )
+        self.assertIn(
+            'WARNING:q2_fondue.sequences:Forward and reverse reads of '
+            'testaccSYNC are out of sync from record 2 on (r2/1 vs. r3/2).',
+            cm.output
+        )
+
+    @patch('q2_fondue.sequences._check_pairing')
+    def test_write2casava_dir_paired_not_checked(self, mock_check):
+        casava_out_single = CasavaOneEightSingleLanePerSampleDirFmt()
+        casava_out_paired = CasavaOneEightSingleLanePerSampleDirFmt()
+        ls_file_paired = ['testacc_00_L001_R1_001.fastq',
+                          'testacc_00_L001_R2_001.fastq']
+        test_temp_dir = self.compress_files_2_tmp_dir(ls_file_paired)
+
+        self.renamed_q.put([
+            (os.path.join(test_temp_dir.name, f'{x}.gz'), True)
+            for x in ls_file_paired
+        ])
+        self.renamed_q.put(None)
+
+        _write2casava_dir(
+            test_temp_dir.name, str(casava_out_single.path),
+            str(casava_out_paired.path), self.renamed_q, self.processed_q
+        )
+        mock_check.assert_not_called()
+        self.assertListEqual(
+            self.processed_q.get(), [f'{x}.gz' for x in ls_file_paired]
+        )
+
     def test_announce_completion_single(self):
         self.processed_q.put(['fileA.fastq'])
         self.processed_q.put(['fileB.fastq'])
@@ -627,6 +1103,20 @@ class TestUtils4SequenceFetching(SequenceTests):
         self.assertListEqual(obs_s, [['fileA.fastq']])
         self.assertListEqual(obs_p, [])
 
+    def test_announce_completion_with_failed_multiple(self):
+        self.processed_q.put(['fileA.fastq'])
+        self.processed_q.put({'failed_ids': {'fileC': 'rewrite error'}})
+        self.processed_q.put({'failed_ids': {'fileB': 'some error'}})
+
+        obs_fail, obs_s, obs_p = _announce_completion(self.processed_q)
+
+        self.assertEqual(self.processed_q.qsize(), 0)
+        self.assertDictEqual(
+            obs_fail, {'fileB': 'some error', 'fileC': 'rewrite error'}
+        )
+        self.assertListEqual(obs_s, [['fileA.fastq']])
+        self.assertListEqual(obs_p, [])
+
 
 class TestSequenceFetching(SequenceTests):
 
@@ -665,7 +1155,7 @@ class TestSequenceFetching(SequenceTests):
             mock_proc.assert_has_calls([
                 call(target=_run_fasterq_dump_for_all, args=(
                     [acc_id], mock_tmpdir.return_value.name, 1, '', 0,
-                    ANY, ANY), daemon=True),
+                    ANY, ANY, False, 1024 ** 3), daemon=True),
                 call(target=_process_downloaded_sequences, args=(
                     mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True)
             ])
@@ -710,7 +1200,7 @@ class TestSequenceFetching(SequenceTests):
             mock_proc.assert_has_calls([
                 call(target=_run_fasterq_dump_for_all, args=(
                     [acc_id], mock_tmpdir.return_value.name, 1, '', 0,
-                    ANY, ANY), daemon=True),
+                    ANY, ANY, False, 1024 ** 3), daemon=True),
                 call(target=_process_downloaded_sequences, args=(
                     mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True),
             ])
@@ -753,7 +1243,7 @@ class TestSequenceFetching(SequenceTests):
         mock_proc.assert_has_calls([
             call(target=_run_fasterq_dump_for_all, args=(
                 ['SRR123456', 'SRR123457'], mock_tmpdir.return_value.name, 1,
-                '', 0, ANY, ANY), daemon=True),
+                '', 0, ANY, ANY, False, 1024 ** 3), daemon=True),
             call(target=_process_downloaded_sequences, args=(
                 mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True),
         ])
@@ -793,7 +1283,7 @@ class TestSequenceFetching(SequenceTests):
         mock_proc.assert_has_calls([
             call(target=_run_fasterq_dump_for_all, args=(
                 [run_id], mock_tmpdir.return_value.name, 1, '',
-                0, ANY, ANY), daemon=True),
+                0, ANY, ANY, False, 1024 ** 3), daemon=True),
             call(target=_process_downloaded_sequences, args=(
                 mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True),
         ])
@@ -830,7 +1320,7 @@ class TestSequenceFetching(SequenceTests):
         mock_proc.assert_has_calls([
             call(target=_run_fasterq_dump_for_all, args=(
                 ['SRR123456', 'SRR123457'], mock_tmpdir.return_value.name, 1,
-                '', 0, ANY, ANY), daemon=True),
+                '', 0, ANY, ANY, False, 1024 ** 3), daemon=True),
             call(target=_process_downloaded_sequences, args=(
                 mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True),
         ])
@@ -859,7 +1349,7 @@ class TestSequenceFetching(SequenceTests):
                 call(target=_run_fasterq_dump_for_all, args=(
                     ['SRR123456'], mock_tmpdir.return_value.name,
                     1,
-                    0, ANY, ANY), daemon=True),
+                    0, ANY, ANY, False, 1024 ** 3), daemon=True),
                 call(target=_process_downloaded_sequences, args=(
                     mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True),
             ])
@@ -894,7 +1384,8 @@ class TestSequenceFetching(SequenceTests):
         mock_proc.assert_has_calls([
             call(target=_run_fasterq_dump_for_all, args=(
                 [acc_id], mock_tmpdir.return_value.name, 1,
-                'path/to/key.ngc', 0, ANY, ANY), daemon=True),
+                'path/to/key.ngc', 0, ANY, ANY, False, 1024 ** 3),
+                daemon=True),
             call(target=_process_downloaded_sequences, args=(
                 mock_tmpdir.return_value.name, ANY, ANY, 1), daemon=True)
         ])
@@ -926,6 +1417,114 @@ class TestSequenceFetching(SequenceTests):
                 restricted_access=True
             )
 
+    @patch('q2_fondue.sequences.Process', SyncProcess)
+    @patch('q2_fondue.sequences.Pool', SyncPool)
+    @patch('q2_fondue.sequences.cpu_count', return_value=2)
+    @patch('q2_fondue.sequences._dump_and_compress')
+    @patch('q2_fondue.sequences._prefetch')
+    def test_get_sequences_downloaded(
+            self, mock_prefetch, mock_dump, mock_cpu_count
+    ):
+        acc_ids = ['SRR123456', 'SRR123457']
+
+        def _prefetch(acc, out_dir):
+            os.makedirs(os.path.join(out_dir, acc))
+
+        def _dump_and_compress(
+                acc, sra_path, out_dir, threads, key_file, min_free_space
+        ):
+            for f in os.listdir(self.get_data_path('')):
+                if f.startswith(acc) and f.endswith('.fastq'):
+                    path_out = os.path.join(out_dir, f'{f}.gz')
+                    with open(self.get_data_path(f), 'rb') as fin, \
+                            gzip.open(path_out, 'wb') as fout:
+                        shutil.copyfileobj(fin, fout)
+            return DUMPED
+
+        mock_prefetch.side_effect = _prefetch
+        mock_dump.side_effect = _dump_and_compress
+
+        casava_single, casava_paired, _ = get_sequences(
+            MagicMock(get_ids=MagicMock(return_value=acc_ids)),
+            email='some@where.com', retries=0
+        )
+
+        mock_prefetch.assert_has_calls([
+            call(acc_ids[0], ANY), call(acc_ids[1], ANY)
+        ])
+        self.assertListEqual(
+            sorted(os.listdir(str(casava_single.path))),
+            ['SRR123456_01_L001_R1_001.fastq.gz']
+        )
+        self.assertListEqual(
+            sorted(os.listdir(str(casava_paired.path))),
+            ['SRR123457_00_L001_R1_001.fastq.gz',
+             'SRR123457_00_L001_R2_001.fastq.gz']
+        )
+        path_out = os.path.join(
+            str(casava_paired.path), 'SRR123457_00_L001_R2_001.fastq.gz'
+        )
+        with gzip.open(path_out, 'rb') as fout, \
+                open(self.get_data_path('SRR123457_2.fastq'), 'rb') as fin:
+            self.assertEqual(fout.read(), fin.read())
+
+    @patch('q2_fondue.sequences.Process', SyncProcess)
+    @patch('q2_fondue.sequences.Pool', SyncPool)
+    @patch('q2_fondue.sequences.cpu_count', return_value=2)
+    @patch('q2_fondue.sequences._fetch_from_ena')
+    @patch('q2_fondue.sequences._dump_and_compress')
+    @patch('q2_fondue.sequences._prefetch')
+    def test_get_sequences_downloaded_from_ena(
+            self, mock_prefetch, mock_dump, mock_ena, mock_cpu_count
+    ):
+        acc_ids = ['SRR123456', 'SRR123457']
+
+        def _fetch_from_ena(acc, out_dir):
+            # only the paired-end run is mirrored on ENA
+            if acc != 'SRR123457':
+                return []
+            paths = []
+            for read in (1, 2):
+                path_out = os.path.join(
+                    out_dir, f'{acc}_00_L001_R{read}_001.fastq.gz'
+                )
+                with open(self.get_data_path(f'{acc}_{read}.fastq'),
+                          'rb') as fin, gzip.open(path_out, 'wb') as fout:
+                    shutil.copyfileobj(fin, fout)
+                paths.append(path_out)
+            return paths
+
+        def _dump_and_compress(
+                acc, sra_path, out_dir, threads, key_file, min_free_space
+        ):
+            path_out = os.path.join(out_dir, f'{acc}.fastq.gz')
+            with open(self.get_data_path(f'{acc}.fastq'), 'rb') as fin, \
+                    gzip.open(path_out, 'wb') as fout:
+                shutil.copyfileobj(fin, fout)
+            return DUMPED
+
+        mock_ena.side_effect = _fetch_from_ena
+        mock_dump.side_effect = _dump_and_compress
+
+        casava_single, casava_paired, _ = get_sequences(
+            MagicMock(get_ids=MagicMock(return_value=acc_ids)),
+            email='some@where.com', retries=0, prefer_ena=True
+        )
+
+        mock_ena.assert_has_calls([
+            call(acc_ids[0], ANY), call(acc_ids[1], ANY)
+        ])
+        mock_prefetch.assert_called_once_with(acc_ids[0], ANY)
+        self.assertListEqual(
+            sorted(os.listdir(str(casava_single.path))),
+            ['SRR123456_01_L001_R1_001.fastq.gz']
+        )
+        self.assertListEqual(
+            sorted(os.listdir(str(casava_paired.path))),
+            ['SRR123457_00_L001_R1_001.fastq.gz',
+             'SRR123457_00_L001_R2_001.fastq.gz']
+        )
+
 
 class TestSequenceCombining(SequenceTests):
 
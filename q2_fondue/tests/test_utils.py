@@ -5,19 +5,29 @@
 #
 # The full license is in the file LICENSE, distributed with this software.
 # ----------------------------------------------------------------------------
-import gzip
+import hashlib
+import json
+import logging
 import os
 import signal
 import threading
 import unittest
 from threading import Thread
-from unittest.mock import patch, MagicMock
+from unittest.mock import MagicMock, patch
 
+from parameterized import parameterized
 from qiime2.plugin.testing import TestPluginBase
 from tqdm import tqdm
 
-from q2_fondue.utils import (handle_threaded_exception, _has_enough_space,
-                             _find_next_id, _chunker, _rewrite_fastq)
+from q2_fondue.rewrite import _rewrite_fastq
+from q2_fondue.utils import (
+    CompressionError, CorruptGzipError, DownloadError, FastqParseError,
+    FastqRewriteError, FastqStats, FastqValidationError,
+    InsufficientSpaceError, PairingError, RewriteCancelledError,
+    TruncatedFastqError, _TempFileRegistry, _casava_filename, _chunker,
+    _find_next_id, _has_enough_space, _set_log_level, _write_checksum_manifest,
+    handle_threaded_exception
+)
 
 
 class TestExceptHooks(unittest.TestCase):
@@ -131,18 +141,167 @@ class TestSRAUtils(TestPluginBase):
         exp_out = ['A', 'B', 'C']
         self.assertEqual(next(obs_out), exp_out)
 
-    def test_rewrite_fastq(self):
-        file_in = self.get_data_path('SRR123456.fastq')
-        file_out = self.get_data_path('SRR123456.fastq.gz')
+    def test_fastq_stats_throughput_untimed(self):
+        obs = FastqStats(reads=2, compressed_bytes=10)
+        self.assertEqual(obs.reads_per_second, 0.0)
+        self.assertEqual(obs.mb_per_second_out, 0.0)
 
-        _rewrite_fastq(file_in, file_out)
+    @parameterized.expand([
+        ('parse', FastqValidationError, FastqParseError),
+        ('truncated', TruncatedFastqError, FastqParseError),
+        ('pairing', PairingError, FastqValidationError),
+        ('compression', CorruptGzipError, CompressionError),
+        ('space', InsufficientSpaceError, FastqRewriteError),
+        ('cancelled', RewriteCancelledError, FastqRewriteError),
+    ])
+    def test_exception_hierarchy(self, name, error, exp_base):
+        self.assertTrue(issubclass(error, exp_base))
 
-        with open(file_in, 'rb') as fin, gzip.open(file_out, 'r') as fout:
-            for lin, lout in zip(fin.readlines(), fout.readlines()):
-                self.assertEqual(lin, lout)
+    def test_download_error_retryable(self):
+        self.assertFalse(DownloadError('failed').retryable)
+        self.assertTrue(DownloadError('failed', retryable=True).retryable)
 
-        # clean up
-        os.remove(file_out)
+    def test_set_log_level(self):
+        _set_log_level('warning')
+        try:
+            for name in ('q2_fondue.utils', 'q2_fondue.compression',
+                         'q2_fondue.fastq', 'q2_fondue.rewrite',
+                         'q2_fondue.bam', 'q2_fondue.downloads'):
+                self.assertEqual(
+                    logging.getLogger(name).level, logging.WARNING
+                )
+        finally:
+            _set_log_level('info')
+
+    @parameterized.expand([
+        (1, False, '.fastq.gz', 'SRR123_01_L001_R1_001.fastq.gz'),
+        (1, True, '.fastq.gz', 'SRR123_00_L001_R1_001.fastq.gz'),
+        (2, True, '.fastq', 'SRR123_00_L001_R2_001.fastq'),
+    ])
+    def test_casava_filename(self, read, paired, extension, exp_name):
+        self.assertEqual(
+            _casava_filename('SRR123', read, paired, extension), exp_name
+        )
+
+    def _write_run_files(self):
+        out_dir = os.path.join(self.temp_dir.name, 'out')
+        os.mkdir(out_dir)
+        for acc in ('SRR123456', 'SRR123457_1'):
+            _rewrite_fastq(
+                self.get_data_path(f'{acc}.fastq'),
+                os.path.join(out_dir, f'{acc}.fastq.gz')
+            )
+        open(os.path.join(out_dir, 'MANIFEST'), 'w').close()
+        return out_dir
+
+    def test_write_checksum_manifest(self):
+        out_dir = self._write_run_files()
+        file_out = os.path.join(self.temp_dir.name, 'checksums.md5')
+
+        obs = _write_checksum_manifest(out_dir, file_out)
+
+        exp = {}
+        for name in ('SRR123456.fastq.gz', 'SRR123457_1.fastq.gz'):
+            with open(os.path.join(out_dir, name), 'rb') as f_in:
+                exp[name] = hashlib.md5(f_in.read()).hexdigest()
+        self.assertDictEqual(obs, exp)
+        with open(file_out) as f_in:
+            self.assertEqual(
+                f_in.read(),
+                ''.join(f'{digest}  {name}\n' for name, digest in exp.items())
+            )
+
+    def test_write_checksum_manifest_json(self):
+        out_dir = self._write_run_files()
+        file_out = os.path.join(self.temp_dir.name, 'checksums.json')
+
+        obs = _write_checksum_manifest(
+            out_dir, file_out, 'sha256', manifest_format='json'
+        )
+
+        with open(file_out) as f_in:
+            manifest = json.load(f_in)
+        self.assertEqual(manifest['algorithm'], 'sha256')
+        self.assertDictEqual(manifest['files'], obs)
+        self.assertEqual(len(obs['SRR123456.fastq.gz']), 64)
+
+    @parameterized.expand([
+        ('algorithm', {'algorithm': 'crc99'}, 'algorithm: crc99'),
+        ('format', {'manifest_format': 'csv'}, 'got "csv"'),
+    ])
+    def test_write_checksum_manifest_invalid(self, name, kwargs, msg):
+        file_out = os.path.join(self.temp_dir.name, 'checksums.md5')
+
+        with self.assertRaisesRegex(ValueError, msg):
+            _write_checksum_manifest(self.temp_dir.name, file_out, **kwargs)
+
+
+class TestTempFileRegistry(TestPluginBase):
+    package = 'q2_fondue.tests'
+
+    def setUp(self):
+        super().setUp()
+        self.registry = _TempFileRegistry()
+        self.file_tmp = os.path.join(self.temp_dir.name, 'out.tmp')
+        with open(self.file_tmp, 'w') as fout:
+            fout.write('partial')
+
+    def test_cleanup(self):
+        self.registry.register(self.file_tmp)
+        with self.registry.directory(self.temp_dir.name) as dir_tmp:
+            self.registry.register(os.path.join(dir_tmp, 'missing'))
+            self.assertEqual(len(self.registry.paths), 3)
+            self.registry.cleanup()
+            self.assertFalse(os.path.exists(dir_tmp))
+
+        self.assertListEqual(self.registry.paths, [])
+        self.assertListEqual(os.listdir(self.temp_dir.name), [])
+
+    def test_cleanup_keep_temp(self):
+        self.registry.keep_temp = True
+        self.registry.register(self.file_tmp)
+        self.registry.cleanup()
+
+        self.assertListEqual(self.registry.paths, [])
+        self.assertTrue(os.path.isfile(self.file_tmp))
+
+    def test_release(self):
+        self.registry.register(self.file_tmp)
+        self.registry.release(self.file_tmp)
+        self.registry.cleanup()
+
+        self.assertTrue(os.path.isfile(self.file_tmp))
+
+    @patch.dict(os.environ, {'Q2_FONDUE_KEEP_TEMP': '1'})
+    def test_keep_temp_from_environment(self):
+        self.assertTrue(_TempFileRegistry().keep_temp)
+
+    def test_remove_on_termination(self):
+        previous = signal.getsignal(signal.SIGTERM)
+
+        with self.registry.remove_on_termination():
+            handler = signal.getsignal(signal.SIGTERM)
+            with self.assertRaises(SystemExit) as cm:
+                handler(signal.SIGTERM, None)
+
+        self.assertEqual(cm.exception.code, 128 + signal.SIGTERM)
+        self.assertEqual(signal.getsignal(signal.SIGTERM), previous)
+
+    def test_remove_on_termination_chained(self):
+        calls = []
+
+        def previous(signum, frame):
+            calls.append(signum)
+
+        original = signal.signal(signal.SIGTERM, previous)
+        self.addCleanup(signal.signal, signal.SIGTERM, original)
+
+        with self.assertRaises(SystemExit), \
+                self.registry.remove_on_termination():
+            signal.getsignal(signal.SIGTERM)(signal.SIGTERM, None)
+
+        self.assertListEqual(calls, [signal.SIGTERM])
+        self.assertIs(signal.getsignal(signal.SIGTERM), previous)
 
 
 if __name__ == "__main__":
@@ -5,27 +5,279 @@
 #
 # The full license is in the file LICENSE, distributed with this software.
 # ----------------------------------------------------------------------------
-import gzip
+import atexit
+import hashlib
+import json
+import logging
 import os
 import shutil
 import signal
 import subprocess
-from typing import List
+import sys
+import tempfile
+import time
+from contextlib import contextmanager
+from dataclasses import dataclass, field
+from threading import Event, Lock, current_thread, main_thread
+from typing import Dict, Iterable, List, Tuple, Union
 
 from entrezpy.esearch import esearcher as es
 from tqdm import tqdm
 
 from q2_fondue.entrezpy_clients._esearch import ESearchAnalyzer
 from q2_fondue.entrezpy_clients._utils import (
-    PREFIX, InvalidIDs, set_up_logger, set_up_entrezpy_logging)
+    InvalidIDs, PREFIX, set_up_entrezpy_logging, set_up_logger
+)
 
 LOGGER = set_up_logger('INFO', logger_name=__name__)
+# modules whose loggers follow the log level of the plugin's actions
+LOGGER_NAMES = (
+    'q2_fondue.utils', 'q2_fondue.compression', 'q2_fondue.fastq',
+    'q2_fondue.rewrite', 'q2_fondue.bam', 'q2_fondue.downloads'
+)
 
 
 class DownloadError(Exception):
+    """Raised when sequences could not be fetched.
+
+    Attributes:
+        retryable (bool): Whether the failure is likely transient (e.g.,
+            a server which kept timing out), such that fetching again
+            later may succeed.
+    """
+
+    def __init__(self, *args, retryable: bool = False):
+        super().__init__(*args)
+        self.retryable = retryable
+
+
+class FastqRewriteError(Exception):
     pass
 
 
+class FastqParseError(FastqRewriteError):
+    """Raised when the records of a FASTQ file cannot be parsed."""
+
+
+class CompressionError(FastqRewriteError):
+    """Raised when data cannot be compressed or decompressed."""
+
+
+class FastqValidationError(FastqParseError):
+    """Raised when a FASTQ record is malformed.
+
+    Attributes:
+        file_in (str): Path to the file containing the record.
+        record (int): 1-based index of the record within the file.
+        read_id (str): ID of the read, if it could be determined.
+        reason (str): Description of the problem.
+    """
+
+    def __init__(self, file_in: str, record: int, read_id: str, reason: str):
+        self.file_in = file_in
+        self.record = record
+        self.read_id = read_id
+        self.reason = reason
+        read = f' (read {read_id})' if read_id else ''
+        super().__init__(
+            f'Record {record}{read} in {file_in} is invalid: {reason}.'
+        )
+
+
+class TruncatedFastqError(FastqValidationError):
+    """Raised when a FASTQ file ends in the middle of a record.
+
+    Attributes:
+        offset (int): Byte offset (in the uncompressed data) at which
+            the truncated record starts.
+    """
+
+    def __init__(self, file_in: str, record: int, read_id: str, offset: int):
+        self.offset = offset
+        super().__init__(
+            file_in, record, read_id,
+            f'record is truncated (starting at byte {offset})'
+        )
+
+
+class PairingError(FastqValidationError):
+    """Raised when the mates of paired-end reads are not in sync."""
+
+
+class CorruptGzipError(CompressionError):
+    """Raised when a gzip file fails an integrity check.
+
+    Attributes:
+        file_in (str): Path to the file.
+        member (int): 1-based index of the corrupt gzip member.
+        offset (int): Byte offset (in the compressed file) at which
+            the corrupt member starts.
+        reason (str): Description of the problem.
+    """
+
+    def __init__(self, file_in: str, member: int, offset: int, reason: str):
+        self.file_in = file_in
+        self.member = member
+        self.offset = offset
+        self.reason = reason
+        super().__init__(
+            f'{file_in} is corrupt at gzip member {member} (starting at '
+            f'byte {offset}): {reason}.'
+        )
+
+
+class InsufficientSpaceError(FastqRewriteError):
+    """Raised when free space on the output file system drops below
+        a threshold while a file is being written.
+
+    Attributes:
+        file_out (str): Path to the file which was being written.
+        free (int): Free space on the file system in bytes.
+        threshold (int): Minimal free space in bytes.
+        completed (List[str]): Paths to the files which were completed
+            before writing was aborted.
+    """
+
+    def __init__(
+            self, file_out: str, free: int, threshold: int,
+            completed: List[str] = None
+    ):
+        self.file_out = file_out
+        self.free = free
+        self.threshold = threshold
+        self.completed = completed or []
+        super().__init__(
+            f'Writing {file_out} was aborted: only {free} bytes are free '
+            f'on its file system (at least {threshold} are required).'
+        )
+
+
+class RewriteCancelledError(FastqRewriteError):
+    """Raised when a rewrite was cancelled (see CancellationToken)."""
+
+    def __init__(self):
+        super().__init__('The rewrite was cancelled.')
+
+
+class CancellationToken:
+    """Cancels running rewrites cooperatively, e.g., from another thread -
+        they are aborted with a RewriteCancelledError when they read their
+        next block of data, cleaning up their temporary files."""
+
+    def __init__(self):
+        self._event = Event()
+
+    @property
+    def cancelled(self) -> bool:
+        return self._event.is_set()
+
+    def cancel(self):
+        self._event.set()
+
+    def check(self):
+        if self._event.is_set():
+            raise RewriteCancelledError()
+
+
+@dataclass
+class FastqStats:
+    """Summary of the sequences written to a FASTQ file.
+
+    Attributes:
+        reads (int): Number of reads.
+        bases (int): Total number of bases.
+        min_length (int): Length of the shortest read, if any.
+        max_length (int): Length of the longest read, if any.
+        compressed_bytes (int): Size of the written (compressed) file.
+        length_mismatches (List[Tuple[str, int, str]]): File, record
+            number and read ID of all the records whose sequence and
+            quality lengths differ, if those were reported.
+        truncated_at (int): Byte offset of the truncated last record
+            which was not written, if any.
+        uniform_quality (bool): Whether all the quality scores are the same,
+            e.g., because qualities were simplified in SRA Lite runs.
+        filled_qualities (int): Number of records lacking qualities which
+            were written with filled-in ones.
+        dropped (Dict[str, int]): Number of records which were not written,
+            by the processing step (e.g., 'subsample') that removed them.
+        trimmed (Dict[str, int]): Number of bases which were trimmed from
+            the reads, by the processing step (e.g., 'sliding_window').
+        checksums (Dict[str, str]): Hex digests of the written file,
+            by hash algorithm, if those were requested.
+        uncompressed_bytes (int): Size of the written sequences before
+            compression.
+        wall_time (float): Seconds it took to write the file.
+        cpu_time (float): CPU seconds used by the thread that wrote the
+            file (not counting any compression threads).
+    """
+    reads: int = 0
+    bases: int = 0
+    min_length: int = None
+    max_length: int = None
+    compressed_bytes: int = 0
+    length_mismatches: List[Tuple[str, int, str]] = field(
+        default_factory=list
+    )
+    truncated_at: int = None
+    uniform_quality: bool = False
+    filled_qualities: int = 0
+    dropped: Dict[str, int] = field(default_factory=dict)
+    trimmed: Dict[str, int] = field(default_factory=dict)
+    checksums: Dict[str, str] = field(default_factory=dict)
+    uncompressed_bytes: int = 0
+    # timings differ between otherwise identical writes
+    wall_time: float = field(default=0.0, compare=False)
+    cpu_time: float = field(default=0.0, compare=False)
+
+    @property
+    def mean_length(self) -> float:
+        return self.bases / self.reads if self.reads else 0.0
+
+    @property
+    def reads_per_second(self) -> float:
+        return self.reads / self.wall_time if self.wall_time else 0.0
+
+    @property
+    def mb_per_second_in(self) -> float:
+        """Throughput in MB of uncompressed sequences per second."""
+        return self.uncompressed_bytes / 1e6 / self.wall_time \
+            if self.wall_time else 0.0
+
+    @property
+    def mb_per_second_out(self) -> float:
+        """Throughput in MB of compressed output per second."""
+        return self.compressed_bytes / 1e6 / self.wall_time \
+            if self.wall_time else 0.0
+
+    def add_read(self, length: int):
+        self.reads += 1
+        self.bases += length
+        self.min_length = length if self.min_length is None \
+            else min(self.min_length, length)
+        self.max_length = length if self.max_length is None \
+            else max(self.max_length, length)
+
+
+def _start_clock() -> Tuple[float, float]:
+    return time.perf_counter(), time.thread_time()
+
+
+def _stop_clock(stats: FastqStats, clock: Tuple[float, float]):
+    """Records the wall and CPU time passed since _start_clock in stats."""
+    stats.wall_time = time.perf_counter() - clock[0]
+    stats.cpu_time = time.thread_time() - clock[1]
+
+
+def _set_log_level(log_level: str):
+    """Sets the level of the loggers of the download and rewrite layers
+        (see LOGGER_NAMES), e.g., to the log_level of an action."""
+    level = logging.getLevelName(log_level.upper())
+    for name in LOGGER_NAMES:
+        logger = logging.getLogger(name)
+        if logger.level != level:
+            logger.setLevel(level)
+
+
 def _chunker(seq, size):
     # source: https://stackoverflow.com/a/434328/579416
     return (seq[pos:pos + size] for pos in range(0, len(seq), size))
@@ -145,6 +397,209 @@ def _find_next_id(acc_id: str, progress_bar: tqdm):
         return pbar_content[index_next_acc]
 
 
-def _rewrite_fastq(file_in: str, file_out: str):
-    with open(file_in, 'rb') as f_in, gzip.open(file_out, 'wb') as f_out:
-        shutil.copyfileobj(f_in, f_out)
+def _is_file_like(obj) -> bool:
+    """Checks whether an input or output is a binary file object or an
+        in-memory buffer (a bytearray or memoryview - bytes are treated
+        as paths) rather than a path."""
+    return hasattr(obj, 'read') or hasattr(obj, 'write') \
+        or isinstance(obj, (bytearray, memoryview))
+
+
+def _is_stream(file_out: Union[str, int]) -> bool:
+    """Checks whether an input or output is standard input/output ('-'),
+        a file descriptor or a file-like object rather than a path."""
+    return file_out == '-' or isinstance(file_out, int) \
+        or _is_file_like(file_out)
+
+
+class _TempFileRegistry:
+    """Keeps track of all the temporary files and directories which are
+        being written, such that none of them are left behind when writing
+        fails or the process exits or is terminated (see cleanup).
+
+    Attributes:
+        keep_temp (bool): Whether temporary files should be left behind
+            for debugging instead - set by the Q2_FONDUE_KEEP_TEMP
+            environment variable by default.
+    """
+
+    def __init__(self):
+        self.keep_temp = bool(os.environ.get('Q2_FONDUE_KEEP_TEMP'))
+        self._paths = set()
+        self._lock = Lock()
+
+    @property
+    def paths(self) -> List[str]:
+        with self._lock:
+            return sorted(self._paths)
+
+    def register(self, path: str):
+        with self._lock:
+            self._paths.add(path)
+
+    def release(self, path: str):
+        """Stops tracking a path, e.g., once it was renamed."""
+        with self._lock:
+            self._paths.discard(path)
+
+    def remove(self, path: str):
+        """Removes a temporary file or directory, unless temporary files
+            should be kept, and stops tracking it."""
+        if not self.keep_temp:
+            if os.path.isdir(path):
+                shutil.rmtree(path, ignore_errors=True)
+            elif os.path.exists(path):
+                os.remove(path)
+        self.release(path)
+
+    def cleanup(self):
+        """Removes all the temporary files which are still tracked - run
+            when the interpreter exits."""
+        for path in self.paths:
+            self.remove(path)
+
+    @contextmanager
+    def directory(self, dir: str = None):
+        """Provides a temporary directory, removed again on exit."""
+        path = tempfile.mkdtemp(dir=dir)
+        self.register(path)
+        try:
+            yield path
+        finally:
+            self.remove(path)
+
+    @contextmanager
+    def remove_on_termination(self):
+        """Turns SIGTERM (e.g., from a job scheduler) into a SystemExit
+            within the context, such that temporary files are cleaned up
+            as on any other error - only possible from the main thread.
+
+        A handler installed before is called first when the signal arrives
+        and is restored on exit.
+        """
+        if current_thread() is not main_thread():
+            yield
+            return
+
+        previous = signal.getsignal(signal.SIGTERM)
+        if previous is None:
+            # installed outside of Python - nothing which can be restored
+            previous = signal.SIG_DFL
+
+        def _exit(signum, frame):
+            if callable(previous):
+                previous(signum, frame)
+            sys.exit(128 + signum)
+
+        signal.signal(signal.SIGTERM, _exit)
+        try:
+            yield
+        finally:
+            signal.signal(signal.SIGTERM, previous)
+
+
+TEMP_FILES = _TempFileRegistry()
+atexit.register(TEMP_FILES.cleanup)
+
+
+@contextmanager
+def _atomic_output(
+        file_out: str, extensions: Iterable[str] = (), append: bool = False
+):
+    """Provides a temporary path ({file_out}.tmp) to be written to instead
+        of file_out - it is renamed to file_out once writing succeeded and
+        removed otherwise, such that interrupted runs never leave behind
+        incomplete files. Files at the temporary path with any of the
+        extensions appended (e.g., an index) are renamed along. In append
+        mode, the temporary file is appended to file_out instead."""
+    tmp_out = f'{file_out}.tmp'
+    paths = [(tmp_out + ext, file_out + ext) for ext in ('', *extensions)]
+    for path, _ in paths:
+        TEMP_FILES.register(path)
+    try:
+        yield tmp_out
+        if append:
+            with open(tmp_out, 'rb') as f_in, open(file_out, 'ab') as f_out:
+                shutil.copyfileobj(f_in, f_out)
+            os.remove(tmp_out)
+    except BaseException:
+        for path, _ in paths:
+            TEMP_FILES.remove(path)
+        raise
+    for path, final_path in paths:
+        if os.path.exists(path):
+            os.replace(path, final_path)
+        TEMP_FILES.release(path)
+
+
+def _remove_files(paths: Iterable[str]):
+    for path in paths:
+        if os.path.exists(path):
+            os.remove(path)
+
+
+def _casava_filename(
+        sample_id: str, read: int = 1, paired: bool = False,
+        extension: str = '.fastq.gz'
+) -> str:
+    """Builds a Casava 1.8 file name, as expected by QIIME 2.
+
+    Paired-end files get the barcode 00, while single-end files get
+    the barcode 01 (as in SRR123456_01_L001_R1_001.fastq.gz).
+    """
+    barcode = '00' if paired else '01'
+    return f'{sample_id}_{barcode}_L001_R{read}_001{extension}'
+
+
+def _file_checksum(
+        file_in: str, algorithm: str = 'md5', block_size: int = 1024 ** 2
+) -> str:
+    """Computes the hex digest of a file, reading it block by block."""
+    hasher = hashlib.new(algorithm)
+    with open(file_in, 'rb') as f_in:
+        for block in iter(lambda: f_in.read(block_size), b''):
+            hasher.update(block)
+    return hasher.hexdigest()
+
+
+def _write_checksum_manifest(
+        directory: str, file_out: str, algorithm: str = 'md5',
+        manifest_format: str = 'md5sum', suffix: str = '.fastq.gz'
+) -> Dict[str, str]:
+    """Writes checksums of all the FASTQ files in a directory.
+
+    Args:
+        directory (str): Directory with the written files.
+        file_out (str): Path where the manifest should be saved.
+        algorithm (str, default='md5'): Hash algorithm to be used.
+        manifest_format (str, default='md5sum'): Either 'md5sum', for
+            lines of digests and file names as written by md5sum and
+            sha256sum (and verifiable with their --check option), or
+            'json' for a mapping of file names to digests.
+        suffix (str, default='.fastq.gz'): Only files whose names end
+            with this suffix are included.
+
+    Returns:
+        Dict[str, str]: Digests by file name (relative to `directory`).
+    """
+    directory = os.fsdecode(directory)
+    if algorithm not in hashlib.algorithms_available:
+        raise ValueError(f'Unsupported hash algorithm: {algorithm}.')
+    if manifest_format not in ('md5sum', 'json'):
+        raise ValueError(
+            'Manifest format must be either "md5sum" or "json" '
+            f'(got "{manifest_format}").'
+        )
+    checksums = {
+        name: _file_checksum(os.path.join(directory, name), algorithm)
+        for name in sorted(os.listdir(directory)) if name.endswith(suffix)
+    }
+    with open(file_out, 'w') as f_out:
+        if manifest_format == 'json':
+            json.dump({'algorithm': algorithm, 'files': checksums},
+                      f_out, indent=2)
+        else:
+            f_out.writelines(
+                f'{digest}  {name}\n' for name, digest in checksums.items()
+            )
+    return checksums
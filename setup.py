@@ -25,7 +25,9 @@ setup(
     url="https://github.com/bokulich-lab/q2-fondue",
     entry_points={
         'qiime2.plugins':
-        ['q2-fondue=q2_fondue.plugin_setup:plugin']
+        ['q2-fondue=q2_fondue.plugin_setup:plugin'],
+        'console_scripts':
+        ['fondue-fastq=q2_fondue.cli:main']
     },
     package_data={
         'q2_fondue': ['citations.bib'],